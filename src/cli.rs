@@ -1,4 +1,8 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+
+use crate::flamegraph::{ColorMode, DEFAULT_MIN_WIDTH, Editor, Renderer, Theme};
+use crate::stackcollapse::{Recursion, SourceRoot, TimeBound};
 
 /// A linux profiling utility that generates interactive flamegraphs
 #[derive(Parser)]
@@ -10,6 +14,8 @@ pub struct Cli {
     pub command: Commands,
 }
 
+// Parsed once per run, so gen's many options needn't be boxed
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Generate flamegraph(s) from perf data file(s)
@@ -18,8 +24,242 @@ pub enum Commands {
         #[arg(short, long, default_value = "flamegraph.html")]
         output: String,
 
+        #[command(flatten)]
+        range: SampleRange,
+
+        #[command(flatten)]
+        shaping: StackShaping,
+
+        /// Shorten Rust symbols by replacing their generic parameters with "<…>"
+        #[arg(long)]
+        rust_elide_generics: bool,
+
+        /// Template argument nesting kept in C++ names; deeper argument lists
+        /// are shown as "<…>" (the tooltip keeps the full name)
+        #[arg(long, default_value_t = 1)]
+        template_depth: usize,
+
+        /// Input comes from `perf script -F+srcline`: read the source file:line printed
+        /// under each frame (frames are split by line, and the tooltip shows it)
+        #[arg(long)]
+        srcline: bool,
+
+        /// Keep the source file (and line) of Python and Node JIT frames
+        #[arg(long)]
+        jit_source: bool,
+
+        /// Mark Python and Node JIT frames as interpreted ("_[b]") or compiled ("_[j]")
+        #[arg(long)]
+        jit_tiers: bool,
+
+        /// Hide CPython interpreter frames between Python frames
+        #[arg(long)]
+        hide_interpreter_frames: bool,
+
+        /// Fold runs of JVM frames (libjvm.so) in Java processes into one frame,
+        /// such as "[jvm gc]" on GC threads
+        #[arg(long)]
+        fold_jvm_internals: bool,
+
+        /// "flame" merges stacks by name; "time" draws a flame chart per thread, with
+        /// frames laid out by when they were sampled (single input file only)
+        #[arg(long, value_enum, default_value_t = Chart::Flame)]
+        chart: Chart,
+
+        /// Reverse stacks so leaf functions are at the root, merging their callers
+        /// (the viewer can switch back without regenerating)
+        #[arg(long)]
+        inverted: bool,
+
+        /// Draw the graph top-down, with the root at the top
+        #[arg(long)]
+        icicle: bool,
+
+        /// How to draw the graph: "dom" (one element per frame) or "canvas" (a compact
+        /// frame table drawn on a canvas, for very large profiles)
+        #[arg(long, value_name = "RENDERER", default_value = "dom")]
+        renderer: Renderer,
+
+        /// Leave out frames narrower than this percentage of the graph (0 keeps all);
+        /// zooming in brings back the ones wide enough at that zoom
+        #[arg(long, value_name = "PERCENT", default_value_t = DEFAULT_MIN_WIDTH, value_parser = parse_percent)]
+        min_width: f64,
+
+        /// Show each frame's left-out children as one "[N small frames]" frame
+        #[arg(long)]
+        aggregate_small: bool,
+
+        /// Draw a sub-second offset heat map above the flamegraph for selecting
+        /// time ranges (single input file only)
+        #[arg(long)]
+        heatmap: bool,
+
+        /// Draw a timeline with one lane per thread above the flamegraph for
+        /// selecting time ranges of some threads (single input file only)
+        #[arg(long, conflicts_with = "heatmap")]
+        lanes: bool,
+
+        /// What frame colours show: "name", "module" (each library a hue), "kind"
+        /// (user, kernel, JIT, inlined) or "language" (the viewer can switch)
+        #[arg(long, value_name = "MODE", default_value = "name")]
+        color_by: ColorMode,
+
+        /// Initial page theme: "dark", "light" or "high-contrast" (the viewer's footer
+        /// can switch, and remembers the choice)
+        #[arg(long, value_name = "THEME", default_value = "dark")]
+        theme: Theme,
+
+        /// CSS file whose rules are added after the built-in styles
+        #[arg(long, value_name = "FILE")]
+        css: Option<String>,
+
+        /// Where the context menu's "Open in editor" sends frames with a source line
+        /// (see --srcline): "vscode", "idea", or a URL template with {path}, {line}
+        /// and {rev} placeholders (e.g. "https://git.example.com/blob/{rev}/{path}#L{line}")
+        #[arg(long, value_name = "TEMPLATE", default_value = "vscode")]
+        editor_url: Editor,
+
+        /// Source paths under BUILD_DIR are looked for under LOCAL_DIR instead, or taken
+        /// relative to BUILD_DIR when LOCAL_DIR is empty (repeatable; the first match wins)
+        #[arg(long, value_name = "BUILD_DIR=LOCAL_DIR")]
+        source_root: Vec<SourceRoot>,
+
+        /// Revision substituted for {rev} in --editor-url
+        #[arg(long, value_name = "REV", default_value = "HEAD")]
+        source_rev: String,
+
+        /// Add a Source tab showing the source files of sampled lines, each line shaded
+        /// by its self samples (files are read from disk, see --source-root)
+        #[arg(long, requires = "srcline")]
+        annotate_source: bool,
+
+        /// Generate a separate flamegraph per CPU in a batch page
+        #[arg(long, value_enum)]
+        split_by: Option<SplitBy>,
+
         /// Input perf data file(s)
         #[arg(required = true)]
         files: Vec<String>,
     },
+
+    /// Print the collapsed stacks of perf data file(s) as folded text
+    Collapse {
+        /// Output file path (standard output if not given)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        #[command(flatten)]
+        range: SampleRange,
+
+        #[command(flatten)]
+        shaping: StackShaping,
+
+        /// Input perf data file(s), merged into one set of stacks
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+
+    /// Print the functions with the most samples in perf data file(s)
+    Top {
+        /// Number of functions to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        #[command(flatten)]
+        range: SampleRange,
+
+        #[command(flatten)]
+        shaping: StackShaping,
+
+        /// Input perf data file(s), merged into one set of stacks
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+}
+
+/// Which samples of the input to use, shared by every subcommand.
+#[derive(Args)]
+pub struct SampleRange {
+    /// Only include samples taken on these CPUs (e.g. "0-3,8")
+    #[arg(long)]
+    pub cpu: Option<String>,
+
+    /// Ignore samples before this time: seconds after the first sample ("5", "250ms"),
+    /// a percentage of the recording ("25%"), or an absolute timestamp ("@158665.5")
+    #[arg(long)]
+    pub start: Option<TimeBound>,
+
+    /// Ignore samples after this time (same formats as --start)
+    #[arg(long)]
+    pub end: Option<TimeBound>,
+}
+
+/// What becomes of the input stacks before they are rendered or exported, shared
+/// by every subcommand.
+#[derive(Args)]
+#[command(group(ArgGroup::new("off_mode").args(["off_cpu", "off_wake"]).multiple(true)))]
+pub struct StackShaping {
+    /// Off-CPU analysis: weight sched:sched_switch stacks by the time each
+    /// thread stayed blocked until it was switched back in
+    #[arg(long)]
+    pub off_cpu: bool,
+
+    /// Off-wake analysis: like --off-cpu, with the stack of the thread that woke
+    /// the blocked one (from sched:sched_wakeup) stacked above a "--" frame
+    #[arg(long)]
+    pub off_wake: bool,
+
+    /// With --off-cpu/--off-wake, split blocked time by thread state (sleep, io, preempted)
+    #[arg(long, requires = "off_mode")]
+    pub off_cpu_states: bool,
+
+    /// TOML file of frame rules (rename, drop, collapse, truncate, merge)
+    /// applied after the built-in tidying
+    #[arg(long, value_name = "FILE")]
+    pub rules: Option<String>,
+
+    /// Fold recursion into one frame: direct self-recursion, or with "=all" any
+    /// frame already on the stack (the tooltip shows how many levels were folded)
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "direct")]
+    pub collapse_recursion: Option<Recursion>,
+
+    /// Only keep stacks with a frame matching this regex
+    #[arg(long, value_name = "REGEX")]
+    pub focus: Option<Regex>,
+
+    /// With --focus, start each kept stack at its outermost matching frame
+    #[arg(long, requires = "focus")]
+    pub reroot: bool,
+
+    /// Drop stacks with a frame matching this regex
+    #[arg(long, value_name = "REGEX")]
+    pub exclude: Option<Regex>,
+
+    /// Cut stacks above the outermost frame matching this regex
+    #[arg(long, value_name = "REGEX")]
+    pub prune: Option<Regex>,
+}
+
+/// Parse a percentage: a finite number from 0 to 100.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(pct),
+        _ => Err(format!("expected a percentage from 0 to 100, got \"{}\"", s)),
+    }
+}
+
+/// How to split a profile into multiple flamegraphs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SplitBy {
+    /// One flamegraph per CPU
+    Cpu,
+}
+
+/// How frames are laid out along the x-axis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Chart {
+    /// Stacks merged by name and sorted alphabetically
+    Flame,
+    /// Stacks in sample order, on a wall-time axis per thread
+    Time,
 }
@@ -1,48 +1,403 @@
 //! Flame graph HTML generator.
 //!
-//! Takes collapsed stack data and renders an interactive HTML flame graph
-//! with a modern dark theme.
+//! Takes a collapsed `Profile` and renders an interactive HTML flame graph
+//! with a dark theme by default, and light and high-contrast alternatives.
 //!
 //! # Example
 //!
 //! ```
-//! use std::collections::HashMap;
-//! use flamegraph::generate_flamegraph;
+//! use flamegraph::{generate_flamegraph_with_options, Options};
+//! use stackcollapse::collapse_perf_profile;
 //!
-//! let mut stacks = HashMap::new();
-//! stacks.insert("main;foo;bar".to_string(), 100);
-//! stacks.insert("main;foo;baz".to_string(), 50);
-//!
-//! let html = generate_flamegraph(&stacks, "My Flame Graph", None);
+//! let input = std::fs::read_to_string("perf.txt").unwrap();
+//! let profile = collapse_perf_profile(&input, &Default::default());
+//! let html = generate_flamegraph_with_options(&profile, "My Flame Graph", None, &Options::default());
 //! std::fs::write("flamegraph.html", html).unwrap();
 //! ```
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
 
+use crate::stackcollapse::{
+    Frame as ProfileFrame, FrameId, FrameInfo, FrameKind, Language, OFF_WAKE_SEPARATOR, Profile, SampleTable, SourceLine, SourceRoot,
+    Thread, function_stats, source_line_stats,
+};
+
+/// Number of sub-second rows in the heat map (20ms each).
+const HEATMAP_ROWS: u64 = 50;
+
+/// Rendering options for `generate_flamegraph_with_options` and
+/// `generate_batch_flamegraph_with_options`.
+#[derive(Debug, Clone)]
+pub struct Options<'a> {
+    /// Timestamped samples; when set, a sub-second offset heat map is drawn above
+    /// the graph and selecting a range in it re-renders the graph for those samples
+    /// (single graph only)
+    pub heatmap: Option<&'a SampleTable>,
+    /// Timestamped samples; when set, a timeline with one lane per thread is drawn
+    /// above the graph and selecting a range of lanes and time in it re-renders the
+    /// graph for those samples (single graph only)
+    pub lanes: Option<&'a SampleTable>,
+    /// Initial colour palette
+    pub palette: Palette,
+    /// Initial colour mode; the module, kind and language modes read the
+    /// profile's frames
+    pub color_by: ColorMode,
+    /// What the stack counts measure
+    pub units: Units,
+    /// Initially reverse stacks so leaf functions are at the root, showing
+    /// who calls them
+    pub inverted: bool,
+    /// Initially draw the graph top-down, with the root at the top
+    pub icicle: bool,
+    /// How the graph is drawn (single graph only)
+    pub renderer: Renderer,
+    /// Frames narrower than this percentage of the graph are left out of DOM
+    /// graphs; zooming in lays them out again
+    pub min_width: f64,
+    /// Replace each frame's pruned children with one "[N small frames]" frame
+    pub aggregate_small: bool,
+    /// Initial theme; the viewer remembers a theme picked in its footer instead
+    pub theme: Theme,
+    /// Extra stylesheet applied after the built-in styles
+    pub css: Option<&'a str>,
+    /// Where the context menu's "Open in editor" sends frames with a source line
+    pub editor: Editor,
+    /// Remapping of build directories in source paths, for editor links
+    pub source_roots: &'a [SourceRoot],
+    /// Revision substituted for `{rev}` in editor URL templates
+    pub source_rev: &'a str,
+    /// Text of source files by their path in the profile; when set, a Source tab
+    /// shows each file with sampled lines, shaded by their self counts
+    pub sources: Option<&'a HashMap<String, String>>,
+}
+
+/// Default `Options::min_width`, in percent.
+pub const DEFAULT_MIN_WIDTH: f64 = 0.08;
+
+impl Default for Options<'_> {
+    fn default() -> Self {
+        Self {
+            heatmap: None,
+            lanes: None,
+            palette: Palette::default(),
+            color_by: ColorMode::default(),
+            units: Units::default(),
+            inverted: false,
+            icicle: false,
+            renderer: Renderer::default(),
+            min_width: DEFAULT_MIN_WIDTH,
+            aggregate_small: false,
+            theme: Theme::default(),
+            css: None,
+            editor: Editor::default(),
+            source_roots: &[],
+            source_rev: "HEAD",
+            sources: None,
+        }
+    }
+}
+
+/// How the viewer draws a graph.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Renderer {
+    /// One element per frame; simple, but slow past tens of thousands of frames
+    #[default]
+    Dom,
+    /// A frame table drawn onto a canvas, for very large profiles
+    Canvas,
+}
+
+impl std::str::FromStr for Renderer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dom" => Ok(Renderer::Dom),
+            "canvas" => Ok(Renderer::Canvas),
+            _ => Err(format!("expected 'dom' or 'canvas', got '{}'", s)),
+        }
+    }
+}
+
+/// Page themes offered by the viewer's theme selector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+    HighContrast,
+}
+
+impl Theme {
+    const ALL: [Theme; 3] = [Theme::Dark, Theme::Light, Theme::HighContrast];
+
+    /// Value of the page's `data-theme` attribute.
+    fn id(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+            Theme::HighContrast => "high-contrast",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Theme::Dark => "Dark",
+            Theme::Light => "Light",
+            Theme::HighContrast => "High contrast",
+        }
+    }
+}
+
+impl std::str::FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Theme::ALL
+            .into_iter()
+            .find(|theme| theme.id() == s)
+            .ok_or_else(|| format!("expected 'dark', 'light' or 'high-contrast', got '{}'", s))
+    }
+}
+
+/// Where "Open in editor" links point.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Editor {
+    #[default]
+    VsCode,
+    Idea,
+    /// URL template with `{path}`, `{line}` and `{rev}` placeholders
+    Url(String),
+}
+
+impl Editor {
+    fn template(&self) -> &str {
+        match self {
+            Editor::VsCode => "vscode://file{path}:{line}",
+            Editor::Idea => "idea://open?file={path}&line={line}",
+            Editor::Url(template) => template,
+        }
+    }
+
+    /// Link to a source line, with its file moved by `opts.source_roots`.
+    fn url(&self, source: &SourceLine, opts: &Options) -> String {
+        let path = SourceRoot::remap(opts.source_roots, &source.file);
+        let mut escaped = String::new();
+        for b in path.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+                escaped.push(b as char);
+            } else {
+                write!(escaped, "%{:02X}", b).unwrap();
+            }
+        }
+        self.template()
+            .replace("{path}", &escaped)
+            .replace("{line}", &source.line.to_string())
+            .replace("{rev}", opts.source_rev)
+    }
+}
+
+impl std::str::FromStr for Editor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vscode" => Ok(Editor::VsCode),
+            "idea" => Ok(Editor::Idea),
+            _ if s.contains("{path}") => Ok(Editor::Url(s.to_string())),
+            _ => Err(format!("expected 'vscode', 'idea' or a URL template with {{path}}, got '{}'", s)),
+        }
+    }
+}
+
+/// Colour palettes offered by the viewer's palette selector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Palette {
+    Warm,
+    #[default]
+    Cool,
+    Neon,
+    Pastel,
+    Mono,
+    /// Greens, used to tell off-CPU graphs apart from on-CPU ones
+    OffCpu,
+}
+
+impl Palette {
+    const ALL: [Palette; 6] = [
+        Palette::Warm,
+        Palette::Cool,
+        Palette::Neon,
+        Palette::Pastel,
+        Palette::Mono,
+        Palette::OffCpu,
+    ];
+
+    /// Key of the palette in the page's JS `palettes` table.
+    fn id(self) -> &'static str {
+        match self {
+            Palette::Warm => "warm",
+            Palette::Cool => "cool",
+            Palette::Neon => "neon",
+            Palette::Pastel => "pastel",
+            Palette::Mono => "mono",
+            Palette::OffCpu => "offcpu",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Palette::Warm => "Warm",
+            Palette::Cool => "Cool",
+            Palette::Neon => "Neon",
+            Palette::Pastel => "Pastel",
+            Palette::Mono => "Monochrome",
+            Palette::OffCpu => "Off-CPU",
+        }
+    }
+
+    /// Hue, saturation and lightness for a name hash (mirrors the JS palettes).
+    fn hsl(self, hash: u32) -> (f64, f64, f64) {
+        let band = |shift: u32, modulo: u32| ((hash >> shift) % modulo) as f64 / 100.0;
+        match self {
+            Palette::Warm => ((hash % 60) as f64, 0.70 + band(8, 20), 0.35 + band(16, 10)),
+            Palette::Cool => (((hash % 120) + 180) as f64, 0.65 + band(8, 25), 0.38 + band(16, 12)),
+            Palette::Neon => ((hash % 360) as f64, 0.90 + band(8, 10), 0.45 + band(16, 10)),
+            Palette::Pastel => ((hash % 360) as f64, 0.40 + band(8, 20), 0.55 + band(16, 15)),
+            Palette::Mono => (220.0, 0.15 + band(8, 10), 0.25 + band(16, 30)),
+            Palette::OffCpu => (((hash % 70) + 100) as f64, 0.45 + band(8, 25), 0.32 + band(16, 12)),
+        }
+    }
+}
+
+/// What frame colours are derived from, offered by the viewer's colour mode selector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// A hash of the frame's name, in the palette
+    #[default]
+    Name,
+    /// A hash of the frame's module, in the palette, so each library gets a hue
+    Module,
+    /// Fixed colours for user, kernel, JIT, inlined and unknown frames
+    Kind,
+    /// Fixed colours for each guessed language
+    Language,
+}
+
+impl ColorMode {
+    const ALL: [ColorMode; 4] = [ColorMode::Name, ColorMode::Module, ColorMode::Kind, ColorMode::Language];
+
+    /// Value of the mode in the page's colour mode selector.
+    fn id(self) -> &'static str {
+        match self {
+            ColorMode::Name => "name",
+            ColorMode::Module => "module",
+            ColorMode::Kind => "kind",
+            ColorMode::Language => "language",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ColorMode::Name => "Name",
+            ColorMode::Module => "Module",
+            ColorMode::Kind => "Kind",
+            ColorMode::Language => "Language",
+        }
+    }
+}
+
+impl std::str::FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ColorMode::ALL
+            .into_iter()
+            .find(|mode| mode.id() == s)
+            .ok_or_else(|| format!("expected 'name', 'module', 'kind' or 'language', got '{}'", s))
+    }
+}
+
+/// What the counts attached to stacks measure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Units {
+    /// Sample counts (or periods)
+    #[default]
+    Samples,
+    /// Nanoseconds spent blocked off-CPU, shown as milliseconds
+    BlockedNanos,
+    /// Nanoseconds of wall time (flame charts), shown as milliseconds
+    WallNanos,
+}
+
+impl Units {
+    /// Key used by the page's JS `formatValue`.
+    fn id(self) -> &'static str {
+        match self {
+            Units::Samples => "samples",
+            Units::BlockedNanos | Units::WallNanos => "ns",
+        }
+    }
+
+    /// Label for the total stat.
+    fn total_label(self) -> &'static str {
+        match self {
+            Units::Samples => "Total Samples",
+            Units::BlockedNanos => "Total Blocked",
+            Units::WallNanos => "Duration",
+        }
+    }
+
+    /// Label for a frame's count in the tooltip.
+    fn count_label(self) -> &'static str {
+        match self {
+            Units::Samples => "Samples",
+            Units::BlockedNanos => "Blocked",
+            Units::WallNanos => "Duration",
+        }
+    }
+}
+
+/// Stacks of viewer frame ids (see `FrameDetails`), root first, with their counts.
+type ViewerStacks = HashMap<Vec<usize>, u64>;
+
 /// A frame in the flame graph.
 #[derive(Debug, Clone)]
 struct Frame {
+    /// Viewer id (see `FrameDetails`); `None` for the root and aggregated frames
+    id: Option<usize>,
     name: String,
     depth: usize,
     start: u64,
     end: u64,
+    /// Stands for several pruned frames (see `prune_frames`)
+    aggregate: bool,
 }
 
-/// Process stacks into frames using the flow/merge algorithm.
-fn process_stacks(stacks: &HashMap<String, u64>) -> (Vec<Frame>, u64, usize) {
+/// Process stacks into frames using the flow/merge algorithm. Frames merge by id,
+/// and stacks are sorted by their ids, which follow the frames' names.
+fn process_stacks(stacks: &ViewerStacks, details: &FrameDetails) -> (Vec<Frame>, u64, usize) {
     let mut sorted: Vec<_> = stacks.iter().collect();
     sorted.sort_by(|a, b| a.0.cmp(b.0));
 
+    let frame = |id: Option<usize>, depth, start, end| Frame {
+        id,
+        name: id.map_or(String::new(), |id| details.names[id].clone()),
+        depth,
+        start,
+        end,
+        aggregate: false,
+    };
     let mut frames = Vec::new();
-    let mut last_stack: Vec<&str> = Vec::new();
+    let mut last_stack: Vec<Option<usize>> = Vec::new();
     let mut time: u64 = 0;
     let mut depth_max: usize = 0;
-    let mut open_frames: HashMap<(String, usize), u64> = HashMap::new();
+    let mut open_frames: HashMap<(Option<usize>, usize), u64> = HashMap::new();
 
-    for (stack_str, count) in &sorted {
-        let this_stack: Vec<&str> = std::iter::once("")
-            .chain(stack_str.split(';'))
+    for (stack, count) in &sorted {
+        let this_stack: Vec<Option<usize>> = std::iter::once(None)
+            .chain(stack.iter().copied().map(Some))
             .collect();
 
         let len_same = last_stack
@@ -53,23 +408,15 @@ fn process_stacks(stacks: &HashMap<String, u64>) -> (Vec<Frame>, u64, usize) {
 
         // Close frames no longer in path
         for i in (len_same..last_stack.len()).rev() {
-            let name = last_stack[i].to_string();
-            let key = (name.clone(), i);
-            if let Some(start) = open_frames.remove(&key) {
-                frames.push(Frame {
-                    name,
-                    depth: i,
-                    start,
-                    end: time,
-                });
+            if let Some(start) = open_frames.remove(&(last_stack[i], i)) {
+                frames.push(frame(last_stack[i], i, start, time));
                 depth_max = depth_max.max(i);
             }
         }
 
         // Open new frames
-        for i in len_same..this_stack.len() {
-            let name = this_stack[i].to_string();
-            open_frames.insert((name, i), time);
+        for (i, &id) in this_stack.iter().enumerate().skip(len_same) {
+            open_frames.insert((id, i), time);
         }
 
         time += *count;
@@ -78,15 +425,8 @@ fn process_stacks(stacks: &HashMap<String, u64>) -> (Vec<Frame>, u64, usize) {
 
     // Close remaining frames
     for i in (0..last_stack.len()).rev() {
-        let name = last_stack[i].to_string();
-        let key = (name.clone(), i);
-        if let Some(start) = open_frames.remove(&key) {
-            frames.push(Frame {
-                name,
-                depth: i,
-                start,
-                end: time,
-            });
+        if let Some(start) = open_frames.remove(&(last_stack[i], i)) {
+            frames.push(frame(last_stack[i], i, start, time));
             depth_max = depth_max.max(i);
         }
     }
@@ -94,24 +434,547 @@ fn process_stacks(stacks: &HashMap<String, u64>) -> (Vec<Frame>, u64, usize) {
     (frames, time, depth_max)
 }
 
+/// One thread's samples in time order, for flame charts: runs of consecutive
+/// samples of the same stack, in nanoseconds since the first sample of the profile.
+#[derive(Debug, Clone)]
+struct Timeline {
+    /// Distinct stacks of the thread, in frame ids of the sample table's profile
+    /// until `to_viewer` turns them into viewer ids
+    stacks: Vec<Vec<usize>>,
+    /// `(start, end, index into stacks)`, in time order
+    runs: Vec<(u64, u64, usize)>,
+    /// Length of the time axis shared by every thread
+    total: u64,
+}
+
+impl Timeline {
+    /// Time spent in each stack, used as the graph's stack counts.
+    fn stack_times(&self) -> HashMap<Vec<FrameId>, u64> {
+        let mut times = HashMap::new();
+        for &(start, end, stack) in &self.runs {
+            *times.entry(self.stacks[stack].clone()).or_insert(0) += end - start;
+        }
+        times
+    }
+
+    /// The timeline with its stacks in viewer ids, given the viewer id of each frame.
+    fn to_viewer(&self, ids: &[usize]) -> Timeline {
+        Timeline {
+            stacks: self.stacks.iter().map(|stack| stack.iter().map(|&id| ids[id]).collect()).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// A thread's timestamped samples as `(ns since the first sample, index into samples)`.
+type ThreadSamples = Vec<(u64, usize)>;
+
+/// Timestamped samples of each thread in time order, along with the sampling
+/// interval (the median gap between a thread's samples) and the order to list
+/// threads in: by process, then thread, leaving out threads without timestamped
+/// samples.
+fn samples_by_thread(table: &SampleTable, first: f64) -> (Vec<ThreadSamples>, u64, Vec<usize>) {
+    let mut by_thread: Vec<ThreadSamples> = vec![Vec::new(); table.threads.len()];
+    for (i, sample) in table.samples.iter().enumerate() {
+        if let Some(time) = sample.time {
+            by_thread[sample.thread].push((((time - first) * 1e9).round() as u64, i));
+        }
+    }
+    for samples in &mut by_thread {
+        samples.sort_by_key(|&(time, _)| time);
+    }
+
+    let mut gaps: Vec<u64> = by_thread
+        .iter()
+        .flat_map(|samples| samples.windows(2).map(|w| w[1].0 - w[0].0))
+        .filter(|&gap| gap > 0)
+        .collect();
+    gaps.sort_unstable();
+    let interval = gaps.get(gaps.len() / 2).copied().unwrap_or(1_000_000);
+
+    let number = |s: &str| s.parse::<u64>().unwrap_or(u64::MAX);
+    let mut order: Vec<usize> = (0..table.threads.len()).filter(|&i| !by_thread[i].is_empty()).collect();
+    order.sort_by_key(|&i| (number(&table.threads[i].pid), number(&table.threads[i].tid)));
+
+    (by_thread, interval, order)
+}
+
+/// Split timestamped samples into per-thread timelines on one time axis. A sample
+/// lasts until the thread's next one, unless that comes more than 1.5 sampling
+/// intervals later; then it lasts one interval and the thread shows as idle until
+/// the next.
+fn thread_timelines(table: &SampleTable) -> Vec<(&Thread, Timeline)> {
+    let Some((first, last)) = table.time_range() else {
+        return Vec::new();
+    };
+    let (by_thread, interval, order) = samples_by_thread(table, first);
+    let total = ((last - first) * 1e9).round() as u64 + interval;
+
+    order
+        .into_iter()
+        .map(|i| {
+            let samples = &by_thread[i];
+            let mut ids = HashMap::new();
+            let mut stacks = Vec::new();
+            let mut runs: Vec<(u64, u64, usize)> = Vec::new();
+            for (j, &(start, sample)) in samples.iter().enumerate() {
+                let end = match samples.get(j + 1) {
+                    Some(&(next, _)) if next - start <= interval * 3 / 2 => next,
+                    _ => start + interval,
+                };
+                // Samples sharing a timestamp: the last one stands for them
+                if end == start {
+                    continue;
+                }
+                let stack = table.samples[sample].stack;
+                let id = *ids.entry(stack).or_insert_with(|| {
+                    stacks.push(table.stacks[stack].clone());
+                    stacks.len() - 1
+                });
+                match runs.last_mut() {
+                    Some(run) if run.1 == start && run.2 == id => run.1 = end,
+                    _ => runs.push((start, end, id)),
+                }
+            }
+            (&table.threads[i], Timeline { stacks, runs, total })
+        })
+        .collect()
+}
+
+/// Lay a timeline out as frames: a root spanning the time axis and, above it, each
+/// run's stack, continuing the previous run's frames while the runs touch and the
+/// frames below match. The viewer's `layoutTimeline` mirrors this.
+fn layout_timeline(timeline: &Timeline, details: &FrameDetails) -> (Vec<Frame>, u64, usize) {
+    let frame = |id: Option<usize>, depth, start, end| Frame {
+        id,
+        name: id.map_or(String::new(), |id| details.names[id].clone()),
+        depth,
+        start,
+        end,
+        aggregate: false,
+    };
+    let mut frames = vec![frame(None, 0, 0, timeline.total)];
+    let mut depth_max = 0;
+    let mut last: &[usize] = &[];
+    let mut open: Vec<u64> = Vec::new();
+    let mut last_end = None;
+
+    for &(start, end, stack) in &timeline.runs {
+        let this = &timeline.stacks[stack][..];
+        let same = if last_end == Some(start) {
+            last.iter().zip(this).take_while(|(a, b)| a == b).count()
+        } else {
+            0
+        };
+        for i in (same..last.len()).rev() {
+            frames.push(frame(Some(last[i]), i + 1, open[i], last_end.unwrap_or(start)));
+            depth_max = depth_max.max(i + 1);
+        }
+        open.truncate(same);
+        open.resize(this.len(), start);
+        last = this;
+        last_end = Some(end);
+    }
+    for i in (0..last.len()).rev() {
+        frames.push(frame(Some(last[i]), i + 1, open[i], last_end.unwrap_or(0)));
+        depth_max = depth_max.max(i + 1);
+    }
+
+    (frames, timeline.total, depth_max)
+}
+
+/// The viewer's `timeline`, or `null` for flame graphs.
+fn timeline_js(timeline: Option<&Timeline>) -> String {
+    let Some(timeline) = timeline else {
+        return "null".to_string();
+    };
+    let stacks: Vec<String> = timeline.stacks.iter().map(|s| format!("\"{}\"", stack_key(s))).collect();
+    let runs: Vec<String> = timeline.runs.iter().map(|(start, end, stack)| format!("{},{},{}", start, end, stack)).collect();
+    format!("{{ stacks: [{}], runs: [{}], total: {} }}", stacks.join(","), runs.join(","), timeline.total)
+}
+
+/// Leave out frames narrower than `min_width` percent of the graph, along with
+/// everything above them. With `aggregate`, each frame's pruned children are
+/// replaced by one "[N small frames]" frame laid out after its other children,
+/// so the children still add up to their parent.
+///
+/// The viewer's `pruneLayout` mirrors this, and must place frames identically.
+fn prune_frames(frames: &[Frame], total: u64, min_width: f64, aggregate: bool) -> Vec<Frame> {
+    // Frames of each depth, by start
+    let mut levels: Vec<Vec<&Frame>> = Vec::new();
+    for frame in frames.iter().filter(|f| f.end > f.start) {
+        if levels.len() <= frame.depth {
+            levels.resize(frame.depth + 1, Vec::new());
+        }
+        levels[frame.depth].push(frame);
+    }
+    for level in &mut levels {
+        level.sort_by_key(|f| f.start);
+    }
+
+    let small = |samples: u64| (samples as f64 / total as f64) * 100.0 < min_width;
+    let mut out = Vec::new();
+    // Frames to place, with their new start
+    let mut pending: Vec<(&Frame, u64)> = levels.first().map_or(Vec::new(), |roots| {
+        roots.iter().filter(|f| !small(f.end - f.start)).map(|f| (*f, f.start)).collect()
+    });
+
+    while let Some((frame, start)) = pending.pop() {
+        out.push(Frame { start, end: start + (frame.end - frame.start), ..frame.clone() });
+
+        let Some(level) = levels.get(frame.depth + 1) else { continue };
+        let from = level.partition_point(|c| c.start < frame.start);
+        let to = level.partition_point(|c| c.start < frame.end);
+        let children = &level[from..to];
+
+        if !aggregate {
+            pending.extend(children.iter()
+                .filter(|c| !small(c.end - c.start))
+                .map(|c| (*c, start + (c.start - frame.start))));
+            continue;
+        }
+
+        // Children follow the frame's self time; pruned ones are gathered at the end
+        let children_samples: u64 = children.iter().map(|c| c.end - c.start).sum();
+        let mut cursor = start + (frame.end - frame.start) - children_samples;
+        let mut pruned = 0;
+        for child in children {
+            if small(child.end - child.start) {
+                pruned += 1;
+            } else {
+                pending.push((child, cursor));
+                cursor += child.end - child.start;
+            }
+        }
+        if pruned > 0 {
+            out.push(Frame {
+                id: None,
+                name: format!("[{} small frame{}]", pruned, if pruned == 1 { "" } else { "s" }),
+                depth: frame.depth + 1,
+                start: cursor,
+                end: start + (frame.end - frame.start),
+                aggregate: true,
+            });
+        }
+    }
+
+    out.sort_by_key(|f| (f.depth, f.start));
+    out
+}
+
+/// Colour of aggregated small frames (slate).
+const AGGREGATE_RGB: (u8, u8, u8) = (71, 85, 105);
+
 /// Generate a color for a function name (deterministic based on name hash).
-fn color_for_name(name: &str) -> (u8, u8, u8) {
+fn color_for_name(name: &str, palette: Palette) -> (u8, u8, u8) {
     if name.is_empty() {
         return (99, 102, 241); // Indigo for root
     }
+    if name == OFF_WAKE_SEPARATOR {
+        return (100, 116, 139); // Slate for the off-wake separator
+    }
     
     let hash: u32 = name.bytes().fold(0u32, |acc, b| {
         acc.wrapping_mul(31).wrapping_add(b as u32)
     });
     
-    // Cool palette: Cyan-blue-purple range (180-300)
-    let hue = ((hash % 120) + 180) as f64;
-    let saturation = 0.65 + (((hash >> 8) % 25) as f64 / 100.0); // 0.65-0.90
-    let lightness = 0.38 + (((hash >> 16) % 12) as f64 / 100.0); // 0.38-0.50
-    
+    let (hue, saturation, lightness) = palette.hsl(hash);
     hsl_to_rgb(hue, saturation, lightness)
 }
 
+/// Colour of frames that the colour mode has nothing to go on for, such as
+/// process names when colouring by module.
+const UNCATEGORIZED_RGB: (u8, u8, u8) = (100, 116, 139);
+
+fn kind_rgb(kind: FrameKind) -> (u8, u8, u8) {
+    match kind {
+        FrameKind::User => (59, 130, 246),
+        FrameKind::Kernel => (234, 88, 12),
+        FrameKind::Jit => (22, 163, 74),
+        FrameKind::Inlined => (8, 145, 178),
+        FrameKind::Unknown => (71, 85, 105),
+    }
+}
+
+fn language_rgb(language: Language) -> (u8, u8, u8) {
+    match language {
+        Language::Rust => (206, 66, 43),
+        Language::Cpp => (219, 39, 119),
+        Language::Java => (176, 114, 25),
+        Language::Python => (53, 114, 165),
+        Language::JavaScript => (161, 122, 8),
+        Language::Native => (124, 58, 237),
+    }
+}
+
+/// Colour of a frame under the options' colour mode (mirrors the JS `frameColor`).
+fn frame_rgb(frame: &Frame, details: &FrameDetails, opts: &Options) -> (u8, u8, u8) {
+    let name = &frame.name;
+    if name.is_empty() || name == OFF_WAKE_SEPARATOR {
+        return color_for_name(name, opts.palette);
+    }
+    let origin = frame.id.map(|id| &details.origins[id]);
+    match opts.color_by {
+        ColorMode::Name => color_for_name(name, opts.palette),
+        ColorMode::Module => origin
+            .and_then(|o| o.module.as_deref())
+            .map_or(UNCATEGORIZED_RGB, |module| color_for_name(module, opts.palette)),
+        ColorMode::Kind => origin
+            .and_then(|o| o.kind)
+            .map_or(UNCATEGORIZED_RGB, kind_rgb),
+        ColorMode::Language => origin
+            .and_then(|o| o.language)
+            .map_or(UNCATEGORIZED_RGB, language_rgb),
+    }
+}
+
+/// `<option>` list for the palette selector with `selected` marked.
+fn palette_options_html(selected: Palette) -> String {
+    Palette::ALL
+        .iter()
+        .map(|&p| {
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                p.id(),
+                if p == selected { " selected" } else { "" },
+                p.label()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n                ")
+}
+
+fn color_mode_options_html(selected: ColorMode) -> String {
+    ColorMode::ALL
+        .iter()
+        .map(|&m| {
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                m.id(),
+                if m == selected { " selected" } else { "" },
+                m.label()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n                ")
+}
+
+fn theme_options_html(selected: Theme) -> String {
+    Theme::ALL
+        .iter()
+        .map(|&t| {
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                t.id(),
+                if t == selected { " selected" } else { "" },
+                t.label()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n                ")
+}
+
+/// The `--css` stylesheet, if any, as a style element following the built-in one.
+fn custom_css_html(opts: &Options) -> String {
+    opts.css
+        .map(|css| format!("<style>\n{}\n</style>\n", css))
+        .unwrap_or_default()
+}
+
+/// Orientations offered by the viewer's view selector, as `(id, label)`. The
+/// page's JS reads "inverted" and "icicle" back out of the id.
+const VIEWS: [(&str, &str); 4] = [
+    ("flame", "Flame graph"),
+    ("icicle", "Icicle"),
+    ("inverted", "Inverted flame graph"),
+    ("inverted-icicle", "Inverted icicle"),
+];
+
+/// `<option>` list for the view selector with the initial view marked. Flame
+/// charts can't be inverted, so only offer them flame and icicle.
+fn view_options_html(opts: &Options, time_chart: bool) -> String {
+    let selected = match (opts.inverted, opts.icicle) {
+        (false, false) => "flame",
+        (false, true) => "icicle",
+        (true, false) => "inverted",
+        (true, true) => "inverted-icicle",
+    };
+    VIEWS
+        .iter()
+        .filter(|(id, _)| !(time_chart && id.starts_with("inverted")))
+        .map(|&(id, label)| {
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                id,
+                if id == selected { " selected" } else { "" },
+                label
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n                ")
+}
+
+/// Rows of the viewer's function table: `[name, self, total, module, call sites,
+/// frame id]`, the id being that of the function's first frame in `profile`, the
+/// `index`th profile of `details`.
+fn function_table_js(profile: &Profile, index: usize, details: &FrameDetails) -> String {
+    let rows: Vec<String> = function_stats(profile)
+        .iter()
+        .map(|f| {
+            let id = details.ids[index][f.frame];
+            format!(
+                "[{},{},{},{},{},{}]",
+                json_string(&f.name),
+                f.self_count,
+                f.total,
+                json_string(details.origins[id].module.as_deref().unwrap_or("")),
+                f.call_sites,
+                id
+            )
+        })
+        .collect();
+    format!("[{}]", rows.join(","))
+}
+
+/// The viewer's `sourceFiles`: for each file with self samples in `profile` whose
+/// text `opts.sources` has, `[file, [text, [line, count, ...]]]`.
+fn source_files_js(profile: &Profile, opts: &Options) -> String {
+    let Some(sources) = opts.sources else {
+        return "[]".to_string();
+    };
+    let files: Vec<String> = source_line_stats(profile)
+        .into_iter()
+        .filter_map(|(file, lines)| {
+            let text = sources.get(file)?;
+            let counts: Vec<String> = lines.iter().map(|(line, count)| format!("{},{}", line, count)).collect();
+            Some(format!("[{},[{},[{}]]]", json_string(file), json_string(text), counts.join(",")))
+        })
+        .collect();
+    format!("[{}]", files.join(","))
+}
+
+/// Markup of the graph/function table/source tab bar and the (initially hidden)
+/// table and source views; `suffix` is appended to element ids as for
+/// `sandwich_html`. The Source tab stays hidden unless there are annotated files.
+fn function_table_html(suffix: &str) -> (String, String) {
+    let tabs = format!(r#"<div class="tabs">
+        <button class="tab active" id="graphTab{suffix}">Flame Graph</button>
+        <button class="tab" id="tableTab{suffix}">Functions</button>
+        <button class="tab" id="sourceTab{suffix}" style="display:none">Source</button>
+    </div>"#);
+    let table = format!(r#"<div class="table-view" id="tableView{suffix}" style="display:none">
+        <div class="table-header">
+            <input type="text" class="table-filter" id="tableFilter{suffix}" placeholder="Filter functions or modules (regex)..." />
+            <span class="stat-label" id="tableCount{suffix}"></span>
+        </div>
+        <div class="table-scroll">
+            <table class="function-table" id="functionTable{suffix}">
+                <thead>
+                    <tr>
+                        <th data-col="0">Function</th>
+                        <th data-col="1" class="num">Self</th>
+                        <th data-col="1" class="num">Self %</th>
+                        <th data-col="2" class="num">Total</th>
+                        <th data-col="2" class="num">Total %</th>
+                        <th data-col="3">Module</th>
+                        <th data-col="4" class="num">Call Sites</th>
+                    </tr>
+                </thead>
+                <tbody id="functionRows{suffix}"></tbody>
+            </table>
+        </div>
+    </div>
+    <div class="table-view" id="sourceView{suffix}" style="display:none">
+        <div class="table-header">
+            <select class="table-filter" id="sourceFile{suffix}"></select>
+            <span class="stat-label" id="sourceCount{suffix}"></span>
+        </div>
+        <div class="table-scroll">
+            <table class="function-table source-table">
+                <thead>
+                    <tr>
+                        <th class="num">Line</th>
+                        <th class="num">Self</th>
+                        <th class="num">Self %</th>
+                        <th>Source</th>
+                    </tr>
+                </thead>
+                <tbody id="sourceRows{suffix}"></tbody>
+            </table>
+        </div>
+    </div>"#);
+    (tabs, table)
+}
+
+/// Class attribute of the chart element for the initial view. The canvas renderer
+/// draws icicles itself.
+fn chart_class(opts: &Options) -> &'static str {
+    if opts.icicle && opts.renderer == Renderer::Dom { "chart icicle" } else { "chart" }
+}
+
+/// The canvas renderer's `frameTable`: interned frames as their names and viewer
+/// ids (-1 for the root), and a flat `[frame, start, end, depth, ...]` array sorted
+/// by depth, then start. Every frame is included, however narrow, so zooming in
+/// reveals them.
+fn frame_table_js(frames: &[Frame]) -> String {
+    let mut sorted: Vec<&Frame> = frames.iter().filter(|f| f.end > f.start).collect();
+    sorted.sort_by_key(|f| (f.depth, f.start));
+
+    // Frames without an id (the root and aggregates) go by their name
+    let mut slots: HashMap<(Option<usize>, &str), usize> = HashMap::new();
+    let mut names = Vec::new();
+    let mut ids = Vec::new();
+    let mut table = Vec::with_capacity(sorted.len() * 4);
+    for frame in sorted {
+        let slot = *slots.entry((frame.id, &frame.name)).or_insert_with(|| {
+            names.push(json_string(if frame.name.is_empty() { "all" } else { &frame.name }));
+            ids.push(frame.id.map_or("-1".to_string(), |id| id.to_string()));
+            names.len() - 1
+        });
+        table.push(format!("{},{},{},{}", slot, frame.start, frame.end, frame.depth));
+    }
+    format!("{{ names: [{}], ids: [{}], frames: [{}] }}", names.join(","), ids.join(","), table.join(","))
+}
+
+/// Markup of the (initially hidden) callers/callees view; `suffix` is appended to
+/// element ids to keep them unique on batch pages.
+fn sandwich_html(suffix: &str) -> String {
+    format!(r#"<div class="sandwich" id="sandwich{suffix}" style="display:none">
+        <div class="sandwich-header">
+            <span class="stat-label">Callers and callees of</span>
+            <span class="sandwich-name" id="sandwichName{suffix}"></span>
+            <button class="btn" id="closeSandwich{suffix}">Close</button>
+        </div>
+        <div class="chart-container">
+            <div class="chart" id="sandwichCallers{suffix}"></div>
+        </div>
+        <div class="chart-container">
+            <div class="chart icicle" id="sandwichCallees{suffix}"></div>
+        </div>
+    </div>"#)
+}
+
+/// Reverse every stack so its leaf function becomes the root.
+fn invert_stacks(stacks: &ViewerStacks) -> ViewerStacks {
+    stacks
+        .iter()
+        .map(|(stack, &count)| (stack.iter().rev().copied().collect(), count))
+        .collect()
+}
+
+/// A stack as the viewer keys it: its frame ids joined by `;`.
+fn stack_key(stack: &[usize]) -> String {
+    let ids: Vec<String> = stack.iter().map(|id| id.to_string()).collect();
+    ids.join(";")
+}
+
+/// Entries of the viewer's `stackCounts` map, which it re-renders from when
+/// switching between normal and inverted views.
+fn stack_counts_js(stacks: &ViewerStacks) -> String {
+    let keyed: HashMap<String, u64> = stacks.iter().map(|(stack, &count)| (stack_key(stack), count)).collect();
+    js_map_entries(&keyed, |count| count.to_string())
+}
+
 fn hsl_to_rgb(h: f64, s: f64, l: f64) -> (u8, u8, u8) {
     let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
     let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
@@ -126,61 +989,2017 @@ fn hsl_to_rgb(h: f64, s: f64, l: f64) -> (u8, u8, u8) {
         _ => (c, 0.0, x),
     };
     
-    (
-        ((r + m) * 255.0) as u8,
-        ((g + m) * 255.0) as u8,
-        ((b + m) * 255.0) as u8,
-    )
-}
-
-fn escape_html(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&#39;")
-}
-
-fn format_samples(n: u64) -> String {
-    let s = n.to_string();
-    let mut result = String::new();
-    for (i, c) in s.chars().enumerate() {
-        if i > 0 && (s.len() - i) % 3 == 0 {
-            result.push(',');
+    (
+        ((r + m) * 255.0) as u8,
+        ((g + m) * 255.0) as u8,
+        ((b + m) * 255.0) as u8,
+    )
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Format a stack count for display in the given units.
+fn format_value(n: u64, units: Units) -> String {
+    match units {
+        Units::Samples => format_samples(n),
+        Units::BlockedNanos | Units::WallNanos => {
+            let hundredths_ms = (n + 5_000) / 10_000;
+            format!("{}.{:02} ms", format_samples(hundredths_ms / 100), hundredths_ms % 100)
+        }
+    }
+}
+
+fn format_samples(n: u64) -> String {
+    let s = n.to_string();
+    let mut result = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if i > 0 && (s.len() - i).is_multiple_of(3) {
+            result.push(',');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Encode a string as a JSON string literal that is safe to embed in a `<script>`.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '<' => out.push_str("\\u003c"),
+            c if (c as u32) < 0x20 || c == '\u{2028}' || c == '\u{2029}' => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Build a JS array of `[key, value]` pairs, sorted by key, to construct a `Map` from.
+fn js_map_entries<V>(map: &HashMap<String, V>, value: impl Fn(&V) -> String) -> String {
+    let mut pairs: Vec<_> = map.iter().collect();
+    pairs.sort_by(|a, b| a.0.cmp(b.0));
+    let pairs: Vec<String> = pairs
+        .into_iter()
+        .map(|(key, v)| format!("[{},{}]", json_string(key), value(v)))
+        .collect();
+    format!("[{}]", pairs.join(","))
+}
+
+/// Where the code of a frame came from.
+#[derive(Debug, Clone, Default)]
+struct Origin {
+    /// File name of the module
+    module: Option<String>,
+    /// Full path of the module
+    path: Option<String>,
+    kind: Option<FrameKind>,
+    language: Option<Language>,
+    source: Option<SourceLine>,
+    address: Option<u64>,
+}
+
+/// The frames of one or more profiles as the viewer knows them. The viewer refers
+/// to a frame by its index here, its id; equal frames of different profiles share
+/// an id, and ids follow the frames' names, so sorting stacks by id sorts them by
+/// name.
+#[derive(Debug, Clone, Default)]
+struct FrameDetails {
+    /// Folded name of each frame
+    names: Vec<String>,
+    origins: Vec<Origin>,
+    /// For each profile, the viewer id of each of its frames
+    ids: Vec<Vec<usize>>,
+    info: FrameInfo,
+}
+
+impl FrameDetails {
+    fn new<'a>(profiles: impl IntoIterator<Item = &'a Profile>) -> Self {
+        let profiles: Vec<&Profile> = profiles.into_iter().collect();
+        let mut details = FrameDetails::default();
+
+        // Each distinct frame with the first address it was sampled at
+        let mut addresses: HashMap<&ProfileFrame, Option<u64>> = HashMap::new();
+        for profile in &profiles {
+            for (frame, &address) in profile.frames.iter().zip(&profile.addresses) {
+                let first = addresses.entry(frame).or_insert(address);
+                *first = first.or(address);
+            }
+            details.info.merge(&profile.info);
+        }
+        let mut frames: Vec<_> = addresses.into_iter().collect();
+        frames.sort_by_cached_key(|(frame, _)| {
+            (
+                frame.folded_name().into_owned(),
+                frame.name.clone(),
+                frame.module.clone(),
+                frame.source.as_ref().map(|s| (s.file.clone(), s.line)),
+                frame.kind.map(FrameKind::id),
+                frame.language.map(Language::id),
+            )
+        });
+
+        let index: HashMap<&ProfileFrame, usize> =
+            frames.iter().enumerate().map(|(id, &(frame, _))| (frame, id)).collect();
+        details.ids = profiles.iter().map(|p| p.frames.iter().map(|f| index[f]).collect()).collect();
+        for (frame, address) in frames {
+            details.names.push(frame.folded_name().into_owned());
+            details.origins.push(Origin {
+                module: frame.module.as_ref().map(|m| m.rsplit('/').next().unwrap_or(m).to_string()),
+                path: frame.module.clone(),
+                kind: frame.kind,
+                language: frame.language,
+                source: frame.source.clone(),
+                address,
+            });
+        }
+        details
+    }
+
+    /// The stacks of `profile`, the `index`th profile given to `new`, in viewer ids.
+    fn stacks(&self, profile: &Profile, index: usize) -> ViewerStacks {
+        let ids = &self.ids[index];
+        let mut stacks = ViewerStacks::new();
+        for (stack, &count) in &profile.stacks {
+            *stacks.entry(stack.iter().map(|&id| ids[id]).collect()).or_insert(0) += count;
+        }
+        stacks
+    }
+}
+
+/// The viewer's `frameNames`: the folded name of each frame, by id.
+fn frame_names_js(details: &FrameDetails) -> String {
+    let names: Vec<String> = details.names.iter().map(|name| json_string(name)).collect();
+    format!("[{}]", names.join(","))
+}
+
+/// Entries of the viewer's `fullNames` map.
+fn full_names_js(details: &FrameDetails) -> String {
+    js_map_entries(&details.info.full_names, |full| json_string(full))
+}
+
+/// Entries of the viewer's `recursionLevels` map.
+fn recursion_js(details: &FrameDetails) -> String {
+    js_map_entries(&details.info.recursion, |levels| levels.to_string())
+}
+
+/// The viewer's `frameOrigins`: by frame id, `[module, kind, language, module
+/// path, source, address, editor link]` with "" where unknown, or `null` for frames
+/// flg added itself.
+fn frame_origins_js(details: &FrameDetails, opts: &Options) -> String {
+    let entries: Vec<String> = details
+        .origins
+        .iter()
+        .map(|origin| {
+            if origin.kind.is_none() {
+                return "null".to_string();
+            }
+            format!(
+                "[{},\"{}\",\"{}\",{},{},\"{}\",{}]",
+                json_string(origin.module.as_deref().unwrap_or("")),
+                origin.kind.map_or("", |k| k.id()),
+                origin.language.map_or("", |l| l.id()),
+                json_string(origin.path.as_deref().unwrap_or("")),
+                json_string(&origin.source.as_ref().map_or(String::new(), |s| s.to_string())),
+                origin.address.map_or(String::new(), |a| format!("{:#x}", a)),
+                json_string(&origin.source.as_ref().map_or(String::new(), |s| opts.editor.url(s, opts)))
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// The viewer's `colorCategories`: label and colour of each kind and language, in
+/// legend order, keyed by colour mode and then category id.
+fn color_categories_js() -> String {
+    let rgb = |(r, g, b): (u8, u8, u8)| format!("rgb({}, {}, {})", r, g, b);
+    let kinds: Vec<String> = FrameKind::ALL
+        .iter()
+        .map(|&k| format!("{}: [\"{}\", \"{}\"]", k.id(), k.label(), rgb(kind_rgb(k))))
+        .collect();
+    let languages: Vec<String> = Language::ALL
+        .iter()
+        .map(|&l| format!("{}: [\"{}\", \"{}\"]", l.id(), l.label(), rgb(language_rgb(l))))
+        .collect();
+    format!(
+        "{{ kind: {{ {} }}, language: {{ {} }}, other: [\"Other\", \"{}\"] }}",
+        kinds.join(", "),
+        languages.join(", "),
+        rgb(UNCATEGORIZED_RGB)
+    )
+}
+
+/// A sample table's stack as a viewer stack key string, given the viewer id of each frame.
+fn viewer_stack_json(stack: &[FrameId], ids: &[usize]) -> String {
+    let stack: Vec<usize> = stack.iter().map(|&id| ids[id]).collect();
+    format!("\"{}\"", stack_key(&stack))
+}
+
+/// Build the markup and script for the sub-second offset heat map.
+///
+/// Samples are bucketed to the millisecond relative to the first sample and
+/// embedded as a flat `[ms, stack, weight, ...]` array alongside the stack table,
+/// whose stacks are keyed by viewer id; `ids` gives the id of each of the table's frames.
+fn heatmap_parts(table: &SampleTable, ids: &[usize]) -> Option<(String, String)> {
+    let (first, last) = table.time_range()?;
+
+    let mut buckets: BTreeMap<(u64, usize), u64> = BTreeMap::new();
+    for sample in &table.samples {
+        if let Some(t) = sample.time {
+            let ms = ((t - first) * 1000.0).floor() as u64;
+            *buckets.entry((ms, sample.stack)).or_insert(0) += sample.weight;
+        }
+    }
+
+    // Only embed stacks that are referenced, renumbered densely
+    let mut stack_ids: HashMap<usize, usize> = HashMap::new();
+    let mut stacks_json = Vec::new();
+    let mut samples_json = Vec::with_capacity(buckets.len() * 3);
+    for ((ms, stack), weight) in buckets {
+        let id = *stack_ids.entry(stack).or_insert_with(|| {
+            stacks_json.push(viewer_stack_json(&table.stacks[stack], ids));
+            stacks_json.len() - 1
+        });
+        samples_json.push(format!("{},{},{}", ms, id, weight));
+    }
+
+    let seconds = (last - first).floor() as u64 + 1;
+    let html = format!(r#"
+    <div class="heatmap-container">
+        <div class="heatmap-header">
+            <span class="stat-label">Sub-second offset heat map</span>
+            <span class="heatmap-range" id="heatmapRange">Drag across the heat map to select a time range</span>
+            <button class="btn" id="clearRange" style="display:none">Clear Range</button>
+        </div>
+        <canvas id="heatmap"></canvas>
+        <div class="heatmap-axis"><span>0s</span><span>{seconds}s</span></div>
+    </div>
+"#);
+
+    let script = format!(
+        "const heatmapData = {{ rows: {}, stacks: [{}], samples: [{}] }};\n{}",
+        HEATMAP_ROWS,
+        stacks_json.join(","),
+        samples_json.join(","),
+        HEATMAP_JS
+    );
+
+    Some((html, script))
+}
+
+/// Build the markup and script for the thread timeline.
+///
+/// Lanes are listed by process, then thread, and samples embedded as a flat
+/// `[µs, lane, stack, weight, ...]` array in time order alongside the stack table,
+/// as for `heatmap_parts`.
+fn lanes_parts(table: &SampleTable, ids: &[usize]) -> Option<(String, String)> {
+    let (first, last) = table.time_range()?;
+    let (by_thread, interval, order) = samples_by_thread(table, first);
+
+    let mut lanes_json = Vec::with_capacity(order.len());
+    let mut samples: Vec<(u64, usize, usize)> = Vec::with_capacity(table.samples.len());
+    for (lane, &thread) in order.iter().enumerate() {
+        let t = &table.threads[thread];
+        let process = table.threads.iter().find(|p| p.pid == t.pid && p.tid == t.pid).unwrap_or(t);
+        lanes_json.push(format!(
+            "[{},{}]",
+            json_string(&format!("{} {}", process.comm, t.pid)),
+            json_string(&format!("{} {}", t.comm, t.tid))
+        ));
+        samples.extend(by_thread[thread].iter().map(|&(ns, sample)| (ns / 1000, lane, sample)));
+    }
+    samples.sort_by_key(|&(us, lane, _)| (us, lane));
+
+    // Only embed stacks that are referenced, renumbered densely
+    let mut stack_ids: HashMap<usize, usize> = HashMap::new();
+    let mut stacks_json = Vec::new();
+    let mut samples_json = Vec::with_capacity(samples.len() * 4);
+    for (us, lane, sample) in samples {
+        let sample = &table.samples[sample];
+        let id = *stack_ids.entry(sample.stack).or_insert_with(|| {
+            stacks_json.push(viewer_stack_json(&table.stacks[sample.stack], ids));
+            stacks_json.len() - 1
+        });
+        samples_json.push(format!("{},{},{},{}", us, lane, id, sample.weight));
+    }
+
+    let seconds = format!("{:.2}", last - first + interval as f64 / 1e9);
+    let html = format!(r#"
+    <div class="heatmap-container">
+        <div class="heatmap-header">
+            <span class="stat-label">Threads</span>
+            <span class="heatmap-range" id="lanesRange">Drag across lanes to select a time range, or click a lane for all of it</span>
+            <button class="btn" id="clearLanes" style="display:none">Clear Selection</button>
+        </div>
+        <canvas id="lanes"></canvas>
+        <div class="heatmap-axis lanes-axis"><span>0s</span><span>{seconds}s</span></div>
+    </div>
+"#);
+
+    let script = format!(
+        "const laneData = {{ interval: {}, total: {}, lanes: [{}], stacks: [{}], samples: [{}] }};\n{}",
+        interval / 1000,
+        ((last - first) * 1e6).round() as u64 + interval / 1000,
+        lanes_json.join(","),
+        stacks_json.join(","),
+        samples_json.join(","),
+        LANES_JS
+    );
+
+    Some((html, script))
+}
+
+/// Light and high-contrast themes, as overrides of the built-in dark styles that
+/// apply unless the page's `data-theme` is "dark". Shared by both page layouts.
+const THEME_CSS: &str = r#"
+:root[data-theme="light"] {
+    --flg-bg: #f8fafc;
+    --flg-text: #1e293b;
+    --flg-heading: #0f172a;
+    --flg-muted: #475569;
+    --flg-faint: #64748b;
+    --flg-panel: #ffffff;
+    --flg-surface: #ffffff;
+    --flg-control: #ffffff;
+    --flg-control-hover: #f1f5f9;
+    --flg-border: #cbd5e1;
+    --flg-frame-border: rgba(255, 255, 255, 0.4);
+    --flg-accent: rgb(79, 70, 229);
+    --flg-shadow: rgba(15, 23, 42, 0.15);
+}
+
+:root[data-theme="high-contrast"] {
+    --flg-bg: #000000;
+    --flg-text: #ffffff;
+    --flg-heading: #ffffff;
+    --flg-muted: #f5f5f5;
+    --flg-faint: #e5e5e5;
+    --flg-panel: #000000;
+    --flg-surface: #000000;
+    --flg-control: #000000;
+    --flg-control-hover: #262626;
+    --flg-border: #ffffff;
+    --flg-frame-border: #000000;
+    --flg-accent: rgb(250, 204, 21);
+    --flg-shadow: rgba(0, 0, 0, 0);
+}
+
+:root:not([data-theme="dark"]) body {
+    background: var(--flg-bg);
+    color: var(--flg-text);
+}
+
+:root:not([data-theme="dark"]) .title-section h1,
+:root:not([data-theme="dark"]) .title-section h2,
+:root:not([data-theme="dark"]) .sandwich-name,
+:root:not([data-theme="dark"]) .tooltip-name,
+:root:not([data-theme="dark"]) .function-table td.name {
+    color: var(--flg-heading);
+}
+
+:root:not([data-theme="dark"]) .title-section .subtitle,
+:root:not([data-theme="dark"]) .stat-label,
+:root:not([data-theme="dark"]) .tab,
+:root:not([data-theme="dark"]) .tooltip-stats dt,
+:root:not([data-theme="dark"]) .palette-selector label {
+    color: var(--flg-faint);
+}
+
+:root:not([data-theme="dark"]) .stat-value,
+:root:not([data-theme="dark"]) .tab:hover,
+:root:not([data-theme="dark"]) .tab.active,
+:root:not([data-theme="dark"]) .function-table td {
+    color: var(--flg-text);
+}
+
+:root:not([data-theme="dark"]) .heatmap-range,
+:root:not([data-theme="dark"]) .color-legend,
+:root:not([data-theme="dark"]) .tooltip-stats dd,
+:root:not([data-theme="dark"]) .footer-info,
+:root:not([data-theme="dark"]) .keyboard-hints,
+:root:not([data-theme="dark"]) .palette-selector,
+:root:not([data-theme="dark"]) .heatmap-axis,
+:root:not([data-theme="dark"]) .search-box svg,
+:root:not([data-theme="dark"]) #lanes {
+    color: var(--flg-muted);
+}
+
+:root:not([data-theme="dark"]) .search-box input,
+:root:not([data-theme="dark"]) .table-filter,
+:root:not([data-theme="dark"]) .btn,
+:root:not([data-theme="dark"]) .palette-selector select,
+:root:not([data-theme="dark"]) .palette-selector select option {
+    background: var(--flg-control);
+    border-color: var(--flg-border);
+    color: var(--flg-text);
+}
+
+:root:not([data-theme="dark"]) .search-box input::placeholder {
+    color: var(--flg-faint);
+}
+
+:root:not([data-theme="dark"]) .btn:hover,
+:root:not([data-theme="dark"]) .context-menu-item:hover,
+:root:not([data-theme="dark"]) .function-table tbody tr:hover {
+    background: var(--flg-control-hover);
+    color: var(--flg-heading);
+}
+
+:root:not([data-theme="dark"]) .search-box input:focus,
+:root:not([data-theme="dark"]) .table-filter:focus,
+:root:not([data-theme="dark"]) .palette-selector select:focus {
+    border-color: var(--flg-accent);
+}
+
+:root:not([data-theme="dark"]) .tab.active {
+    border-bottom-color: var(--flg-accent);
+}
+
+:root:not([data-theme="dark"]) .chart-container,
+:root:not([data-theme="dark"]) .table-scroll,
+:root:not([data-theme="dark"]) .heatmap-container {
+    background: var(--flg-panel);
+    border-color: var(--flg-border);
+}
+
+:root:not([data-theme="dark"]) .tooltip,
+:root:not([data-theme="dark"]) .context-menu,
+:root:not([data-theme="dark"]) .function-table th {
+    background: var(--flg-surface);
+    border-color: var(--flg-border);
+    color: var(--flg-text);
+    box-shadow: 0 12px 32px var(--flg-shadow);
+}
+
+:root:not([data-theme="dark"]) .function-table th {
+    box-shadow: none;
+    color: var(--flg-muted);
+}
+
+:root:not([data-theme="dark"]) .function-table td,
+:root:not([data-theme="dark"]) .tabs,
+:root:not([data-theme="dark"]) footer,
+:root:not([data-theme="dark"]) .flamegraph-section {
+    border-color: var(--flg-border);
+}
+
+:root:not([data-theme="dark"]) .context-menu-separator,
+:root:not([data-theme="dark"]) .keyboard-hints kbd {
+    background: var(--flg-control-hover);
+}
+
+:root:not([data-theme="dark"]) .frame {
+    border-color: var(--flg-frame-border);
+}
+
+:root[data-theme="high-contrast"] .frame {
+    color: #ffffff;
+    text-shadow: 0 0 2px #000000, 0 0 2px #000000;
+}
+
+:root[data-theme="high-contrast"] .frame:hover {
+    border-color: #ffffff;
+}
+"#;
+
+/// Applies the theme picked on an earlier visit before the page draws, and keeps
+/// every theme selector on the page in step, remembering the pick in localStorage.
+/// Canvases drawn in theme colours redraw on the document's `themechange` event.
+const THEME_JS: &str = r#"
+(function() {
+    const root = document.documentElement;
+    let saved = null;
+    try {
+        saved = localStorage.getItem('flg-theme');
+    } catch (e) {
+        // Storage can be unavailable for pages opened from disk
+    }
+    if (['dark', 'light', 'high-contrast'].includes(saved)) root.dataset.theme = saved;
+    
+    document.addEventListener('DOMContentLoaded', () => {
+        const selects = document.querySelectorAll('.theme-select');
+        selects.forEach(select => {
+            select.value = root.dataset.theme;
+            select.addEventListener('change', () => {
+                root.dataset.theme = select.value;
+                selects.forEach(other => { other.value = select.value; });
+                try {
+                    localStorage.setItem('flg-theme', select.value);
+                } catch (e) {}
+                document.dispatchEvent(new Event('themechange'));
+            });
+        });
+    });
+})();
+"#;
+
+/// Colour modes and their legend. Inserted inside each graph's closure; expects
+/// `palettes`, `hashString`, `hslToRgb`, `frameNames`, `frameOrigins`,
+/// `colorCategories`, `stackCounts` and the graph's `colorBySelect` and
+/// `colorLegend`.
+const FRAME_COLOR_JS: &str = r#"
+    // Category of a frame under a colour mode: its module, kind or language id,
+    // or '' when unknown
+    function frameCategory(mode, id) {
+        const origin = frameOrigins[id];
+        if (!origin) return '';
+        return origin[mode === 'module' ? 0 : mode === 'kind' ? 1 : 2];
+    }
+    
+    function hashColor(palette, key) {
+        const hsl = palette(hashString(key));
+        const rgb = hslToRgb(hsl.h, hsl.s, hsl.l);
+        return `rgb(${rgb.r}, ${rgb.g}, ${rgb.b})`;
+    }
+    
+    // Colour of a frame: a hash of its name or module in the palette, or a fixed
+    // colour for its kind or language
+    function frameColor(palette, id) {
+        const mode = colorBySelect.value;
+        if (mode === 'name') return hashColor(palette, frameNames[id] || '');
+        const category = frameCategory(mode, id);
+        if (!category) return colorCategories.other[1];
+        return mode === 'module' ? hashColor(palette, category) : colorCategories[mode][category][1];
+    }
+    
+    // Colours in use, most sampled first, weighting each by the samples of stacks
+    // with a frame in it; frames without a category (process names and the like)
+    // come last
+    function updateLegend() {
+        const mode = colorBySelect.value;
+        colorLegend.innerHTML = '';
+        colorLegend.style.display = mode === 'name' ? 'none' : 'flex';
+        if (mode === 'name') return;
+        
+        const weights = new Map();
+        stackCounts.forEach((count, stack) => {
+            new Set(stack.split(';').map(id => frameCategory(mode, id))).forEach(category => {
+                weights.set(category, (weights.get(category) || 0) + count);
+            });
+        });
+        const palette = palettes[paletteSelect.value];
+        const entries = Array.from(weights.keys())
+            .sort((a, b) => (a === '') - (b === '') || weights.get(b) - weights.get(a));
+        const legendMax = 12;
+        entries.slice(0, legendMax).forEach(category => {
+            const [label, color] = !category ? colorCategories.other
+                : mode === 'module' ? [category, palette ? hashColor(palette, category) : colorCategories.other[1]]
+                : colorCategories[mode][category];
+            const item = document.createElement('span');
+            item.className = 'color-legend-item';
+            const swatch = document.createElement('span');
+            swatch.className = 'color-swatch';
+            swatch.style.background = color;
+            item.appendChild(swatch);
+            item.appendChild(document.createTextNode(label));
+            colorLegend.appendChild(item);
+        });
+        if (entries.length > legendMax) {
+            const more = document.createElement('span');
+            more.className = 'color-legend-item';
+            more.textContent = '+' + (entries.length - legendMax) + ' more';
+            colorLegend.appendChild(more);
+        }
+    }
+    
+    colorBySelect.addEventListener('change', updateLegend);
+    paletteSelect.addEventListener('change', updateLegend);
+    updateLegend();
+"#;
+
+/// Tooltip rows for a frame's depth and origin, filled in by `FRAME_DETAILS_JS`.
+const TOOLTIP_DETAILS_HTML: &str = r#"<dt class="detail-depth">Depth</dt>
+            <dd class="detail-depth"></dd>
+            <dt class="detail-module" style="display:none">Module</dt>
+            <dd class="detail-module" style="display:none"></dd>
+            <dt class="detail-source" style="display:none">Source</dt>
+            <dd class="detail-source" style="display:none"></dd>
+            <dt class="detail-kind" style="display:none">Kind</dt>
+            <dd class="detail-kind" style="display:none"></dd>
+            <dt class="detail-address" style="display:none">Address</dt>
+            <dd class="detail-address" style="display:none"></dd>"#;
+
+/// Tooltip details of a frame and the context menu's "Copy full name" and "Open in
+/// editor". Inserted inside each graph's closure; expects `tooltip`, `frameOrigins`,
+/// `colorCategories`, `fullNames`, `copyNameBtn`, `openEditorBtn`, `showSourceBtn`,
+/// `SOURCE_VIEW_JS` and the renderer's `contextTarget`, `frameName`, `frameId` and
+/// `hideContextMenu`. Renderers call `updateContextMenu` after picking a
+/// `contextTarget`.
+const FRAME_DETAILS_JS: &str = r#"
+    // Fill the tooltip's detail rows for a frame (with a null depth outside the
+    // graph); rows without a value are hidden
+    function showFrameDetails(id, depth) {
+        const origin = frameOrigins[id] || [];
+        const kind = colorCategories.kind[origin[1]];
+        const language = colorCategories.language[origin[2]];
+        const details = {
+            depth: depth === null ? '' : String(depth),
+            module: origin[3],
+            source: origin[4] && annotatedSource(id) ? origin[4] + ' (right-click to show)' : origin[4],
+            kind: kind ? kind[0] + (language ? ' · ' + language[0] : '') : '',
+            address: origin[5],
+        };
+        Object.entries(details).forEach(([key, value]) => {
+            const [label, row] = tooltip.querySelectorAll('.detail-' + key);
+            row.textContent = value || '';
+            label.style.display = row.style.display = value ? '' : 'none';
+        });
+    }
+    
+    function copyText(text) {
+        if (navigator.clipboard) {
+            navigator.clipboard.writeText(text).catch(() => {});
+            return;
+        }
+        // Pages opened from disk may not get the async clipboard API
+        const area = document.createElement('textarea');
+        area.value = text;
+        document.body.appendChild(area);
+        area.select();
+        document.execCommand('copy');
+        area.remove();
+    }
+    
+    copyNameBtn.addEventListener('click', () => {
+        if (contextTarget === null) return;
+        const name = frameName(contextTarget);
+        hideContextMenu();
+        copyText(fullNames.get(name) || name);
+    });
+    
+    function editorLink(target) {
+        const origin = frameOrigins[frameId(target)];
+        return origin ? origin[6] : '';
+    }
+    
+    // Only frames with a known source line can be opened
+    function updateContextMenu() {
+        openEditorBtn.style.display = editorLink(contextTarget) ? '' : 'none';
+        showSourceBtn.style.display = annotatedSource(frameId(contextTarget)) ? '' : 'none';
+    }
+    
+    openEditorBtn.addEventListener('click', () => {
+        if (contextTarget === null) return;
+        const link = editorLink(contextTarget);
+        hideContextMenu();
+        if (!link) return;
+        // Web links get a tab of their own; editor schemes are handed to the system
+        if (/^https?:/.test(link)) window.open(link, '_blank', 'noopener');
+        else window.location.href = link;
+    });
+"#;
+
+/// Client-side equivalent of `process_stacks` plus frame rendering, used to redraw
+/// the chart from embedded stack data, and the view selector switching between
+/// flame/icicle and normal/inverted graphs. Inserted inside each graph's closure;
+/// expects `frameNames`, `stackCounts`, `timeline`, `initialInverted`, `minWidth`, `aggregateSmall`, `viewSelect` and the renderer's
+/// `showLayout`, `showInitialChart` and `setIcicle` (`DOM_CHART_JS` or `CANVAS_JS`).
+const STACK_RENDER_JS: &str = r#"
+    function escapeHtml(s) {
+        return s.replace(/&/g, '&amp;').replace(/</g, '&lt;').replace(/>/g, '&gt;')
+            .replace(/"/g, '&quot;').replace(/'/g, '&#39;');
+    }
+    
+    // Frame ids of a stack key, e.g. "3;5;9"
+    function stackIds(key) {
+        return key === '' ? [] : key.split(';').map(Number);
+    }
+    
+    function laidOutFrame(id, depth, start, end) {
+        return { id, name: id >= 0 ? frameNames[id] : '', depth, start, end };
+    }
+    
+    // Lay out a Map of stack key -> count using the same flow/merge algorithm as the
+    // generator, which orders stacks by their frame ids
+    function layoutStacks(counts) {
+        const stacks = Array.from(counts.keys()).filter(k => counts.get(k) > 0)
+            .map(key => [[-1].concat(stackIds(key)), counts.get(key)]);
+        stacks.sort(([a], [b]) => {
+            for (let i = 0; i < a.length && i < b.length; i++) {
+                if (a[i] !== b[i]) return a[i] - b[i];
+            }
+            return a.length - b.length;
+        });
+        const out = [];
+        const open = [];
+        let last = [];
+        let time = 0;
+        let depthMax = 0;
+        const closeFrom = (from) => {
+            for (let i = last.length - 1; i >= from; i--) {
+                out.push(laidOutFrame(last[i], i, open[i], time));
+                depthMax = Math.max(depthMax, i);
+            }
+        };
+        stacks.forEach(([stack, count]) => {
+            let same = 0;
+            while (same < last.length && same < stack.length && last[same] === stack[same]) same++;
+            closeFrom(same);
+            for (let i = same; i < stack.length; i++) open[i] = time;
+            time += count;
+            last = stack;
+        });
+        closeFrom(0);
+        return { frames: out, total: time, depthMax };
+    }
+    
+    // Client-side equivalent of `layout_timeline`, for flame charts
+    function layoutTimeline(timeline) {
+        const out = [laidOutFrame(-1, 0, 0, timeline.total)];
+        const open = [];
+        let last = [];
+        let lastEnd = -1;
+        let depthMax = 0;
+        const closeFrom = (from) => {
+            for (let i = last.length - 1; i >= from; i--) {
+                out.push(laidOutFrame(last[i], i + 1, open[i], lastEnd));
+                depthMax = Math.max(depthMax, i + 1);
+            }
+        };
+        for (let r = 0; r < timeline.runs.length; r += 3) {
+            const [start, end, id] = timeline.runs.slice(r, r + 3);
+            const stack = stackIds(timeline.stacks[id]);
+            let same = 0;
+            if (start === lastEnd) {
+                while (same < last.length && same < stack.length && last[same] === stack[same]) same++;
+            }
+            closeFrom(same);
+            for (let i = same; i < stack.length; i++) open[i] = start;
+            last = stack;
+            lastEnd = end;
+        }
+        closeFrom(0);
+        return { frames: out, total: timeline.total, depthMax };
+    }
+    
+    // Folded stacks of the current view, or null when showing all of them
+    let shownCounts = null;
+    let viewInverted = initialInverted;
+    
+    function invertCounts(counts) {
+        const out = new Map();
+        counts.forEach((n, key) => out.set(key.split(';').reverse().join(';'), n));
+        return out;
+    }
+    
+    function renderStacks(counts) {
+        shownCounts = counts;
+        drawStacks(counts);
+    }
+    
+    // Mirror of `prune_frames`: frames narrower than `minWidth` percent of the graph are
+    // left out and, with `aggregateSmall`, stood in for by one "[N small frames]" frame
+    // after their siblings. Children of frames inside a focus ({ start, end, depth, width },
+    // in laid-out positions) are also kept when wide enough next to the focus width; they
+    // go after the children kept before, so frames already shown keep their place.
+    function pruneLayout(layout, focuses) {
+        const levels = [];
+        layout.frames.forEach(fr => {
+            if (fr.end > fr.start) (levels[fr.depth] = levels[fr.depth] || []).push(fr);
+        });
+        levels.forEach(level => level.sort((a, b) => a.start - b.start));
+        // Index of the first width a frame is wide enough next to, or -1
+        const tier = (samples, widths) => widths.findIndex(w => !(samples / w * 100 < minWidth));
+        
+        const out = [];
+        const pending = (levels[0] || []).filter(fr => tier(fr.end - fr.start, [layout.total]) >= 0)
+            .map(fr => [fr, fr.start]);
+        while (pending.length) {
+            const [fr, start] = pending.pop();
+            const samples = fr.end - fr.start;
+            out.push({ id: fr.id, name: fr.name, depth: fr.depth, start, end: start + samples });
+            
+            const level = levels[fr.depth + 1];
+            if (!level) continue;
+            const firstFrom = (x) => {
+                let lo = 0;
+                let hi = level.length;
+                while (lo < hi) {
+                    const mid = (lo + hi) >> 1;
+                    if (level[mid].start < x) lo = mid + 1; else hi = mid;
+                }
+                return lo;
+            };
+            const children = level.slice(firstFrom(fr.start), firstFrom(fr.end));
+            const widths = [layout.total].concat(focuses
+                .filter(f => fr.depth >= f.depth && start >= f.start && start + samples <= f.end)
+                .map(f => f.width));
+            
+            if (!aggregateSmall) {
+                children.forEach(c => {
+                    if (tier(c.end - c.start, widths) >= 0) pending.push([c, start + (c.start - fr.start)]);
+                });
+                continue;
+            }
+            
+            // Children follow the frame's self time; pruned ones are gathered at the end
+            const tiers = widths.map(() => []);
+            let pruned = 0;
+            children.forEach(c => {
+                const t = tier(c.end - c.start, widths);
+                if (t < 0) pruned++; else tiers[t].push(c);
+            });
+            let cursor = start + samples - children.reduce((sum, c) => sum + (c.end - c.start), 0);
+            tiers.flat().forEach(c => {
+                pending.push([c, cursor]);
+                cursor += c.end - c.start;
+            });
+            if (pruned > 0) {
+                out.push({ id: -1, name: '[' + pruned + ' small frame' + (pruned === 1 ? '' : 's') + ']',
+                    depth: fr.depth + 1, start: cursor, end: start + samples, aggregate: true });
+            }
+        }
+        return out;
+    }
+    
+    // Frame markup for a layout, pruned as above; frames shallower than `skip` are left
+    // out and the rest move down to fill their place
+    function framesHtml(layout, skip, focuses = []) {
+        const parts = [];
+        pruneLayout(layout, focuses).forEach(fr => {
+            if (fr.depth < skip) return;
+            const samples = fr.end - fr.start;
+            const widthPct = samples / layout.total * 100;
+            const name = escapeHtml(fr.name === '' ? 'all' : fr.name);
+            const depth = fr.depth - skip;
+            parts.push('<div class="' + (fr.aggregate ? 'frame aggregate' : 'frame') + '" style="left:' + (fr.start / layout.total * 100).toFixed(4) +
+                '%;width:' + widthPct.toFixed(4) + '%;bottom:' + (depth * frameHeight) +
+                'px;" data-name="' + name + '" data-id="' + fr.id + '" data-samples="' + samples + '" data-pct="' +
+                widthPct.toFixed(2) + '" data-depth="' + depth + '" data-start="' + fr.start +
+                '" data-end="' + fr.end + '">' + name + '</div>');
+        });
+        return parts.join('');
+    }
+    
+    function drawStacks(counts) {
+        showLayout(layoutStacks(viewInverted ? invertCounts(counts) : counts));
+    }
+    
+    function restoreChart() {
+        shownCounts = null;
+        if (viewInverted === initialInverted) {
+            showInitialChart();
+        } else {
+            drawStacks(stackCounts);
+        }
+    }
+    
+    // Icicle only flips the chart; inverting re-lays out the stacks
+    viewSelect.addEventListener('change', () => {
+        const view = viewSelect.value;
+        setIcicle(view.endsWith('icicle'));
+        const inverted = view.startsWith('inverted');
+        if (inverted === viewInverted) return;
+        viewInverted = inverted;
+        if (shownCounts) {
+            drawStacks(shownCounts);
+        } else {
+            restoreChart();
+        }
+    });
+"#;
+
+/// Chart updates for graphs drawn as one `<div class="frame">` per frame.
+const DOM_CHART_JS: &str = r#"
+    // Pruned detail laid out again for frames zoomed into (see `pruneLayout`)
+    let focuses = [];
+    
+    function setChart(content, total, depthMax) {
+        tooltip.classList.remove('visible');
+        hideContextMenu();
+        zoomedFrame = null;
+        hiddenStacks.clear();
+        focuses = [];
+        chart.innerHTML = content;
+        chart.style.height = ((depthMax + 1) * frameHeight) + 'px';
+        totalSamples = total;
+        totalSamplesValue.textContent = formatValue(total);
+        depthMaxValue.textContent = depthMax;
+        initFrames();
+        applyPalette(paletteSelect.value);
+        applySearch();
+        resetBtn.disabled = !searchTerm;
+    }
+    
+    function showLayout(layout) {
+        setChart(framesHtml(layout, 0), layout.total, layout.depthMax);
+    }
+    
+    function showInitialChart() {
+        setChart(initialChart.html, initialChart.total, initialChart.depthMax);
+    }
+    
+    function setIcicle(on) {
+        chart.classList.toggle('icicle', on);
+    }
+    
+    function frameName(frame) {
+        return frame.dataset.name;
+    }
+    
+    function frameId(frame) {
+        return parseInt(frame.dataset.id);
+    }
+    
+    // Before zooming into a frame, bring back the frames pruned inside it (or, for an
+    // aggregated frame, the ones it stands for) that are wide enough at that zoom
+    function refineChart(frame) {
+        const start = parseInt(frame.dataset.start);
+        const end = parseInt(frame.dataset.end);
+        const depth = parseInt(frame.dataset.depth);
+        let focus = { start, end, depth, width: end - start };
+        if (frame.classList.contains('aggregate')) {
+            const parent = frames.find(f => parseInt(f.dataset.depth) === depth - 1 &&
+                parseInt(f.dataset.start) <= start && end <= parseInt(f.dataset.end));
+            if (!parent) return;
+            focus = { start: parseInt(parent.dataset.start), end: parseInt(parent.dataset.end),
+                depth: depth - 1, width: end - start };
+        }
+        if (!(minWidth > 0) || focus.width >= totalSamples || focuses.some(f => f.start === focus.start &&
+            f.end === focus.end && f.depth === focus.depth && f.width <= focus.width)) return;
+        
+        focuses.push(focus);
+        const counts = shownCounts || stackCounts;
+        const layout = timeline ? layoutTimeline(timeline) : layoutStacks(viewInverted ? invertCounts(counts) : counts);
+        tooltip.classList.remove('visible');
+        chart.innerHTML = framesHtml(layout, 0, focuses);
+        initFrames();
+        applyPalette(paletteSelect.value);
+        recalculateLayout();
+    }
+"#;
+
+/// Canvas renderer: the frames live in typed arrays sorted by depth, then start,
+/// are drawn onto a single canvas and are hit-tested with binary searches, so
+/// profiles with hundreds of thousands of frames stay responsive. Provides the
+/// same zoom, search, hide and tooltip behaviour as the DOM page. Expects
+/// `frameTable`, `initialChart`, `initialIcicle`, `offWakeSeparator` and `FRAME_COLOR_JS`.
+const CANVAS_JS: &str = r#"
+    const canvas = document.getElementById('chartCanvas');
+    const ctx = canvas.getContext('2d');
+    
+    let ft = null;
+    let depthMax = 0;
+    let icicle = initialIcicle;
+    let colors = [];
+    let nameMatches = null;
+    let zoomedFrame = -1;
+    let searchTerm = null;
+    let contextTarget = null;
+    // Hidden stacks as [start, end, depth], and the sample ranges they remove
+    let hiddenStacks = [];
+    let hiddenRanges = [];
+    
+    function decodeTable(table) {
+        const count = table.frames.length / 4;
+        const t = {
+            names: table.names,
+            ids: table.ids,
+            name: new Int32Array(count),
+            start: new Float64Array(count),
+            end: new Float64Array(count),
+            depth: new Int32Array(count)
+        };
+        for (let i = 0; i < count; i++) {
+            t.name[i] = table.frames[i * 4];
+            t.start[i] = table.frames[i * 4 + 1];
+            t.end[i] = table.frames[i * 4 + 2];
+            t.depth[i] = table.frames[i * 4 + 3];
+        }
+        return t;
+    }
+    
+    // Position of a sample once hidden stacks have been cut out of the graph
+    function mapX(x) {
+        let removed = 0;
+        for (const [s, e] of hiddenRanges) {
+            if (s >= x) break;
+            removed += Math.min(e, x) - s;
+        }
+        return x - removed;
+    }
+    
+    function viewRange() {
+        if (zoomedFrame >= 0) return [mapX(ft.start[zoomedFrame]), mapX(ft.end[zoomedFrame])];
+        return [0, mapX(totalSamples)];
+    }
+    
+    // First frame at a depth ending after x (in mapped samples)
+    function firstFrameAfter(depth, x) {
+        let lo = ft.rows[depth];
+        let hi = ft.rows[depth + 1];
+        while (lo < hi) {
+            const mid = (lo + hi) >> 1;
+            if (mapX(ft.end[mid]) <= x) lo = mid + 1; else hi = mid;
+        }
+        return lo;
+    }
+    
+    // Frame at a depth containing sample x (in original samples), or -1
+    function frameContaining(depth, x) {
+        let lo = ft.rows[depth];
+        let hi = ft.rows[depth + 1] - 1;
+        while (lo <= hi) {
+            const mid = (lo + hi) >> 1;
+            if (ft.end[mid] <= x) lo = mid + 1;
+            else if (ft.start[mid] > x) hi = mid - 1;
+            else return mid;
+        }
+        return -1;
+    }
+    
+    function isFrameHidden(i) {
+        return hiddenStacks.some(([s, e, d]) => ft.depth[i] >= d && ft.start[i] >= s && ft.end[i] <= e);
+    }
+    
+    function setTable(table, total) {
+        tooltip.classList.remove('visible');
+        hideContextMenu();
+        zoomedFrame = -1;
+        hiddenStacks = [];
+        hiddenRanges = [];
+        const count = table.name.length;
+        depthMax = count ? table.depth[count - 1] : 0;
+        table.rows = new Int32Array(depthMax + 2);
+        for (let i = 0; i < count; i++) table.rows[table.depth[i] + 1] = i + 1;
+        ft = table;
+        totalSamples = total;
+        totalSamplesValue.textContent = formatValue(total);
+        depthMaxValue.textContent = depthMax;
+        applyPalette(paletteSelect.value);
+        applySearch();
+        resetBtn.disabled = !searchTerm;
+    }
+    
+    function showLayout(layout) {
+        const frames = layout.frames.filter(fr => fr.end > fr.start)
+            .sort((a, b) => a.depth - b.depth || a.start - b.start);
+        // Frames without an id (the root and aggregates) go by their name
+        const slots = new Map();
+        const table = { names: [], ids: [], frames: [] };
+        frames.forEach(fr => {
+            const name = fr.name === '' ? 'all' : fr.name;
+            const key = fr.id >= 0 ? fr.id : name;
+            if (!slots.has(key)) {
+                slots.set(key, table.names.length);
+                table.names.push(name);
+                table.ids.push(fr.id);
+            }
+            table.frames.push(slots.get(key), fr.start, fr.end, fr.depth);
+        });
+        setTable(decodeTable(table), layout.total);
+    }
+    
+    function showInitialChart() {
+        setTable(decodeTable(frameTable), initialChart.total);
+    }
+    
+    function setIcicle(on) {
+        icicle = on;
+        draw();
+    }
+    
+    function frameName(i) {
+        return ft.names[ft.name[i]];
+    }
+    
+    function frameId(i) {
+        return ft.ids[ft.name[i]];
+    }
+    
+    function draw() {
+        const width = chart.clientWidth;
+        const height = (depthMax + 1) * frameHeight;
+        chart.style.height = height + 'px';
+        if (!width) return;
+        const dpr = window.devicePixelRatio || 1;
+        canvas.style.width = width + 'px';
+        canvas.style.height = height + 'px';
+        canvas.width = width * dpr;
+        canvas.height = height * dpr;
+        ctx.setTransform(dpr, 0, 0, dpr, 0, 0);
+        ctx.clearRect(0, 0, width, height);
+        ctx.font = "500 11px 'SF Mono', 'Fira Code', 'JetBrains Mono', Consolas, monospace";
+        ctx.textBaseline = 'middle';
+        const charWidth = ctx.measureText('M').width || 7;
+        
+        const [x0, x1] = viewRange();
+        const scale = width / (x1 - x0);
+        const zoomDepth = zoomedFrame >= 0 ? ft.depth[zoomedFrame] : 0;
+        for (let depth = 0; depth <= depthMax; depth++) {
+            const y = (icicle ? depth : depthMax - depth) * frameHeight;
+            for (let i = firstFrameAfter(depth, x0); i < ft.rows[depth + 1]; i++) {
+                const start = mapX(ft.start[i]);
+                if (start >= x1) break;
+                const left = Math.max(0, (start - x0) * scale);
+                const right = Math.min(width, (mapX(ft.end[i]) - x0) * scale);
+                // Hidden frames map to nothing; sub-pixel ones are skipped
+                if (right - left < 0.5) continue;
+                
+                const matched = nameMatches && nameMatches[ft.name[i]];
+                ctx.globalAlpha = nameMatches && !matched ? 0.25 : depth < zoomDepth ? 0.4 : 1;
+                ctx.fillStyle = matched ? 'rgb(250, 204, 21)' : colors[ft.name[i]];
+                ctx.fillRect(left + 0.5, y + 1, Math.max(right - left - 1, 0.5), frameHeight - 2);
+                
+                const chars = Math.floor((right - left - 12) / charWidth);
+                if (chars >= 2) {
+                    const name = frameName(i);
+                    ctx.fillStyle = matched ? '#1e1e1e' : 'rgba(255, 255, 255, 0.9)';
+                    ctx.fillText(name.length > chars ? name.slice(0, chars - 1) + '…' : name,
+                        left + 6, y + frameHeight / 2);
+                }
+            }
+        }
+        ctx.globalAlpha = 1;
+    }
+    
+    function frameAt(e) {
+        const rect = canvas.getBoundingClientRect();
+        const row = Math.floor((e.clientY - rect.top) / frameHeight);
+        const depth = icicle ? row : depthMax - row;
+        if (depth < 0 || depth > depthMax || !rect.width) return -1;
+        const [x0, x1] = viewRange();
+        const x = x0 + (e.clientX - rect.left) / rect.width * (x1 - x0);
+        const i = firstFrameAfter(depth, x);
+        return i < ft.rows[depth + 1] && mapX(ft.start[i]) <= x ? i : -1;
+    }
+    
+    function applyPalette(paletteName, list) {
+        const palette = palettes[paletteName];
+        if (!palette) return;
+        const color = (name, id) => {
+            if (name === 'all') return 'rgb(99, 102, 241)';
+            if (name === offWakeSeparator) return 'rgb(100, 116, 139)';
+            return frameColor(palette, id);
+        };
+        if (list) {
+            list.forEach(f => { f.style.background = color(f.dataset.name, parseInt(f.dataset.id)); });
+            return;
+        }
+        colors = ft.names.map((name, slot) => color(name, ft.ids[slot]));
+        draw();
+    }
+    
+    paletteSelect.addEventListener('change', (e) => {
+        applyPalette(e.target.value);
+    });
+    colorBySelect.addEventListener('change', () => applyPalette(paletteSelect.value));
+    
+    // Folded path from the root to a frame, e.g. "app;main;parse"
+    function framePath(i) {
+        const names = [];
+        for (let depth = 1; depth < ft.depth[i]; depth++) {
+            const parent = frameContaining(depth, ft.start[i]);
+            if (parent >= 0) names.push(frameName(parent));
+        }
+        names.push(frameName(i));
+        return names.join(';');
+    }
+    
+    function showTooltip(i) {
+        const name = frameName(i);
+        const samples = ft.end[i] - ft.start[i];
+        let childSamples = 0;
+        const depth = ft.depth[i] + 1;
+        if (depth <= depthMax) {
+            for (let j = frameContaining(depth, ft.start[i]); j >= 0 && j < ft.rows[depth + 1] &&
+                ft.start[j] < ft.end[i]; j++) {
+                if (!isFrameHidden(j)) childSamples += ft.end[j] - ft.start[j];
+            }
+        }
+        const selfSamples = samples - childSamples;
+        
+        tooltipName.textContent = fullNames.get(name) || name;
+        tooltipSamples.textContent = formatValue(samples);
+        tooltipPct.textContent = (samples / totalSamples * 100).toFixed(2) + '%';
+        tooltipSelf.textContent = formatValue(selfSamples) + ' (' +
+            (selfSamples / totalSamples * 100).toFixed(2) + '%)';
+        
+        // Folded levels are keyed by root-first paths, which inverted frames don't have
+        const levels = recursionLevels.size && !viewInverted ? recursionLevels.get(framePath(i)) : undefined;
+        tooltipRecursion.textContent = levels ? levels + ' levels folded' : '';
+        tooltipRecursion.style.display = tooltipRecursionLabel.style.display = levels ? '' : 'none';
+        showFrameDetails(frameId(i), ft.depth[i]);
+        tooltip.classList.add('visible');
+    }
+    
+    let hoverFrame = -1;
+    canvas.addEventListener('mousemove', (e) => {
+        const i = frameAt(e);
+        if (i !== hoverFrame) {
+            hoverFrame = i;
+            canvas.style.cursor = i >= 0 ? 'pointer' : '';
+            if (i >= 0) showTooltip(i); else tooltip.classList.remove('visible');
+        }
+        if (i < 0) return;
+        const rect = tooltip.getBoundingClientRect();
+        tooltip.style.left = Math.min(e.clientX + 16, window.innerWidth - rect.width - 16) + 'px';
+        tooltip.style.top = Math.min(e.clientY + 16, window.innerHeight - rect.height - 16) + 'px';
+    });
+    
+    canvas.addEventListener('mouseleave', () => {
+        hoverFrame = -1;
+        tooltip.classList.remove('visible');
+    });
+    
+    canvas.addEventListener('click', (e) => {
+        const i = frameAt(e);
+        if (i >= 0) zoomTo(i);
+    });
+    
+    canvas.addEventListener('contextmenu', (e) => {
+        const i = frameAt(e);
+        if (i < 0) return;
+        e.preventDefault();
+        contextTarget = i;
+        updateContextMenu();
+        contextMenu.style.left = e.clientX + 'px';
+        contextMenu.style.top = e.clientY + 'px';
+        contextMenu.classList.add('visible');
+        
+        setTimeout(() => {
+            const rect = contextMenu.getBoundingClientRect();
+            if (rect.right > window.innerWidth) {
+                contextMenu.style.left = (e.clientX - rect.width) + 'px';
+            }
+            if (rect.bottom > window.innerHeight) {
+                contextMenu.style.top = (e.clientY - rect.height) + 'px';
+            }
+        }, 0);
+    });
+    
+    function hideContextMenu() {
+        contextMenu.classList.remove('visible');
+        contextTarget = null;
+    }
+    
+    hideStackBtn.addEventListener('click', () => {
+        if (contextTarget === null) return;
+        const i = contextTarget;
+        hiddenStacks.push([ft.start[i], ft.end[i], ft.depth[i]]);
+        
+        // Merge the removed sample ranges
+        const ranges = hiddenStacks.map(([s, e]) => [s, e]).sort((a, b) => a[0] - b[0]);
+        hiddenRanges = [];
+        ranges.forEach(([s, e]) => {
+            const last = hiddenRanges[hiddenRanges.length - 1];
+            if (last && s <= last[1]) last[1] = Math.max(last[1], e);
+            else hiddenRanges.push([s, e]);
+        });
+        if (zoomedFrame >= 0 && isFrameHidden(zoomedFrame)) zoomedFrame = -1;
+        
+        hideContextMenu();
+        applySearch();
+        resetBtn.disabled = false;
+    });
+    
+    resetHiddenBtn.addEventListener('click', () => {
+        hideContextMenu();
+        resetAll();
+    });
+    
+    function zoomTo(i) {
+        zoomedFrame = i;
+        resetBtn.disabled = false;
+        applySearch();
+    }
+    
+    function resetAll() {
+        zoomedFrame = -1;
+        hiddenStacks = [];
+        hiddenRanges = [];
+        resetBtn.disabled = true;
+        searchTerm = null;
+        searchInput.value = '';
+        nameMatches = null;
+        matchedStat.style.display = 'none';
+        clearSearchBtn.style.display = 'none';
+        draw();
+    }
+    
+    function applySearch() {
+        if (!searchTerm) {
+            nameMatches = null;
+            matchedStat.style.display = 'none';
+            clearSearchBtn.style.display = 'none';
+            draw();
+            return;
+        }
+        
+        let regex;
+        try {
+            regex = new RegExp(searchTerm, 'i');
+        } catch (e) {
+            return;
+        }
+        nameMatches = ft.names.map(name => regex.test(name));
+        
+        // Matched samples over the widest frame in view, as on the DOM page
+        const [x0, x1] = viewRange();
+        const zoomDepth = zoomedFrame >= 0 ? ft.depth[zoomedFrame] : 0;
+        let matchedSamples = 0;
+        for (let depth = zoomDepth; depth <= depthMax; depth++) {
+            for (let i = firstFrameAfter(depth, x0); i < ft.rows[depth + 1] && mapX(ft.start[i]) < x1; i++) {
+                if (nameMatches[ft.name[i]] && mapX(ft.end[i]) > mapX(ft.start[i])) {
+                    matchedSamples += ft.end[i] - ft.start[i];
+                }
+            }
+        }
+        const visibleSamples = zoomedFrame >= 0 ? ft.end[zoomedFrame] - ft.start[zoomedFrame] : totalSamples;
+        const matchedPct = visibleSamples > 0 ? (matchedSamples / visibleSamples * 100) : 0;
+        matchedValue.textContent = matchedPct.toFixed(1) + '%';
+        matchedStat.style.display = 'flex';
+        clearSearchBtn.style.display = 'block';
+        draw();
+    }
+    
+    function clearSearch() {
+        searchTerm = null;
+        searchInput.value = '';
+        applySearch();
+        if (hiddenStacks.length === 0 && zoomedFrame < 0) {
+            resetBtn.disabled = true;
+        }
+    }
+    
+    document.addEventListener('click', (e) => {
+        if (!contextMenu.contains(e.target) && e.target !== canvas) {
+            hideContextMenu();
+        }
+    });
+    
+    searchInput.addEventListener('input', (e) => {
+        searchTerm = e.target.value || null;
+        applySearch();
+        if (searchTerm) resetBtn.disabled = false;
+    });
+    
+    resetBtn.addEventListener('click', resetAll);
+    clearSearchBtn.addEventListener('click', clearSearch);
+    
+    document.addEventListener('keydown', (e) => {
+        if (e.key === 'Escape') {
+            if (contextMenu.classList.contains('visible')) {
+                hideContextMenu();
+            } else if (searchTerm || hiddenStacks.length > 0 || zoomedFrame >= 0) {
+                resetAll();
+            }
+        } else if (e.key === '/' && document.activeElement !== searchInput) {
+            e.preventDefault();
+            searchInput.focus();
+        }
+    });
+    
+    // Redraw when the chart is resized, including when it is shown again
+    if (window.ResizeObserver) {
+        new ResizeObserver(() => draw()).observe(chart);
+    } else {
+        window.addEventListener('resize', draw);
+    }
+    showInitialChart();
+"#;
+
+/// Sandwich view: right-clicking a frame offers its callers (inverted, growing up
+/// into it) and callees (merged, hanging below it) across the whole graph, in place
+/// of the chart. Expects `STACK_RENDER_JS` and the sandwich markup.
+const SANDWICH_JS: &str = r#"
+    let sandwichFrames = [];
+    
+    function showSandwich(name) {
+        const callers = new Map();
+        const callees = new Map();
+        (shownCounts || stackCounts).forEach((n, key) => {
+            const stack = key.split(';');
+            // Outermost call only, so recursion isn't counted twice
+            const at = stack.findIndex(id => frameNames[id] === name);
+            if (at < 0) return;
+            const up = stack.slice(0, at + 1).reverse().join(';');
+            const down = stack.slice(at).join(';');
+            callers.set(up, (callers.get(up) || 0) + n);
+            callees.set(down, (callees.get(down) || 0) + n);
+        });
+        if (callers.size === 0) return;
+        
+        // Both graphs are rooted at the function itself, so the "all" frame is skipped
+        [[sandwichCallers, callers], [sandwichCallees, callees]].forEach(([el, counts]) => {
+            const layout = layoutStacks(counts);
+            el.innerHTML = framesHtml(layout, 1);
+            el.style.height = (layout.depthMax * frameHeight) + 'px';
+        });
+        sandwichFrames = Array.from(sandwich.querySelectorAll('.frame'));
+        sandwichFrames.forEach(bindSandwichFrame);
+        applyPalette(paletteSelect.value, sandwichFrames);
+        
+        tooltip.classList.remove('visible');
+        sandwichName.textContent = fullNames.get(name) || name;
+        chart.parentNode.style.display = 'none';
+        sandwich.style.display = '';
+    }
+    
+    function closeSandwich() {
+        tooltip.classList.remove('visible');
+        sandwich.style.display = 'none';
+        chart.parentNode.style.display = '';
+        sandwichCallers.innerHTML = sandwichCallees.innerHTML = '';
+        sandwichFrames = [];
+    }
+    
+    function bindSandwichFrame(frame) {
+        frame.addEventListener('mouseenter', () => {
+            const samples = parseInt(frame.dataset.samples);
+            const depth = parseInt(frame.dataset.depth);
+            const start = parseInt(frame.dataset.start);
+            const end = parseInt(frame.dataset.end);
+            let childSamples = 0;
+            frame.parentNode.querySelectorAll('.frame').forEach(f => {
+                if (parseInt(f.dataset.depth) === depth + 1 && parseInt(f.dataset.start) >= start &&
+                    parseInt(f.dataset.end) <= end) {
+                    childSamples += parseInt(f.dataset.samples);
+                }
+            });
+            const selfSamples = samples - childSamples;
+            tooltipName.textContent = fullNames.get(frame.dataset.name) || frame.dataset.name;
+            tooltipSamples.textContent = formatValue(samples);
+            tooltipPct.textContent = (samples / totalSamples * 100).toFixed(2) + '%';
+            tooltipSelf.textContent = formatValue(selfSamples) + ' (' +
+                (selfSamples / totalSamples * 100).toFixed(2) + '%)';
+            tooltipRecursion.style.display = tooltipRecursionLabel.style.display = 'none';
+            showFrameDetails(parseInt(frame.dataset.id), null);
+            tooltip.classList.add('visible');
+        });
+        frame.addEventListener('mouseleave', () => {
+            tooltip.classList.remove('visible');
+        });
+        frame.addEventListener('mousemove', (e) => {
+            const rect = tooltip.getBoundingClientRect();
+            tooltip.style.left = Math.min(e.clientX + 16, window.innerWidth - rect.width - 16) + 'px';
+            tooltip.style.top = Math.min(e.clientY + 16, window.innerHeight - rect.height - 16) + 'px';
+        });
+        // Re-centre the sandwich on a caller or callee
+        frame.addEventListener('click', () => {
+            showSandwich(frame.dataset.name);
+        });
+    }
+    
+    showSandwichBtn.addEventListener('click', () => {
+        if (contextTarget === null) return;
+        const name = frameName(contextTarget);
+        hideContextMenu();
+        if (name !== 'all') showSandwich(name);
+    });
+    
+    closeSandwichBtn.addEventListener('click', closeSandwich);
+    paletteSelect.addEventListener('change', () => applyPalette(paletteSelect.value, sandwichFrames));
+    colorBySelect.addEventListener('change', () => applyPalette(paletteSelect.value, sandwichFrames));
+    document.addEventListener('keydown', (e) => {
+        if (e.key === 'Escape' && sandwichFrames.length) closeSandwich();
+    });
+"#;
+
+/// Function table tab: sorting, filtering, and highlighting a clicked function in
+/// the graph through the search box. Expects `functionStats`, `STACK_RENDER_JS`,
+/// `SOURCE_VIEW_JS` and the table markup.
+const FUNCTION_TABLE_JS: &str = r#"
+    let tableSort = { col: 1, desc: true };
+    let tableRendered = false;
+    
+    function renderTable() {
+        let regex = null;
+        try {
+            regex = tableFilter.value ? new RegExp(tableFilter.value, 'i') : null;
+        } catch (e) {
+            return;
+        }
+        const rows = functionStats.filter(r => !regex || regex.test(r[0]) || regex.test(r[3]));
+        const { col, desc } = tableSort;
+        rows.sort((a, b) => {
+            const cmp = col === 0 || col === 3 ? a[col].localeCompare(b[col]) : a[col] - b[col];
+            return desc ? -cmp : cmp;
+        });
+        // Percentages are of the whole profile, like the generator's counts
+        const pct = n => (n / initialChart.total * 100).toFixed(2) + '%';
+        functionRows.innerHTML = rows.map(r => {
+            const name = escapeHtml(r[0]);
+            const source = annotatedSource(r[5]);
+            const link = source ? '<span class="source-link" data-file="' + escapeHtml(source[0]) +
+                '" data-line="' + source[1] + '">source</span>' : '';
+            return '<tr data-name="' + name + '"><td class="name" title="' + name + '">' + name +
+                '</td><td class="num">' + formatValue(r[1]) + '</td><td class="num">' + pct(r[1]) +
+                '</td><td class="num">' + formatValue(r[2]) + '</td><td class="num">' + pct(r[2]) +
+                '</td><td>' + escapeHtml(r[3]) + link + '</td><td class="num">' + r[4] + '</td></tr>';
+        }).join('');
+        tableCount.textContent = rows.length + ' of ' + functionStats.length + ' functions';
+        functionTable.querySelectorAll('th').forEach(th => {
+            const sorted = parseInt(th.dataset.col) === col;
+            th.classList.toggle('sorted', sorted);
+            th.classList.toggle('desc', sorted && desc);
+        });
+        tableRendered = true;
+    }
+    
+    function showTable(show) {
+        tableView.style.display = show ? '' : 'none';
+        graphView.style.display = show ? 'none' : '';
+        tableTab.classList.toggle('active', show);
+        graphTab.classList.toggle('active', !show);
+        tooltip.classList.remove('visible');
+        if (show && !tableRendered) renderTable();
+    }
+    
+    graphTab.addEventListener('click', () => showTable(false));
+    tableTab.addEventListener('click', () => showTable(true));
+    tableFilter.addEventListener('input', renderTable);
+    
+    functionTable.querySelectorAll('th').forEach(th => {
+        th.addEventListener('click', () => {
+            const col = parseInt(th.dataset.col);
+            // Names and modules read best A-Z, counts highest first
+            const textual = col === 0 || col === 3;
+            tableSort = tableSort.col === col ? { col, desc: !tableSort.desc } : { col, desc: !textual };
+            renderTable();
+        });
+    });
+    
+    functionRows.addEventListener('click', (e) => {
+        const link = e.target.closest('.source-link');
+        if (link) {
+            showSource(link.dataset.file, parseInt(link.dataset.line));
+            return;
+        }
+        const row = e.target.closest('tr');
+        if (!row) return;
+        showTable(false);
+        searchTerm = '^' + row.dataset.name.replace(/[.*+?^${}()|[\]\\]/g, '\\$&') + '$';
+        searchInput.value = searchTerm;
+        applySearch();
+        resetBtn.disabled = false;
+    });
+"#;
+
+/// Source tab: annotated files with each line shaded by its self samples, opened
+/// from the tab, the context menu's "Show source" or a function table row. Expects
+/// `sourceFiles`, the source view markup, `showSourceBtn` and the views and tabs of
+/// `FUNCTION_TABLE_JS`.
+const SOURCE_VIEW_JS: &str = r#"
+    // File and line of a frame's source position, if its file is annotated
+    function annotatedSource(id) {
+        const origin = frameOrigins[id];
+        const source = origin ? origin[4] : '';
+        const colon = source.lastIndexOf(':');
+        const file = source.slice(0, colon);
+        return colon > 0 && sourceFiles.has(file) ? [file, parseInt(source.slice(colon + 1))] : null;
+    }
+    
+    if (sourceFiles.size) {
+        // Hottest files first
+        const files = Array.from(sourceFiles, ([file, [, counts]]) => {
+            let total = 0;
+            for (let i = 1; i < counts.length; i += 2) total += counts[i];
+            return [file, total];
+        }).sort((a, b) => b[1] - a[1] || a[0].localeCompare(b[0]));
+        sourceFile.innerHTML = files.map(([file, total]) => '<option value="' + escapeHtml(file) + '">' +
+            escapeHtml(file) + ' (' + (total / initialChart.total * 100).toFixed(2) + '%)</option>').join('');
+        sourceTab.style.display = '';
+    }
+    
+    function renderSource(file, line) {
+        const [text, counts] = sourceFiles.get(file);
+        const samples = new Map();
+        let max = 0;
+        for (let i = 0; i < counts.length; i += 2) {
+            samples.set(counts[i], counts[i + 1]);
+            max = Math.max(max, counts[i + 1]);
+        }
+        // Percentages are of the whole profile, like the function table's
+        const pct = n => (n / initialChart.total * 100).toFixed(2) + '%';
+        // A final newline doesn't start another line
+        sourceRows.innerHTML = text.replace(/\n$/, '').split('\n').map((code, i) => {
+            const n = samples.get(i + 1) || 0;
+            // Square root keeps lukewarm lines visible next to very hot ones
+            const shade = n ? ' style="background:rgba(239, 68, 68, ' +
+                (0.1 + 0.5 * Math.sqrt(n / max)).toFixed(3) + ')"' : '';
+            return '<tr' + (i + 1 === line ? ' class="current"' : '') + shade + '><td class="num">' + (i + 1) +
+                '</td><td class="num">' + (n ? formatValue(n) : '') + '</td><td class="num">' + (n ? pct(n) : '') +
+                '</td><td class="code">' + escapeHtml(code) + '</td></tr>';
+        }).join('');
+        sourceFile.value = file;
+        sourceCount.textContent = samples.size + (samples.size === 1 ? ' sampled line' : ' sampled lines');
+        const row = line ? sourceRows.children[line - 1] : null;
+        if (row) row.scrollIntoView({ block: 'center' });
+    }
+    
+    function showSource(file, line) {
+        graphView.style.display = tableView.style.display = 'none';
+        sourceView.style.display = '';
+        graphTab.classList.remove('active');
+        tableTab.classList.remove('active');
+        sourceTab.classList.add('active');
+        tooltip.classList.remove('visible');
+        renderSource(file, line);
+    }
+    
+    function hideSource() {
+        sourceView.style.display = 'none';
+        sourceTab.classList.remove('active');
+    }
+    
+    graphTab.addEventListener('click', hideSource);
+    tableTab.addEventListener('click', hideSource);
+    sourceTab.addEventListener('click', () => showSource(sourceFile.value, null));
+    sourceFile.addEventListener('change', () => renderSource(sourceFile.value, null));
+    
+    showSourceBtn.addEventListener('click', () => {
+        if (contextTarget === null) return;
+        const source = annotatedSource(frameId(contextTarget));
+        hideContextMenu();
+        if (source) showSource(source[0], source[1]);
+    });
+"#;
+
+/// Heat map drawing and range selection. Expects `heatmapData` and `STACK_RENDER_JS`.
+const HEATMAP_JS: &str = r#"
+    // Sub-second offset heat map: one column per second, one row per sub-second bucket
+    const heatmap = document.getElementById('heatmap');
+    const heatmapRange = document.getElementById('heatmapRange');
+    const clearRangeBtn = document.getElementById('clearRange');
+    const hmRows = heatmapData.rows;
+    const hmRowMs = 1000 / hmRows;
+    const hmSamples = heatmapData.samples;
+    let hmCols = 1;
+    for (let i = 0; i < hmSamples.length; i += 3) {
+        hmCols = Math.max(hmCols, Math.floor(hmSamples[i] / 1000) + 1);
+    }
+    const hmCounts = new Float64Array(hmCols * hmRows);
+    let hmMax = 0;
+    for (let i = 0; i < hmSamples.length; i += 3) {
+        const cell = Math.floor(hmSamples[i] / 1000) * hmRows + Math.floor((hmSamples[i] % 1000) / hmRowMs);
+        hmCounts[cell] += hmSamples[i + 2];
+        hmMax = Math.max(hmMax, hmCounts[cell]);
+    }
+    
+    let hmSelection = null;
+    let hmDragStart = null;
+    
+    function cellAt(e) {
+        const rect = heatmap.getBoundingClientRect();
+        const col = Math.min(hmCols - 1, Math.max(0, Math.floor((e.clientX - rect.left) / rect.width * hmCols)));
+        const row = Math.min(hmRows - 1, Math.max(0, Math.floor((e.clientY - rect.top) / rect.height * hmRows)));
+        return col * hmRows + row;
+    }
+    
+    function cellStartMs(cell) {
+        return Math.floor(cell / hmRows) * 1000 + (cell % hmRows) * hmRowMs;
+    }
+    
+    function formatSeconds(ms) {
+        return (ms / 1000).toFixed(2) + 's';
+    }
+    
+    function drawHeatmap() {
+        const dpr = window.devicePixelRatio || 1;
+        const width = heatmap.clientWidth;
+        const height = heatmap.clientHeight;
+        heatmap.width = width * dpr;
+        heatmap.height = height * dpr;
+        const ctx = heatmap.getContext('2d');
+        ctx.scale(dpr, dpr);
+        ctx.clearRect(0, 0, width, height);
+        const cw = width / hmCols;
+        const ch = height / hmRows;
+        for (let cell = 0; cell < hmCounts.length; cell++) {
+            const x = Math.floor(cell / hmRows) * cw;
+            const y = (cell % hmRows) * ch;
+            if (hmCounts[cell] > 0) {
+                // Square root keeps sparse cells visible next to very hot ones
+                const t = Math.sqrt(hmCounts[cell] / hmMax);
+                ctx.fillStyle = `hsl(${250 - 210 * t}, 85%, ${22 + 40 * t}%)`;
+                ctx.fillRect(x, y, Math.ceil(cw), Math.ceil(ch));
+            }
+            if (hmSelection && cell >= hmSelection.from && cell <= hmSelection.to) {
+                ctx.fillStyle = 'rgba(250, 204, 21, 0.45)';
+                ctx.fillRect(x, y, Math.ceil(cw), Math.ceil(ch));
+            }
+        }
+    }
+    
+    function applyRange() {
+        const fromMs = cellStartMs(hmSelection.from);
+        const toMs = cellStartMs(hmSelection.to) + hmRowMs;
+        const counts = new Map();
+        let selected = 0;
+        for (let i = 0; i < hmSamples.length; i += 3) {
+            if (hmSamples[i] >= fromMs && hmSamples[i] < toMs) {
+                const key = heatmapData.stacks[hmSamples[i + 1]];
+                counts.set(key, (counts.get(key) || 0) + hmSamples[i + 2]);
+                selected += hmSamples[i + 2];
+            }
+        }
+        renderStacks(counts);
+        heatmapRange.textContent = formatSeconds(fromMs) + ' – ' + formatSeconds(toMs) +
+            ' (' + describeValue(selected) + ')';
+        clearRangeBtn.style.display = 'block';
+    }
+    
+    function clearRange() {
+        hmSelection = null;
+        drawHeatmap();
+        restoreChart();
+        heatmapRange.textContent = 'Drag across the heat map to select a time range';
+        clearRangeBtn.style.display = 'none';
+    }
+    
+    heatmap.addEventListener('mousedown', (e) => {
+        e.preventDefault();
+        hmDragStart = cellAt(e);
+        hmSelection = { from: hmDragStart, to: hmDragStart };
+        drawHeatmap();
+    });
+    
+    heatmap.addEventListener('mousemove', (e) => {
+        if (hmDragStart !== null) return;
+        const cell = cellAt(e);
+        if (!hmSelection) {
+            heatmapRange.textContent = formatSeconds(cellStartMs(cell)) + ': ' +
+                describeValue(hmCounts[cell]);
+        }
+    });
+    
+    document.addEventListener('mousemove', (e) => {
+        if (hmDragStart === null) return;
+        const cell = cellAt(e);
+        hmSelection = { from: Math.min(hmDragStart, cell), to: Math.max(hmDragStart, cell) };
+        heatmapRange.textContent = formatSeconds(cellStartMs(hmSelection.from)) + ' – ' +
+            formatSeconds(cellStartMs(hmSelection.to) + hmRowMs);
+        drawHeatmap();
+    });
+    
+    document.addEventListener('mouseup', () => {
+        if (hmDragStart === null) return;
+        hmDragStart = null;
+        applyRange();
+    });
+    
+    clearRangeBtn.addEventListener('click', clearRange);
+    window.addEventListener('resize', drawHeatmap);
+    drawHeatmap();
+"#;
+
+/// Thread timeline drawing and selection. Expects `laneData`, `STACK_RENDER_JS`
+/// and `FRAME_COLOR_JS`.
+const LANES_JS: &str = r#"
+    // Thread timeline: a lane per thread under a header row per process, with each
+    // sample drawn in the colour of its top frame
+    const lanesCanvas = document.getElementById('lanes');
+    const lanesRange = document.getElementById('lanesRange');
+    const clearLanesBtn = document.getElementById('clearLanes');
+    const laneLabelWidth = 160;
+    const laneRows = [];
+    let lanesHeight = 0;
+    laneData.lanes.forEach(([process, label], lane) => {
+        if (lane === 0 || laneData.lanes[lane - 1][0] !== process) {
+            laneRows.push({ y: lanesHeight, height: 18, lane: -1, label: process });
+            lanesHeight += 18;
+        }
+        laneRows.push({ y: lanesHeight, height: 14, lane, label });
+        lanesHeight += 14;
+    });
+    // Frame id of each stack's leaf, or -1 for an empty stack
+    const laneTops = laneData.stacks.map(stack => stack === '' ? -1 : Number(stack.slice(stack.lastIndexOf(';') + 1)));
+    const laneHelp = lanesRange.textContent;
+    
+    // Offsets into laneData.samples of each lane's samples, in time order
+    const laneSamples = laneData.lanes.map(() => []);
+    for (let i = 0; i < laneData.samples.length; i += 4) laneSamples[laneData.samples[i + 1]].push(i);
+    
+    let laneSelection = null;
+    let laneDrag = null;
+    
+    function laneX(t, width) {
+        return laneLabelWidth + t / laneData.total * (width - laneLabelWidth);
+    }
+    
+    function laneTimeAt(e) {
+        const rect = lanesCanvas.getBoundingClientRect();
+        const x = (e.clientX - rect.left - laneLabelWidth) / (rect.width - laneLabelWidth);
+        return Math.min(laneData.total, Math.max(0, x * laneData.total));
+    }
+    
+    // Lane under the mouse; process headers count as their first thread
+    function laneAt(e) {
+        const y = e.clientY - lanesCanvas.getBoundingClientRect().top;
+        const row = laneRows.find(r => y < r.y + r.height) || laneRows[laneRows.length - 1];
+        return row.lane >= 0 ? row.lane : laneRows[laneRows.indexOf(row) + 1].lane;
+    }
+    
+    // Offset of the lane's sample covering time t, or -1
+    function laneSampleAt(lane, t) {
+        const offsets = laneSamples[lane];
+        let lo = 0;
+        let hi = offsets.length;
+        while (lo < hi) {
+            const mid = (lo + hi) >> 1;
+            if (laneData.samples[offsets[mid]] <= t) lo = mid + 1; else hi = mid;
+        }
+        const i = offsets[lo - 1];
+        return i !== undefined && t < laneData.samples[i] + laneData.interval ? i : -1;
+    }
+    
+    function drawLanes() {
+        const palette = palettes[paletteSelect.value];
+        if (!palette) return;
+        const dpr = window.devicePixelRatio || 1;
+        const width = lanesCanvas.clientWidth;
+        lanesCanvas.style.height = lanesHeight + 'px';
+        lanesCanvas.width = width * dpr;
+        lanesCanvas.height = lanesHeight * dpr;
+        const ctx = lanesCanvas.getContext('2d');
+        ctx.scale(dpr, dpr);
+        ctx.clearRect(0, 0, width, lanesHeight);
+        ctx.textBaseline = 'middle';
+        
+        // Labels follow the theme: process names in the page's text colour, threads
+        // and lane backgrounds in the canvas's own
+        const headerColor = window.getComputedStyle(document.body).color;
+        const labelColor = window.getComputedStyle(lanesCanvas).color;
+        const colors = new Map();
+        const sampleWidth = Math.max(1, laneData.interval / laneData.total * (width - laneLabelWidth));
+        laneRows.forEach(row => {
+            const header = row.lane < 0;
+            ctx.font = header ? "600 11px 'Inter', sans-serif" : "11px 'Inter', sans-serif";
+            ctx.fillStyle = header ? headerColor : labelColor;
+            ctx.fillText(row.label, header ? 0 : 12, row.y + row.height / 2, laneLabelWidth - 16);
+            if (header) return;
+            
+            ctx.globalAlpha = 0.06;
+            ctx.fillRect(laneLabelWidth, row.y + 1, width - laneLabelWidth, row.height - 2);
+            ctx.globalAlpha = 1;
+            laneSamples[row.lane].forEach(i => {
+                const top = laneTops[laneData.samples[i + 2]];
+                if (!colors.has(top)) colors.set(top, frameColor(palette, top));
+                ctx.fillStyle = colors.get(top);
+                ctx.fillRect(laneX(laneData.samples[i], width), row.y + 1, sampleWidth, row.height - 2);
+            });
+        });
+        
+        if (laneSelection) {
+            const top = laneRows.find(r => r.lane === laneSelection.first).y;
+            const bottom = laneRows.find(r => r.lane === laneSelection.last);
+            const x = laneX(laneSelection.from, width);
+            ctx.fillStyle = 'rgba(250, 204, 21, 0.3)';
+            ctx.fillRect(x, top, Math.max(1, laneX(laneSelection.to, width) - x), bottom.y + bottom.height - top);
+        }
+    }
+    
+    function formatLaneTime(us) {
+        return (us / 1e6).toFixed(3) + 's';
+    }
+    
+    function describeLaneSelection() {
+        const threads = laneSelection.last - laneSelection.first + 1;
+        return formatLaneTime(laneSelection.from) + ' – ' + formatLaneTime(laneSelection.to) + ' on ' +
+            (threads === 1 ? laneData.lanes[laneSelection.first][1] : threads + ' threads');
+    }
+    
+    function applyLanes() {
+        const counts = new Map();
+        let selected = 0;
+        for (let lane = laneSelection.first; lane <= laneSelection.last; lane++) {
+            laneSamples[lane].forEach(i => {
+                const t = laneData.samples[i];
+                if (t < laneSelection.from || t >= laneSelection.to) return;
+                const key = laneData.stacks[laneData.samples[i + 2]];
+                counts.set(key, (counts.get(key) || 0) + laneData.samples[i + 3]);
+                selected += laneData.samples[i + 3];
+            });
         }
-        result.push(c);
+        renderStacks(counts);
+        lanesRange.textContent = describeLaneSelection() + ' (' + describeValue(selected) + ')';
+        clearLanesBtn.style.display = 'block';
     }
-    result
-}
+    
+    function clearLanes() {
+        laneSelection = null;
+        drawLanes();
+        restoreChart();
+        lanesRange.textContent = laneHelp;
+        clearLanesBtn.style.display = 'none';
+    }
+    
+    function dragLanes(e) {
+        const t = laneTimeAt(e);
+        const lane = laneAt(e);
+        laneSelection = {
+            from: Math.min(laneDrag.t, t), to: Math.max(laneDrag.t, t),
+            first: Math.min(laneDrag.lane, lane), last: Math.max(laneDrag.lane, lane)
+        };
+    }
+    
+    lanesCanvas.addEventListener('mousedown', (e) => {
+        e.preventDefault();
+        laneDrag = { t: laneTimeAt(e), lane: laneAt(e) };
+        dragLanes(e);
+        drawLanes();
+    });
+    
+    lanesCanvas.addEventListener('mousemove', (e) => {
+        if (laneDrag !== null || laneSelection) return;
+        const lane = laneAt(e);
+        const t = laneTimeAt(e);
+        const i = laneSampleAt(lane, t);
+        lanesRange.textContent = laneData.lanes[lane][1] + ' at ' + formatLaneTime(t) + ': ' +
+            (i < 0 ? 'not sampled' : frameNames[laneTops[laneData.samples[i + 2]]] || '');
+    });
+    
+    lanesCanvas.addEventListener('mouseleave', () => {
+        if (laneDrag === null && !laneSelection) lanesRange.textContent = laneHelp;
+    });
+    
+    document.addEventListener('mousemove', (e) => {
+        if (laneDrag === null) return;
+        dragLanes(e);
+        lanesRange.textContent = describeLaneSelection();
+        drawLanes();
+    });
+    
+    document.addEventListener('mouseup', () => {
+        if (laneDrag === null) return;
+        laneDrag = null;
+        // A click without dragging selects the whole of its lane
+        if (laneSelection.from === laneSelection.to) {
+            laneSelection.from = 0;
+            laneSelection.to = laneData.total;
+        }
+        applyLanes();
+        drawLanes();
+    });
+    
+    clearLanesBtn.addEventListener('click', clearLanes);
+    paletteSelect.addEventListener('change', drawLanes);
+    colorBySelect.addEventListener('change', drawLanes);
+    document.addEventListener('themechange', drawLanes);
+    window.addEventListener('resize', drawLanes);
+    drawLanes();
+"#;
 
 /// Generate a flame graph HTML document.
 ///
 /// # Arguments
-/// * `stacks` - HashMap of semicolon-separated stack traces to sample counts
+/// * `profile` - Collapsed stacks with their frames
 /// * `title` - Title for the flame graph
 /// * `subtitle` - Optional subtitle
+/// * `opts` - Rendering options
 ///
 /// # Returns
 /// Complete HTML document as a string
-pub fn generate_flamegraph(
-    stacks: &HashMap<String, u64>,
+pub fn generate_flamegraph_with_options(
+    profile: &Profile,
     title: &str,
     subtitle: Option<&str>,
+    opts: &Options,
 ) -> String {
-    let (frames, total_samples, depth_max) = process_stacks(stacks);
+    // The heat map's and timeline's samples have frames of their own
+    let samples = opts.heatmap.or(opts.lanes);
+    let details = FrameDetails::new(std::iter::once(profile).chain(samples.map(|table| &table.profile)));
+    let stacks = &details.stacks(profile, 0);
+    let (frames, total_samples, depth_max) = if opts.inverted {
+        process_stacks(&invert_stacks(stacks), &details)
+    } else {
+        process_stacks(stacks, &details)
+    };
     
     if total_samples == 0 {
         return generate_error_html("No valid stack data provided");
     }
 
+    let (heatmap_html, extra_js) = opts
+        .heatmap
+        .and_then(|table| heatmap_parts(table, &details.ids[1]))
+        .or_else(|| opts.lanes.and_then(|table| lanes_parts(table, &details.ids[1])))
+        .unwrap_or_default();
+
     let frame_height = 20;
     let chart_height = (depth_max + 1) * frame_height;
+    let (tabs_html, table_html) = function_table_html("");
 
     let mut html = String::with_capacity(512 * 1024);
     
     // HTML header and styles
     write!(html, r##"<!DOCTYPE html>
-<html lang="en">
+<html lang="en" data-theme="{theme}">
 <head>
 <meta charset="UTF-8">
 <meta name="viewport" content="width=device-width, initial-scale=1.0">
@@ -377,6 +3196,180 @@ header {{
     display: none;
 }}
 
+.chart.icicle,
+.chart.icicle .frame {{
+    transform: scaleY(-1);
+}}
+
+.chart canvas {{
+    display: block;
+}}
+
+.sandwich-header {{
+    display: flex;
+    align-items: center;
+    gap: 12px;
+    margin-bottom: 8px;
+}}
+
+.sandwich-name {{
+    flex: 1;
+    font-family: 'SF Mono', 'Fira Code', Consolas, monospace;
+    font-size: 0.8125rem;
+    color: #f1f5f9;
+    overflow: hidden;
+    text-overflow: ellipsis;
+    white-space: nowrap;
+}}
+
+.sandwich-header .btn {{
+    padding: 6px 12px;
+    font-size: 0.75rem;
+}}
+
+.sandwich .chart-container + .chart-container {{
+    margin-top: 2px;
+}}
+
+.tabs {{
+    display: flex;
+    gap: 4px;
+    margin-bottom: 12px;
+    border-bottom: 1px solid rgba(255, 255, 255, 0.05);
+}}
+
+.tab {{
+    background: none;
+    border: none;
+    border-bottom: 2px solid transparent;
+    padding: 8px 14px;
+    font-size: 0.8125rem;
+    font-weight: 500;
+    color: #64748b;
+    cursor: pointer;
+}}
+
+.tab:hover {{
+    color: #e2e8f0;
+}}
+
+.tab.active {{
+    color: #e2e8f0;
+    border-bottom-color: rgb(99, 102, 241);
+}}
+
+.table-header {{
+    display: flex;
+    align-items: center;
+    gap: 16px;
+    margin-bottom: 8px;
+}}
+
+.table-filter {{
+    background: rgba(255, 255, 255, 0.05);
+    border: 1px solid rgba(255, 255, 255, 0.1);
+    border-radius: 8px;
+    padding: 8px 12px;
+    font-size: 0.8125rem;
+    color: #e2e8f0;
+    width: 320px;
+    outline: none;
+}}
+
+.table-filter:focus {{
+    border-color: rgba(99, 102, 241, 0.5);
+}}
+
+.table-scroll {{
+    max-height: 70vh;
+    overflow: auto;
+    background: rgba(0, 0, 0, 0.2);
+    border-radius: 12px;
+    border: 1px solid rgba(255, 255, 255, 0.05);
+}}
+
+.function-table {{
+    width: 100%;
+    border-collapse: collapse;
+    font-size: 0.8125rem;
+    font-variant-numeric: tabular-nums;
+}}
+
+.function-table th {{
+    position: sticky;
+    top: 0;
+    background: #1e293b;
+    color: #94a3b8;
+    font-weight: 500;
+    text-align: left;
+    padding: 8px 12px;
+    cursor: pointer;
+    user-select: none;
+    white-space: nowrap;
+}}
+
+.function-table th.sorted::after {{
+    content: ' \25B2';
+    font-size: 0.625rem;
+}}
+
+.function-table th.sorted.desc::after {{
+    content: ' \25BC';
+}}
+
+.function-table td {{
+    padding: 5px 12px;
+    border-top: 1px solid rgba(255, 255, 255, 0.04);
+    color: #cbd5e1;
+    white-space: nowrap;
+}}
+
+.function-table td.name {{
+    font-family: 'SF Mono', 'Fira Code', Consolas, monospace;
+    font-size: 0.75rem;
+    color: #f1f5f9;
+    max-width: 600px;
+    overflow: hidden;
+    text-overflow: ellipsis;
+}}
+
+.function-table .num {{
+    text-align: right;
+}}
+
+.function-table tbody tr {{
+    cursor: pointer;
+}}
+
+.function-table tbody tr:hover {{
+    background: rgba(255, 255, 255, 0.05);
+}}
+
+.source-table tbody tr {{
+    cursor: default;
+}}
+
+.source-table tr.current {{
+    box-shadow: inset 3px 0 0 rgb(99, 102, 241);
+}}
+
+.source-table td.code {{
+    font-family: 'SF Mono', 'Fira Code', Consolas, monospace;
+    font-size: 0.75rem;
+    white-space: pre;
+    width: 100%;
+}}
+
+.source-link {{
+    margin-left: 8px;
+    color: rgb(129, 140, 248);
+    cursor: pointer;
+}}
+
+.source-link:hover {{
+    text-decoration: underline;
+}}
+
 .tooltip {{
     position: fixed;
     background: #1e293b;
@@ -463,6 +3456,7 @@ header {{
 .tooltip-stats dd {{
     color: #94a3b8;
     font-variant-numeric: tabular-nums;
+    overflow-wrap: anywhere;
 }}
 
 footer {{
@@ -534,6 +3528,81 @@ footer {{
     color: #e2e8f0;
 }}
 
+.color-legend {{
+    flex-wrap: wrap;
+    gap: 6px 16px;
+    margin-top: 8px;
+    font-size: 0.75rem;
+    color: #94a3b8;
+}}
+
+.color-legend-item {{
+    display: flex;
+    align-items: center;
+    gap: 6px;
+}}
+
+.color-swatch {{
+    width: 12px;
+    height: 12px;
+    border-radius: 3px;
+}}
+
+.heatmap-container {{
+    background: rgba(0, 0, 0, 0.2);
+    border-radius: 12px;
+    border: 1px solid rgba(255, 255, 255, 0.05);
+    padding: 12px 16px 16px;
+    margin-bottom: 16px;
+}}
+
+.heatmap-header {{
+    display: flex;
+    justify-content: space-between;
+    align-items: center;
+    gap: 12px;
+    margin-bottom: 8px;
+}}
+
+.heatmap-range {{
+    font-size: 0.75rem;
+    color: #94a3b8;
+    font-variant-numeric: tabular-nums;
+}}
+
+.heatmap-header .btn {{
+    padding: 6px 12px;
+    font-size: 0.75rem;
+}}
+
+#heatmap {{
+    display: block;
+    width: 100%;
+    height: 200px;
+    cursor: crosshair;
+    border-radius: 6px;
+}}
+
+.heatmap-axis {{
+    display: flex;
+    justify-content: space-between;
+    font-size: 0.6875rem;
+    color: #475569;
+    margin-top: 4px;
+    font-variant-numeric: tabular-nums;
+}}
+
+#lanes {{
+    display: block;
+    width: 100%;
+    color: #94a3b8;
+    cursor: crosshair;
+}}
+
+.lanes-axis {{
+    padding-left: 160px;
+}}
+
 @media (max-width: 768px) {{
     .container {{
         padding: 16px;
@@ -552,7 +3621,9 @@ footer {{
         flex-wrap: wrap;
     }}
 }}
+{theme_css}
 </style>
+{custom_css}<script>{theme_js}</script>
 </head>
 <body>
 <div class="container">
@@ -576,80 +3647,100 @@ footer {{
     
     <div class="stats">
         <div class="stat">
-            <span class="stat-label">Total Samples</span>
-            <span class="stat-value">{total_samples_fmt}</span>
+            <span class="stat-label">{total_label}</span>
+            <span class="stat-value" id="totalSamplesValue">{total_samples_fmt}</span>
         </div>
         <div class="stat">
             <span class="stat-label">Max Depth</span>
-            <span class="stat-value">{depth_max}</span>
+            <span class="stat-value" id="depthMaxValue">{depth_max}</span>
         </div>
         <div class="stat" id="matchedStat" style="display:none">
             <span class="stat-label">Matched</span>
             <span class="stat-value" id="matchedValue">0%</span>
         </div>
     </div>
-    
+    {tabs_html}
+    <div id="graphView">
+    {heatmap_html}
+    {sandwich_html}
     <div class="chart-container">
-        <div class="chart" id="chart">
+        <div class="{chart_class}" id="chart">
 "##,
         title = escape_html(title),
+        theme = opts.theme.id(),
+        theme_css = THEME_CSS,
+        custom_css = custom_css_html(opts),
+        theme_js = THEME_JS,
         chart_height = chart_height,
         frame_height_css = frame_height - 2,
         title_escaped = escape_html(title),
         subtitle_html = subtitle.map(|s| format!(r#"<p class="subtitle">{}</p>"#, escape_html(s))).unwrap_or_default(),
-        total_samples_fmt = format_samples(total_samples),
-        depth_max = depth_max
+        total_label = opts.units.total_label(),
+        total_samples_fmt = format_value(total_samples, opts.units),
+        depth_max = depth_max,
+        heatmap_html = heatmap_html,
+        sandwich_html = sandwich_html(""),
+        tabs_html = tabs_html,
+        chart_class = chart_class(opts)
     ).unwrap();
 
-    // Generate frames
-    for frame in &frames {
-        let duration = frame.end - frame.start;
-        if duration == 0 {
-            continue;
+    // Generate frames; the canvas renderer embeds them in its script instead
+    match opts.renderer {
+        Renderer::Canvas => {
+            writeln!(html, r#"            <canvas id="chartCanvas"></canvas>"#).unwrap();
         }
-        
-        let width_pct = (duration as f64 / total_samples as f64) * 100.0;
-        if width_pct < 0.08 {
-            continue; // Skip very narrow frames
+        Renderer::Dom => {
+            for frame in prune_frames(&frames, total_samples, opts.min_width, opts.aggregate_small) {
+                let duration = frame.end - frame.start;
+                let width_pct = (duration as f64 / total_samples as f64) * 100.0;
+                
+                let left_pct = (frame.start as f64 / total_samples as f64) * 100.0;
+                let bottom = frame.depth * frame_height;
+                let pct = (duration as f64 / total_samples as f64) * 100.0;
+                
+                let (r, g, b) = if frame.aggregate { AGGREGATE_RGB } else { frame_rgb(&frame, &details, opts) };
+                let display_name = if frame.name.is_empty() { "all" } else { &frame.name };
+                
+                writeln!(
+                    html,
+                    r#"            <div class="{}" style="left:{:.4}%;width:{:.4}%;bottom:{}px;background:rgb({},{},{});" data-name="{}" data-id="{}" data-samples="{}" data-pct="{:.2}" data-depth="{}" data-start="{}" data-end="{}">{}</div>"#,
+                    if frame.aggregate { "frame aggregate" } else { "frame" },
+                    left_pct,
+                    width_pct,
+                    bottom,
+                    r, g, b,
+                    escape_html(display_name),
+                    frame.id.map_or(-1, |id| id as i64),
+                    duration,
+                    pct,
+                    frame.depth,
+                    frame.start,
+                    frame.end,
+                    escape_html(display_name)
+                ).unwrap();
+            }
         }
-        
-        let left_pct = (frame.start as f64 / total_samples as f64) * 100.0;
-        let bottom = frame.depth * frame_height;
-        let pct = (duration as f64 / total_samples as f64) * 100.0;
-        
-        let (r, g, b) = color_for_name(&frame.name);
-        let display_name = if frame.name.is_empty() { "all" } else { &frame.name };
-        
-        writeln!(
-            html,
-            r#"            <div class="frame" style="left:{:.4}%;width:{:.4}%;bottom:{}px;background:rgb({},{},{});" data-name="{}" data-samples="{}" data-pct="{:.2}" data-depth="{}" data-start="{}" data-end="{}">{}</div>"#,
-            left_pct,
-            width_pct,
-            bottom,
-            r, g, b,
-            escape_html(display_name),
-            duration,
-            pct,
-            frame.depth,
-            frame.start,
-            frame.end,
-            escape_html(display_name)
-        ).unwrap();
     }
 
     // Close chart and add tooltip + context menu + footer + script
     write!(html, r##"        </div>
     </div>
+    <div class="color-legend" id="colorLegend" style="display:none"></div>
+    </div>
+    {table_html}
     
     <div class="tooltip" id="tooltip">
         <div class="tooltip-name" id="tooltipName"></div>
         <dl class="tooltip-stats">
-            <dt>Samples</dt>
+            <dt>{count_label}</dt>
             <dd id="tooltipSamples"></dd>
             <dt>Percentage</dt>
             <dd id="tooltipPct"></dd>
             <dt>Self</dt>
             <dd id="tooltipSelf"></dd>
+            <dt id="tooltipRecursionLabel" style="display:none">Recursion</dt>
+            <dd id="tooltipRecursion" style="display:none"></dd>
+            {tooltip_details}
         </dl>
     </div>
     
@@ -662,6 +3753,35 @@ footer {{
             </svg>
             <span>Hide this stack</span>
         </div>
+        <div class="context-menu-item" id="showSandwich">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <path d="M12 2 2 7l10 5 10-5-10-5z"/>
+                <path d="m2 17 10 5 10-5"/>
+                <path d="m2 12 10 5 10-5"/>
+            </svg>
+            <span>Show callers and callees</span>
+        </div>
+        <div class="context-menu-item" id="copyName">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <rect x="9" y="9" width="13" height="13" rx="2"/>
+                <path d="M5 15H4a2 2 0 0 1-2-2V4a2 2 0 0 1 2-2h9a2 2 0 0 1 2 2v1"/>
+            </svg>
+            <span>Copy full name</span>
+        </div>
+        <div class="context-menu-item" id="openEditor" style="display:none">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <path d="M18 13v6a2 2 0 0 1-2 2H5a2 2 0 0 1-2-2V8a2 2 0 0 1 2-2h6"/>
+                <path d="M15 3h6v6M10 14L21 3"/>
+            </svg>
+            <span>Open in editor</span>
+        </div>
+        <div class="context-menu-item" id="showSource" style="display:none">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <path d="M14 2H6a2 2 0 0 0-2 2v16a2 2 0 0 0 2 2h12a2 2 0 0 0 2-2V8z"/>
+                <path d="M14 2v6h6M8 13h8M8 17h5"/>
+            </svg>
+            <span>Show source</span>
+        </div>
         <div class="context-menu-separator"></div>
         <div class="context-menu-item" id="resetHidden">
             <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
@@ -676,11 +3796,25 @@ footer {{
         <div class="palette-selector">
             <label for="paletteSelect">Color Palette:</label>
             <select id="paletteSelect">
-                <option value="warm">Warm</option>
-                <option value="cool" selected>Cool</option>
-                <option value="neon">Neon</option>
-                <option value="pastel">Pastel</option>
-                <option value="mono">Monochrome</option>
+                {palette_options}
+            </select>
+        </div>
+        <div class="palette-selector">
+            <label for="colorBySelect">Color By:</label>
+            <select id="colorBySelect">
+                {color_mode_options}
+            </select>
+        </div>
+        <div class="palette-selector">
+            <label for="themeSelect">Theme:</label>
+            <select id="themeSelect" class="theme-select">
+                {theme_options}
+            </select>
+        </div>
+        <div class="palette-selector">
+            <label for="viewSelect">View:</label>
+            <select id="viewSelect">
+                {view_options}
             </select>
         </div>
         <div class="keyboard-hints">
@@ -700,6 +3834,8 @@ footer {{
     const tooltipSamples = document.getElementById('tooltipSamples');
     const tooltipPct = document.getElementById('tooltipPct');
     const tooltipSelf = document.getElementById('tooltipSelf');
+    const tooltipRecursion = document.getElementById('tooltipRecursion');
+    const tooltipRecursionLabel = document.getElementById('tooltipRecursionLabel');
     const searchInput = document.getElementById('search');
     const resetBtn = document.getElementById('resetZoom');
     const clearSearchBtn = document.getElementById('clearSearch');
@@ -709,6 +3845,31 @@ footer {{
     const hideStackBtn = document.getElementById('hideStack');
     const resetHiddenBtn = document.getElementById('resetHidden');
     const paletteSelect = document.getElementById('paletteSelect');
+    const viewSelect = document.getElementById('viewSelect');
+    const colorBySelect = document.getElementById('colorBySelect');
+    const colorLegend = document.getElementById('colorLegend');
+    const showSandwichBtn = document.getElementById('showSandwich');
+    const copyNameBtn = document.getElementById('copyName');
+    const openEditorBtn = document.getElementById('openEditor');
+    const showSourceBtn = document.getElementById('showSource');
+    const sandwich = document.getElementById('sandwich');
+    const sandwichName = document.getElementById('sandwichName');
+    const sandwichCallers = document.getElementById('sandwichCallers');
+    const sandwichCallees = document.getElementById('sandwichCallees');
+    const closeSandwichBtn = document.getElementById('closeSandwich');
+    const graphTab = document.getElementById('graphTab');
+    const tableTab = document.getElementById('tableTab');
+    const graphView = document.getElementById('graphView');
+    const tableView = document.getElementById('tableView');
+    const tableFilter = document.getElementById('tableFilter');
+    const tableCount = document.getElementById('tableCount');
+    const functionTable = document.getElementById('functionTable');
+    const functionRows = document.getElementById('functionRows');
+    const sourceTab = document.getElementById('sourceTab');
+    const sourceView = document.getElementById('sourceView');
+    const sourceFile = document.getElementById('sourceFile');
+    const sourceCount = document.getElementById('sourceCount');
+    const sourceRows = document.getElementById('sourceRows');
     
     // Color palette functions
     const palettes = {{
@@ -741,6 +3902,12 @@ footer {{
             const sat = 0.15 + ((hash >> 8) % 10) / 100;
             const lit = 0.25 + ((hash >> 16) % 30) / 100;
             return {{ h: hue, s: sat, l: lit }};
+        }},
+        offcpu: (hash) => {{
+            const hue = (hash % 70) + 100; // Green-teal range (100-170)
+            const sat = 0.45 + ((hash >> 8) % 25) / 100;
+            const lit = 0.32 + ((hash >> 16) % 12) / 100;
+            return {{ h: hue, s: sat, l: lit }};
         }}
     }};
     
@@ -770,45 +3937,129 @@ footer {{
         return hash;
     }}
     
-    function applyPalette(paletteName) {{
+    const totalSamplesValue = document.getElementById('totalSamplesValue');
+    const depthMaxValue = document.getElementById('depthMaxValue');
+    
+    let totalSamples = {total_samples};
+    const frameHeight = {frame_height};
+    const valueUnit = '{value_unit}';
+    const fullNames = new Map({full_names});
+    const recursionLevels = new Map({recursion});
+    const frameNames = {frame_names};
+    const frameOrigins = {frame_origins};
+    const colorCategories = {color_categories};
+    const stackCounts = new Map({stack_counts});
+    const timeline = null;
+    const initialInverted = {inverted};
+    const minWidth = {min_width};
+    const aggregateSmall = {aggregate_small};
+    const functionStats = {function_stats};
+    const sourceFiles = new Map({source_files});
+    
+    function formatNumber(n) {{
+        return n.toString().replace(/\B(?=(\d{{3}})+(?!\d))/g, ',');
+    }}
+    
+    // Format a frame count in the graph's units (samples, or blocked nanoseconds as ms)
+    function formatValue(n) {{
+        if (valueUnit === 'ns') {{
+            return (n / 1e6).toFixed(2).replace(/\B(?=(\d{{3}})+(?!\d))/g, ',') + ' ms';
+        }}
+        return formatNumber(n);
+    }}
+    
+    function describeValue(n) {{
+        return valueUnit === 'ns' ? formatValue(n) + ' blocked' : formatNumber(n) + ' samples';
+    }}
+    {frame_color_js}{frame_details_js}
+"##,
+        count_label = opts.units.count_label(),
+        palette_options = palette_options_html(opts.palette),
+        color_mode_options = color_mode_options_html(opts.color_by),
+        theme_options = theme_options_html(opts.theme),
+        tooltip_details = TOOLTIP_DETAILS_HTML,
+        view_options = view_options_html(opts, false),
+        table_html = table_html,
+        total_samples = total_samples,
+        frame_height = frame_height,
+        value_unit = opts.units.id(),
+        full_names = full_names_js(&details),
+        recursion = recursion_js(&details),
+        frame_names = frame_names_js(&details),
+        frame_origins = frame_origins_js(&details, opts),
+        color_categories = color_categories_js(),
+        frame_color_js = FRAME_COLOR_JS,
+        frame_details_js = FRAME_DETAILS_JS,
+        stack_counts = stack_counts_js(stacks),
+        inverted = opts.inverted,
+        min_width = opts.min_width,
+        aggregate_small = opts.aggregate_small,
+        function_stats = function_table_js(profile, 0, &details),
+        source_files = source_files_js(profile, opts)
+    ).unwrap();
+
+    match opts.renderer {
+        Renderer::Dom => write!(html, r##"    function applyPalette(paletteName, list = frames) {{
         const palette = palettes[paletteName];
         if (!palette) return;
         
-        frames.forEach(f => {{
+        list.forEach(f => {{
             const name = f.dataset.name;
             if (name === 'all') {{
                 f.style.background = 'rgb(99, 102, 241)';
                 return;
             }}
-            const hash = hashString(name);
-            const hsl = palette(hash);
-            const rgb = hslToRgb(hsl.h, hsl.s, hsl.l);
-            f.style.background = `rgb(${{rgb.r}}, ${{rgb.g}}, ${{rgb.b}})`;
+            if (name === '{separator}') {{
+                f.style.background = 'rgb(100, 116, 139)';
+                return;
+            }}
+            if (f.classList.contains('aggregate')) {{
+                f.style.background = 'rgb(71, 85, 105)';
+                return;
+            }}
+            f.style.background = frameColor(palette, parseInt(f.dataset.id));
         }});
     }}
     
     paletteSelect.addEventListener('change', (e) => {{
         applyPalette(e.target.value);
     }});
+    colorBySelect.addEventListener('change', () => applyPalette(paletteSelect.value));
     
-    const frames = Array.from(document.querySelectorAll('.frame'));
-    const totalSamples = {total_samples};
+    let frames = [];
+    const initialChart = {{ html: chart.innerHTML, total: totalSamples, depthMax: {depth_max} }};
     
     let zoomedFrame = null;
     let searchTerm = null;
     let contextTarget = null;
     let hiddenStacks = new Set();
     
-    // Store original positions
-    frames.forEach(f => {{
-        f.dataset.origStart = f.dataset.start;
-        f.dataset.origEnd = f.dataset.end;
-        f.dataset.origLeft = f.style.left;
-        f.dataset.origWidth = f.style.width;
-    }});
+    // Collect frames, store original positions and attach handlers
+    function initFrames() {{
+        frames = Array.from(chart.querySelectorAll('.frame'));
+        frames.forEach(f => {{
+            f.dataset.origStart = f.dataset.start;
+            f.dataset.origEnd = f.dataset.end;
+            f.dataset.origLeft = f.style.left;
+            f.dataset.origWidth = f.style.width;
+            bindFrame(f);
+        }});
+    }}
     
-    function formatNumber(n) {{
-        return n.toString().replace(/\B(?=(\d{{3}})+(?!\d))/g, ',');
+    // Folded path from the root to a frame, e.g. "app;main;parse"
+    function framePath(frame) {{
+        const depth = parseInt(frame.dataset.depth);
+        const start = parseInt(frame.dataset.origStart);
+        const names = [];
+        frames.forEach(f => {{
+            const fDepth = parseInt(f.dataset.depth);
+            if (fDepth > 0 && fDepth < depth && parseInt(f.dataset.origStart) <= start &&
+                start < parseInt(f.dataset.origEnd)) {{
+                names[fDepth - 1] = f.dataset.name;
+            }}
+        }});
+        names[depth - 1] = frame.dataset.name;
+        return names.join(';');
     }}
     
     function isFrameHidden(frame) {{
@@ -941,8 +4192,8 @@ footer {{
         applySearch();
     }}
     
-    // Tooltip handling
-    frames.forEach(frame => {{
+    // Tooltip, zoom and context menu handling
+    function bindFrame(frame) {{
         frame.addEventListener('mouseenter', (e) => {{
             const name = frame.dataset.name;
             const samples = parseInt(frame.dataset.samples);
@@ -966,10 +4217,16 @@ footer {{
             const selfSamples = samples - childSamples;
             const selfPct = (selfSamples / totalSamples * 100).toFixed(2);
             
-            tooltipName.textContent = name;
-            tooltipSamples.textContent = formatNumber(samples);
+            tooltipName.textContent = fullNames.get(name) || name;
+            tooltipSamples.textContent = formatValue(samples);
             tooltipPct.textContent = pct.toFixed(2) + '%';
-            tooltipSelf.textContent = formatNumber(selfSamples) + ' (' + selfPct + '%)';
+            tooltipSelf.textContent = formatValue(selfSamples) + ' (' + selfPct + '%)';
+            
+            // Folded levels are keyed by root-first paths, which inverted frames don't have
+            const levels = recursionLevels.size && !viewInverted ? recursionLevels.get(framePath(frame)) : undefined;
+            tooltipRecursion.textContent = levels ? levels + ' levels folded' : '';
+            tooltipRecursion.style.display = tooltipRecursionLabel.style.display = levels ? '' : 'none';
+            showFrameDetails(parseInt(frame.dataset.id), depth);
             tooltip.classList.add('visible');
         }});
         
@@ -994,6 +4251,7 @@ footer {{
         frame.addEventListener('contextmenu', (e) => {{
             e.preventDefault();
             contextTarget = frame;
+            updateContextMenu();
             
             contextMenu.style.left = e.clientX + 'px';
             contextMenu.style.top = e.clientY + 'px';
@@ -1009,8 +4267,10 @@ footer {{
                 }}
             }}, 0);
         }});
-    }});
+    }}
     
+    initFrames();
+    {dom_chart_js}
     function hideContextMenu() {{
         contextMenu.classList.remove('visible');
         contextTarget = null;
@@ -1036,6 +4296,7 @@ footer {{
     
     function zoomTo(frame) {{
         if (!frame || frame.classList.contains('hidden')) return;
+        refineChart(frame);
         
         const targetStart = parseInt(frame.dataset.start);
         const targetEnd = parseInt(frame.dataset.end);
@@ -1182,11 +4443,35 @@ footer {{
             searchInput.focus();
         }}
     }});
+"##,
+            depth_max = depth_max,
+            separator = OFF_WAKE_SEPARATOR,
+            dom_chart_js = DOM_CHART_JS
+        ),
+        Renderer::Canvas => write!(html, r##"    const frameTable = {frame_table};
+    const initialChart = {{ total: totalSamples, depthMax: {depth_max} }};
+    const initialIcicle = {icicle};
+    const offWakeSeparator = '{separator}';
+    {canvas_js}"##,
+            frame_table = frame_table_js(&frames),
+            depth_max = depth_max,
+            icicle = opts.icicle,
+            separator = OFF_WAKE_SEPARATOR,
+            canvas_js = CANVAS_JS
+        ),
+    }
+    .unwrap();
+
+    write!(html, r##"    {stack_render_js}{sandwich_js}{function_table_js}{source_view_js}{extra_js}
 }})();
 </script>
 </body>
 </html>"##,
-        total_samples = total_samples
+        stack_render_js = STACK_RENDER_JS,
+        sandwich_js = SANDWICH_JS,
+        function_table_js = FUNCTION_TABLE_JS,
+        source_view_js = SOURCE_VIEW_JS,
+        extra_js = extra_js
     ).unwrap();
 
     html
@@ -1194,28 +4479,63 @@ footer {{
 
 /// A flamegraph entry for batch generation.
 pub struct FlameGraphEntry {
-    pub stacks: HashMap<String, u64>,
+    pub profile: Profile,
     pub title: String,
 }
 
 /// Generate a batch flame graph HTML document with multiple graphs stacked vertically.
 ///
 /// # Arguments
-/// * `entries` - Vector of FlameGraphEntry structs (stacks + title pairs)
+/// * `entries` - Vector of FlameGraphEntry structs (profile + title pairs)
+/// * `opts` - Rendering options; the heat map option only applies to single
+///   graphs and is ignored here
 ///
 /// # Returns
 /// Complete HTML document as a string containing all flamegraphs
-pub fn generate_batch_flamegraph(entries: &[FlameGraphEntry]) -> String {
+pub fn generate_batch_flamegraph_with_options(entries: &[FlameGraphEntry], opts: &Options) -> String {
+    render_batch(entries, None, opts)
+}
+
+/// Generate a flame chart page: one graph per thread, with frames laid out by
+/// when they were sampled instead of merged by name, all on the same time axis.
+pub fn generate_flame_chart(table: &SampleTable, opts: &Options) -> String {
+    let timelines = thread_timelines(table);
+    if timelines.is_empty() {
+        return generate_error_html("No timestamped samples found (the perf script output needs its time field)");
+    }
+
+    let entries: Vec<FlameGraphEntry> = timelines
+        .iter()
+        .map(|(thread, timeline)| FlameGraphEntry {
+            profile: table.profile.with_stacks(timeline.stack_times()),
+            title: format!("{} {}/{}", thread.comm, thread.pid, thread.tid),
+        })
+        .collect();
+    let timelines: Vec<Timeline> = timelines.into_iter().map(|(_, timeline)| timeline).collect();
+    let opts = Options {
+        units: Units::WallNanos,
+        inverted: false,
+        ..opts.clone()
+    };
+    render_batch(&entries, Some(&timelines), &opts)
+}
+
+/// Batch page body; with `timelines` (one per entry, in the frame ids of the
+/// entry's profile), graphs are flame charts.
+fn render_batch(entries: &[FlameGraphEntry], timelines: Option<&[Timeline]>, opts: &Options) -> String {
     if entries.is_empty() {
         return generate_error_html("No flamegraph entries provided");
     }
+    let details = &FrameDetails::new(entries.iter().map(|entry| &entry.profile));
 
     let frame_height = 20;
+    let color_mode_options = color_mode_options_html(opts.color_by);
+    let theme_options = theme_options_html(opts.theme);
     let mut html = String::with_capacity(512 * 1024 * entries.len());
     
     // HTML header and shared styles
     write!(html, r##"<!DOCTYPE html>
-<html lang="en">
+<html lang="en" data-theme="{theme}">
 <head>
 <meta charset="UTF-8">
 <meta name="viewport" content="width=device-width, initial-scale=1.0">
@@ -1422,6 +4742,176 @@ header {{
     display: none;
 }}
 
+.chart.icicle,
+.chart.icicle .frame {{
+    transform: scaleY(-1);
+}}
+
+.sandwich-header {{
+    display: flex;
+    align-items: center;
+    gap: 12px;
+    margin-bottom: 8px;
+}}
+
+.sandwich-name {{
+    flex: 1;
+    font-family: 'SF Mono', 'Fira Code', Consolas, monospace;
+    font-size: 0.8125rem;
+    color: #f1f5f9;
+    overflow: hidden;
+    text-overflow: ellipsis;
+    white-space: nowrap;
+}}
+
+.sandwich-header .btn {{
+    padding: 6px 12px;
+    font-size: 0.75rem;
+}}
+
+.sandwich .chart-container + .chart-container {{
+    margin-top: 2px;
+}}
+
+.tabs {{
+    display: flex;
+    gap: 4px;
+    margin-bottom: 12px;
+    border-bottom: 1px solid rgba(255, 255, 255, 0.05);
+}}
+
+.tab {{
+    background: none;
+    border: none;
+    border-bottom: 2px solid transparent;
+    padding: 8px 14px;
+    font-size: 0.8125rem;
+    font-weight: 500;
+    color: #64748b;
+    cursor: pointer;
+}}
+
+.tab:hover {{
+    color: #e2e8f0;
+}}
+
+.tab.active {{
+    color: #e2e8f0;
+    border-bottom-color: rgb(99, 102, 241);
+}}
+
+.table-header {{
+    display: flex;
+    align-items: center;
+    gap: 16px;
+    margin-bottom: 8px;
+}}
+
+.table-filter {{
+    background: rgba(255, 255, 255, 0.05);
+    border: 1px solid rgba(255, 255, 255, 0.1);
+    border-radius: 8px;
+    padding: 8px 12px;
+    font-size: 0.8125rem;
+    color: #e2e8f0;
+    width: 320px;
+    outline: none;
+}}
+
+.table-filter:focus {{
+    border-color: rgba(99, 102, 241, 0.5);
+}}
+
+.table-scroll {{
+    max-height: 70vh;
+    overflow: auto;
+    background: rgba(0, 0, 0, 0.2);
+    border-radius: 12px;
+    border: 1px solid rgba(255, 255, 255, 0.05);
+}}
+
+.function-table {{
+    width: 100%;
+    border-collapse: collapse;
+    font-size: 0.8125rem;
+    font-variant-numeric: tabular-nums;
+}}
+
+.function-table th {{
+    position: sticky;
+    top: 0;
+    background: #1e293b;
+    color: #94a3b8;
+    font-weight: 500;
+    text-align: left;
+    padding: 8px 12px;
+    cursor: pointer;
+    user-select: none;
+    white-space: nowrap;
+}}
+
+.function-table th.sorted::after {{
+    content: ' \25B2';
+    font-size: 0.625rem;
+}}
+
+.function-table th.sorted.desc::after {{
+    content: ' \25BC';
+}}
+
+.function-table td {{
+    padding: 5px 12px;
+    border-top: 1px solid rgba(255, 255, 255, 0.04);
+    color: #cbd5e1;
+    white-space: nowrap;
+}}
+
+.function-table td.name {{
+    font-family: 'SF Mono', 'Fira Code', Consolas, monospace;
+    font-size: 0.75rem;
+    color: #f1f5f9;
+    max-width: 600px;
+    overflow: hidden;
+    text-overflow: ellipsis;
+}}
+
+.function-table .num {{
+    text-align: right;
+}}
+
+.function-table tbody tr {{
+    cursor: pointer;
+}}
+
+.function-table tbody tr:hover {{
+    background: rgba(255, 255, 255, 0.05);
+}}
+
+.source-table tbody tr {{
+    cursor: default;
+}}
+
+.source-table tr.current {{
+    box-shadow: inset 3px 0 0 rgb(99, 102, 241);
+}}
+
+.source-table td.code {{
+    font-family: 'SF Mono', 'Fira Code', Consolas, monospace;
+    font-size: 0.75rem;
+    white-space: pre;
+    width: 100%;
+}}
+
+.source-link {{
+    margin-left: 8px;
+    color: rgb(129, 140, 248);
+    cursor: pointer;
+}}
+
+.source-link:hover {{
+    text-decoration: underline;
+}}
+
 .tooltip {{
     position: fixed;
     background: #1e293b;
@@ -1464,6 +4954,7 @@ header {{
 .tooltip-stats dd {{
     color: #94a3b8;
     font-variant-numeric: tabular-nums;
+    overflow-wrap: anywhere;
 }}
 
 .context-menu {{
@@ -1548,6 +5039,26 @@ footer {{
     color: #e2e8f0;
 }}
 
+.color-legend {{
+    flex-wrap: wrap;
+    gap: 6px 16px;
+    margin-top: 8px;
+    font-size: 0.75rem;
+    color: #94a3b8;
+}}
+
+.color-legend-item {{
+    display: flex;
+    align-items: center;
+    gap: 6px;
+}}
+
+.color-swatch {{
+    width: 12px;
+    height: 12px;
+    border-radius: 3px;
+}}
+
 .keyboard-hints {{
     display: flex;
     gap: 16px;
@@ -1563,15 +5074,43 @@ footer {{
     font-size: 0.6875rem;
     margin-right: 4px;
 }}
+{theme_css}
 </style>
+{custom_css}<script>{theme_js}</script>
 </head>
 <body>
 <div class="container">
-"##, frame_height_css = frame_height - 2).unwrap();
+<script>
+const fullNames = new Map({full_names});
+const recursionLevels = new Map({recursion});
+const frameNames = {frame_names};
+const frameOrigins = {frame_origins};
+const colorCategories = {color_categories};
+</script>
+"##,
+        theme = opts.theme.id(),
+        theme_css = THEME_CSS,
+        custom_css = custom_css_html(opts),
+        theme_js = THEME_JS,
+        frame_height_css = frame_height - 2,
+        full_names = full_names_js(details),
+        recursion = recursion_js(details),
+        frame_names = frame_names_js(details),
+        frame_origins = frame_origins_js(details, opts),
+        color_categories = color_categories_js()
+    ).unwrap();
 
     // Generate each flamegraph section
     for (idx, entry) in entries.iter().enumerate() {
-        let (frames, total_samples, depth_max) = process_stacks(&entry.stacks);
+        let stacks = details.stacks(&entry.profile, idx);
+        let timeline = timelines.map(|t| t[idx].to_viewer(&details.ids[idx]));
+        let (frames, total_samples, depth_max) = if let Some(timeline) = &timeline {
+            layout_timeline(timeline, details)
+        } else if opts.inverted {
+            process_stacks(&invert_stacks(&stacks), details)
+        } else {
+            process_stacks(&stacks, details)
+        };
         
         if total_samples == 0 {
             writeln!(html, r#"<div class="flamegraph-section">
@@ -1582,6 +5121,8 @@ footer {{
 
         let chart_height = (depth_max + 1) * frame_height;
         let chart_id = format!("chart_{}", idx);
+        let suffix = format!("_{}", idx);
+        let (tabs_html, table_html) = function_table_html(&suffix);
         
         // Section header
         writeln!(html, r#"<div class="flamegraph-section" id="section_{}">
@@ -1604,57 +5145,56 @@ footer {{
     
     <div class="stats">
         <div class="stat">
-            <span class="stat-label">Total Samples</span>
-            <span class="stat-value">{}</span>
+            <span class="stat-label">{}</span>
+            <span class="stat-value" id="totalSamplesValue_{idx}">{}</span>
         </div>
         <div class="stat">
             <span class="stat-label">Max Depth</span>
-            <span class="stat-value">{}</span>
+            <span class="stat-value" id="depthMaxValue_{idx}">{}</span>
         </div>
         <div class="stat" id="matchedStat_{}" style="display:none">
             <span class="stat-label">Matched</span>
             <span class="stat-value" id="matchedValue_{}">0%</span>
         </div>
     </div>
-    
+    {tabs_html}
+    <div id="graphView_{idx}">
+    {}
     <div class="chart-container">
-        <div class="chart" id="{}" style="height: {}px;">"#,
+        <div class="{}" id="{}" style="height: {}px;">"#,
             idx,
             escape_html(&entry.title),
             idx, idx, idx,
-            format_samples(total_samples),
+            opts.units.total_label(),
+            format_value(total_samples, opts.units),
             depth_max,
             idx, idx,
-            chart_id, chart_height
+            sandwich_html(&suffix),
+            chart_class(opts), chart_id, chart_height
         ).unwrap();
 
         // Generate frames
-        for frame in &frames {
+        for frame in prune_frames(&frames, total_samples, opts.min_width, opts.aggregate_small) {
             let duration = frame.end - frame.start;
-            if duration == 0 {
-                continue;
-            }
-            
             let width_pct = (duration as f64 / total_samples as f64) * 100.0;
-            if width_pct < 0.08 {
-                continue;
-            }
             
             let left_pct = (frame.start as f64 / total_samples as f64) * 100.0;
             let bottom = frame.depth * frame_height;
             let pct = (duration as f64 / total_samples as f64) * 100.0;
             
-            let (r, g, b) = color_for_name(&frame.name);
+            let (r, g, b) = if frame.aggregate { AGGREGATE_RGB } else { frame_rgb(&frame, details, opts) };
             let display_name = if frame.name.is_empty() { "all" } else { &frame.name };
             
             writeln!(
                 html,
-                r#"            <div class="frame" style="left:{:.4}%;width:{:.4}%;bottom:{}px;background:rgb({},{},{});" data-name="{}" data-samples="{}" data-pct="{:.2}" data-depth="{}" data-start="{}" data-end="{}">{}</div>"#,
+                r#"            <div class="{}" style="left:{:.4}%;width:{:.4}%;bottom:{}px;background:rgb({},{},{});" data-name="{}" data-id="{}" data-samples="{}" data-pct="{:.2}" data-depth="{}" data-start="{}" data-end="{}">{}</div>"#,
+                if frame.aggregate { "frame aggregate" } else { "frame" },
                 left_pct,
                 width_pct,
                 bottom,
                 r, g, b,
                 escape_html(display_name),
+                frame.id.map_or(-1, |id| id as i64),
                 duration,
                 pct,
                 frame.depth,
@@ -1667,16 +5207,22 @@ footer {{
         // Close chart div and add tooltip, context menu, footer, and JS for this section
         writeln!(html, r#"        </div>
     </div>
+    <div class="color-legend" id="colorLegend_{idx}" style="display:none"></div>
+    </div>
+    {table_html}
     
     <div class="tooltip" id="tooltip_{}">
         <div class="tooltip-name" id="tooltipName_{}"></div>
         <dl class="tooltip-stats">
-            <dt>Samples</dt>
+            <dt>{}</dt>
             <dd id="tooltipSamples_{}"></dd>
             <dt>Percentage</dt>
             <dd id="tooltipPct_{}"></dd>
             <dt>Self</dt>
             <dd id="tooltipSelf_{}"></dd>
+            <dt id="tooltipRecursionLabel_{idx}" style="display:none">Recursion</dt>
+            <dd id="tooltipRecursion_{idx}" style="display:none"></dd>
+            {tooltip_details}
         </dl>
     </div>
     
@@ -1689,6 +5235,35 @@ footer {{
             </svg>
             <span>Hide this stack</span>
         </div>
+        <div class="context-menu-item" id="showSandwich_{idx}">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <path d="M12 2 2 7l10 5 10-5-10-5z"/>
+                <path d="m2 17 10 5 10-5"/>
+                <path d="m2 12 10 5 10-5"/>
+            </svg>
+            <span>Show callers and callees</span>
+        </div>
+        <div class="context-menu-item" id="copyName_{idx}">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <rect x="9" y="9" width="13" height="13" rx="2"/>
+                <path d="M5 15H4a2 2 0 0 1-2-2V4a2 2 0 0 1 2-2h9a2 2 0 0 1 2 2v1"/>
+            </svg>
+            <span>Copy full name</span>
+        </div>
+        <div class="context-menu-item" id="openEditor_{idx}" style="display:none">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <path d="M18 13v6a2 2 0 0 1-2 2H5a2 2 0 0 1-2-2V8a2 2 0 0 1 2-2h6"/>
+                <path d="M15 3h6v6M10 14L21 3"/>
+            </svg>
+            <span>Open in editor</span>
+        </div>
+        <div class="context-menu-item" id="showSource_{idx}" style="display:none">
+            <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
+                <path d="M14 2H6a2 2 0 0 0-2 2v16a2 2 0 0 0 2 2h12a2 2 0 0 0 2-2V8z"/>
+                <path d="M14 2v6h6M8 13h8M8 17h5"/>
+            </svg>
+            <span>Show source</span>
+        </div>
         <div class="context-menu-separator"></div>
         <div class="context-menu-item" id="resetHidden_{}">
             <svg viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
@@ -1703,11 +5278,25 @@ footer {{
         <div class="palette-selector">
             <label for="paletteSelect_{}">Color Palette:</label>
             <select id="paletteSelect_{}">
-                <option value="warm">Warm</option>
-                <option value="cool" selected>Cool</option>
-                <option value="neon">Neon</option>
-                <option value="pastel">Pastel</option>
-                <option value="mono">Monochrome</option>
+                {}
+            </select>
+        </div>
+        <div class="palette-selector">
+            <label for="colorBySelect_{idx}">Color By:</label>
+            <select id="colorBySelect_{idx}">
+                {color_mode_options}
+            </select>
+        </div>
+        <div class="palette-selector">
+            <label for="themeSelect_{idx}">Theme:</label>
+            <select id="themeSelect_{idx}" class="theme-select">
+                {theme_options}
+            </select>
+        </div>
+        <div class="palette-selector">
+            <label for="viewSelect_{idx}">View:</label>
+            <select id="viewSelect_{idx}">
+                {}
             </select>
         </div>
         <div class="keyboard-hints">
@@ -1716,7 +5305,9 @@ footer {{
         </div>
     </footer>
 </div>"#,
-            idx, idx, idx, idx, idx, idx, idx, idx, idx, idx
+            idx, idx, opts.units.count_label(), idx, idx, idx, idx, idx, idx, idx, idx,
+            palette_options_html(opts.palette), view_options_html(opts, timelines.is_some()),
+            tooltip_details = TOOLTIP_DETAILS_HTML
         ).unwrap();
 
         // Generate JavaScript for this chart (wrapped in IIFE for isolation)
@@ -1729,6 +5320,8 @@ footer {{
     const tooltipSamples = document.getElementById('tooltipSamples_' + idx);
     const tooltipPct = document.getElementById('tooltipPct_' + idx);
     const tooltipSelf = document.getElementById('tooltipSelf_' + idx);
+    const tooltipRecursion = document.getElementById('tooltipRecursion_' + idx);
+    const tooltipRecursionLabel = document.getElementById('tooltipRecursionLabel_' + idx);
     const searchInput = document.getElementById('search_' + idx);
     const resetBtn = document.getElementById('resetZoom_' + idx);
     const clearSearchBtn = document.getElementById('clearSearch_' + idx);
@@ -1738,13 +5331,41 @@ footer {{
     const hideStackBtn = document.getElementById('hideStack_' + idx);
     const resetHiddenBtn = document.getElementById('resetHidden_' + idx);
     const paletteSelect = document.getElementById('paletteSelect_' + idx);
+    const viewSelect = document.getElementById('viewSelect_' + idx);
+    const colorBySelect = document.getElementById('colorBySelect_' + idx);
+    const colorLegend = document.getElementById('colorLegend_' + idx);
+    const showSandwichBtn = document.getElementById('showSandwich_' + idx);
+    const copyNameBtn = document.getElementById('copyName_' + idx);
+    const openEditorBtn = document.getElementById('openEditor_' + idx);
+    const showSourceBtn = document.getElementById('showSource_' + idx);
+    const sandwich = document.getElementById('sandwich_' + idx);
+    const sandwichName = document.getElementById('sandwichName_' + idx);
+    const sandwichCallers = document.getElementById('sandwichCallers_' + idx);
+    const sandwichCallees = document.getElementById('sandwichCallees_' + idx);
+    const closeSandwichBtn = document.getElementById('closeSandwich_' + idx);
+    const graphTab = document.getElementById('graphTab_' + idx);
+    const tableTab = document.getElementById('tableTab_' + idx);
+    const graphView = document.getElementById('graphView_' + idx);
+    const tableView = document.getElementById('tableView_' + idx);
+    const tableFilter = document.getElementById('tableFilter_' + idx);
+    const tableCount = document.getElementById('tableCount_' + idx);
+    const functionTable = document.getElementById('functionTable_' + idx);
+    const functionRows = document.getElementById('functionRows_' + idx);
+    const sourceTab = document.getElementById('sourceTab_' + idx);
+    const sourceView = document.getElementById('sourceView_' + idx);
+    const sourceFile = document.getElementById('sourceFile_' + idx);
+    const sourceCount = document.getElementById('sourceCount_' + idx);
+    const sourceRows = document.getElementById('sourceRows_' + idx);
+    const totalSamplesValue = document.getElementById('totalSamplesValue_' + idx);
+    const depthMaxValue = document.getElementById('depthMaxValue_' + idx);
     
     const palettes = {{
         warm: (hash) => {{ const hue = (hash % 60) + 0; const sat = 0.70 + ((hash >> 8) % 20) / 100; const lit = 0.35 + ((hash >> 16) % 10) / 100; return {{ h: hue, s: sat, l: lit }}; }},
         cool: (hash) => {{ const hue = (hash % 120) + 180; const sat = 0.65 + ((hash >> 8) % 25) / 100; const lit = 0.38 + ((hash >> 16) % 12) / 100; return {{ h: hue, s: sat, l: lit }}; }},
         neon: (hash) => {{ const hue = hash % 360; const sat = 0.90 + ((hash >> 8) % 10) / 100; const lit = 0.45 + ((hash >> 16) % 10) / 100; return {{ h: hue, s: sat, l: lit }}; }},
         pastel: (hash) => {{ const hue = hash % 360; const sat = 0.40 + ((hash >> 8) % 20) / 100; const lit = 0.55 + ((hash >> 16) % 15) / 100; return {{ h: hue, s: sat, l: lit }}; }},
-        mono: (hash) => {{ const hue = 220; const sat = 0.15 + ((hash >> 8) % 10) / 100; const lit = 0.25 + ((hash >> 16) % 30) / 100; return {{ h: hue, s: sat, l: lit }}; }}
+        mono: (hash) => {{ const hue = 220; const sat = 0.15 + ((hash >> 8) % 10) / 100; const lit = 0.25 + ((hash >> 16) % 30) / 100; return {{ h: hue, s: sat, l: lit }}; }},
+        offcpu: (hash) => {{ const hue = (hash % 70) + 100; const sat = 0.45 + ((hash >> 8) % 25) / 100; const lit = 0.32 + ((hash >> 16) % 12) / 100; return {{ h: hue, s: sat, l: lit }}; }}
     }};
     
     function hslToRgb(h, s, l) {{
@@ -1763,38 +5384,69 @@ footer {{
     
     function hashString(str) {{ let hash = 0; for (let i = 0; i < str.length; i++) {{ hash = (hash * 31 + str.charCodeAt(i)) >>> 0; }} return hash; }}
     
-    function applyPalette(paletteName) {{
+    function applyPalette(paletteName, list = frames) {{
         const palette = palettes[paletteName];
         if (!palette) return;
-        frames.forEach(f => {{
+        list.forEach(f => {{
             const name = f.dataset.name;
             if (name === 'all') {{ f.style.background = 'rgb(99, 102, 241)'; return; }}
-            const hash = hashString(name);
-            const hsl = palette(hash);
-            const rgb = hslToRgb(hsl.h, hsl.s, hsl.l);
-            f.style.background = `rgb(${{rgb.r}}, ${{rgb.g}}, ${{rgb.b}})`;
+            if (name === '{separator}') {{ f.style.background = 'rgb(100, 116, 139)'; return; }}
+            if (f.classList.contains('aggregate')) {{ f.style.background = 'rgb(71, 85, 105)'; return; }}
+            f.style.background = frameColor(palette, parseInt(f.dataset.id));
         }});
     }}
     
     paletteSelect.addEventListener('change', (e) => {{ applyPalette(e.target.value); }});
+    colorBySelect.addEventListener('change', () => {{ applyPalette(paletteSelect.value); }});
     
-    const frames = Array.from(chart.querySelectorAll('.frame'));
-    const totalSamples = {};
-    
+    let frames = [];
+    let totalSamples = {};
+    const frameHeight = {frame_height};
+    const valueUnit = '{value_unit}';
+    const stackCounts = new Map({stack_counts});
+    const timeline = {timeline};
+    const initialInverted = {inverted};
+    const minWidth = {min_width};
+    const aggregateSmall = {aggregate_small};
+    const functionStats = {function_stats};
+    const sourceFiles = new Map({source_files});
+    const initialChart = {{ html: chart.innerHTML, total: totalSamples, depthMax: {depth_max} }};
+    {frame_color_js}{frame_details_js}
     let zoomedFrame = null;
     let searchTerm = null;
     let contextTarget = null;
     let hiddenStacks = new Set();
     
-    frames.forEach(f => {{
-        f.dataset.origStart = f.dataset.start;
-        f.dataset.origEnd = f.dataset.end;
-        f.dataset.origLeft = f.style.left;
-        f.dataset.origWidth = f.style.width;
-    }});
+    function initFrames() {{
+        frames = Array.from(chart.querySelectorAll('.frame'));
+        frames.forEach(f => {{
+            f.dataset.origStart = f.dataset.start;
+            f.dataset.origEnd = f.dataset.end;
+            f.dataset.origLeft = f.style.left;
+            f.dataset.origWidth = f.style.width;
+            bindFrame(f);
+        }});
+    }}
     
     function formatNumber(n) {{ return n.toString().replace(/\B(?=(\d{{3}})+(?!\d))/g, ','); }}
     
+    function formatValue(n) {{
+        if (valueUnit === 'ns') return (n / 1e6).toFixed(2).replace(/\B(?=(\d{{3}})+(?!\d))/g, ',') + ' ms';
+        return formatNumber(n);
+    }}
+    
+    function framePath(frame) {{
+        const depth = parseInt(frame.dataset.depth);
+        const start = parseInt(frame.dataset.origStart);
+        const names = [];
+        frames.forEach(f => {{
+            const fDepth = parseInt(f.dataset.depth);
+            if (fDepth > 0 && fDepth < depth && parseInt(f.dataset.origStart) <= start && start < parseInt(f.dataset.origEnd)) names[fDepth - 1] = f.dataset.name;
+        }});
+        names[depth - 1] = frame.dataset.name;
+        return names.join(';');
+    }}
+    
     function isFrameHidden(frame) {{
         const start = parseInt(frame.dataset.origStart);
         const end = parseInt(frame.dataset.origEnd);
@@ -1857,7 +5509,7 @@ footer {{
         applySearch();
     }}
     
-    frames.forEach(frame => {{
+    function bindFrame(frame) {{
         frame.addEventListener('mouseenter', (e) => {{
             const name = frame.dataset.name;
             const samples = parseInt(frame.dataset.samples);
@@ -1875,10 +5527,15 @@ footer {{
             }});
             const selfSamples = samples - childSamples;
             const selfPct = (selfSamples / totalSamples * 100).toFixed(2);
-            tooltipName.textContent = name;
-            tooltipSamples.textContent = formatNumber(samples);
+            tooltipName.textContent = fullNames.get(name) || name;
+            tooltipSamples.textContent = formatValue(samples);
             tooltipPct.textContent = pct.toFixed(2) + '%';
-            tooltipSelf.textContent = formatNumber(selfSamples) + ' (' + selfPct + '%)';
+            tooltipSelf.textContent = formatValue(selfSamples) + ' (' + selfPct + '%)';
+            // Folded levels are keyed by root-first paths, which inverted frames don't have
+            const levels = recursionLevels.size && !viewInverted ? recursionLevels.get(framePath(frame)) : undefined;
+            tooltipRecursion.textContent = levels ? levels + ' levels folded' : '';
+            tooltipRecursion.style.display = tooltipRecursionLabel.style.display = levels ? '' : 'none';
+            showFrameDetails(parseInt(frame.dataset.id), depth);
             tooltip.classList.add('visible');
         }});
         frame.addEventListener('mouseleave', () => {{ tooltip.classList.remove('visible'); }});
@@ -1895,12 +5552,15 @@ footer {{
         frame.addEventListener('contextmenu', (e) => {{
             e.preventDefault();
             contextTarget = frame;
+            updateContextMenu();
             contextMenu.style.left = e.clientX + 'px';
             contextMenu.style.top = e.clientY + 'px';
             contextMenu.classList.add('visible');
         }});
-    }});
+    }}
     
+    initFrames();
+    {dom_chart_js}{stack_render_js}{sandwich_js}{function_table_js}{source_view_js}
     function hideContextMenu() {{ contextMenu.classList.remove('visible'); contextTarget = null; }}
     
     hideStackBtn.addEventListener('click', () => {{
@@ -1918,6 +5578,7 @@ footer {{
     
     function zoomTo(frame) {{
         if (!frame || frame.classList.contains('hidden')) return;
+        refineChart(frame);
         const targetStart = parseInt(frame.dataset.start);
         const targetEnd = parseInt(frame.dataset.end);
         const targetDepth = parseInt(frame.dataset.depth);
@@ -1986,7 +5647,27 @@ footer {{
     resetBtn.addEventListener('click', resetAll);
     clearSearchBtn.addEventListener('click', clearSearch);
 }})();
-</script>"#, idx, total_samples).unwrap();
+</script>"#,
+            idx, total_samples,
+            frame_height = frame_height,
+            depth_max = depth_max,
+            value_unit = opts.units.id(),
+            separator = OFF_WAKE_SEPARATOR,
+            stack_counts = stack_counts_js(&stacks),
+            frame_color_js = FRAME_COLOR_JS,
+            frame_details_js = FRAME_DETAILS_JS,
+            timeline = timeline_js(timeline.as_ref()),
+            inverted = opts.inverted,
+            min_width = opts.min_width,
+            aggregate_small = opts.aggregate_small,
+            dom_chart_js = DOM_CHART_JS,
+            stack_render_js = STACK_RENDER_JS,
+            sandwich_js = SANDWICH_JS,
+            function_stats = function_table_js(&entry.profile, idx, details),
+            source_files = source_files_js(&entry.profile, opts),
+            function_table_js = FUNCTION_TABLE_JS,
+            source_view_js = SOURCE_VIEW_JS
+        ).unwrap();
     }
 
     // Close container and document
@@ -2043,6 +5724,7 @@ body {{
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stackcollapse::{Frame as ProfileFrame, SourceLine};
 
     #[test]
     fn test_basic_generation() {
@@ -2051,7 +5733,7 @@ mod tests {
         stacks.insert("main;foo;baz".to_string(), 50);
         stacks.insert("main;qux".to_string(), 25);
 
-        let html = generate_flamegraph(&stacks, "Test Graph", None);
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test Graph", None, &Options::default());
         
         assert!(html.contains("<!DOCTYPE html>"));
         assert!(html.contains("Test Graph"));
@@ -2065,7 +5747,7 @@ mod tests {
         let mut stacks = HashMap::new();
         stacks.insert("a;b".to_string(), 10);
 
-        let html = generate_flamegraph(&stacks, "Title", Some("My Subtitle"));
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Title", Some("My Subtitle"), &Options::default());
         
         assert!(html.contains("My Subtitle"));
     }
@@ -2073,7 +5755,7 @@ mod tests {
     #[test]
     fn test_empty_stacks() {
         let stacks = HashMap::new();
-        let html = generate_flamegraph(&stacks, "Empty", None);
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Empty", None, &Options::default());
         
         assert!(html.contains("Error"));
         assert!(html.contains("No valid stack data"));
@@ -2084,21 +5766,529 @@ mod tests {
         let mut stacks = HashMap::new();
         stacks.insert("main;<script>alert('xss')</script>".to_string(), 10);
 
-        let html = generate_flamegraph(&stacks, "Test <XSS>", None);
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test <XSS>", None, &Options::default());
         
         assert!(!html.contains("<script>alert"));
         assert!(html.contains("&lt;script&gt;"));
     }
 
+    #[test]
+    fn test_heatmap_embedding() {
+        let input = r#"
+app 10 [000] 100.000000: 1 cpu-clock:
+        1111 work (/bin/app)
+
+app 10 [000] 101.250000: 1 cpu-clock:
+        2222 </script> (/bin/app)
+
+"#;
+        let samples = crate::stackcollapse::collapse_perf_samples(input, &Default::default());
+        let opts = Options { heatmap: Some(&samples), ..Default::default() };
+        let html = generate_flamegraph_with_options(&samples.profile, "Heat", None, &opts);
+
+        assert!(html.contains(r#"<canvas id="heatmap">"#));
+        // Frame ids follow the names: </script>, app, work
+        assert!(html.contains(r#"stacks: ["1;2","1;0"]"#));
+        assert!(html.contains(r#"const frameNames = ["\u003c/script>","app","work"];"#));
+        assert!(html.contains("samples: [0,0,1,1250,1,1]"));
+
+        let plain = generate_flamegraph_with_options(&samples.profile, "Plain", None, &Options::default());
+        assert!(!plain.contains("heatmapData"));
+    }
+
+    #[test]
+    fn test_lanes_embedding() {
+        let input = r#"
+app 10/10 [000] 1.000000: 1 cpu-clock:
+        1111 load (/bin/app)
+
+worker 10/11 [001] 1.005000: 1 cpu-clock:
+        3333 poll (/bin/app)
+
+app 10/10 [000] 1.010000: 1 cpu-clock:
+        1111 load (/bin/app)
+
+other 7/7 [002] 1.020000: 1 cpu-clock:
+        4444 idle (/bin/other)
+
+"#;
+        let samples = crate::stackcollapse::collapse_perf_samples(input, &Default::default());
+        let opts = Options { lanes: Some(&samples), ..Default::default() };
+        let html = generate_flamegraph_with_options(&samples.profile, "Lanes", None, &opts);
+
+        assert!(html.contains(r#"<canvas id="lanes">"#));
+        // Processes in pid order, each thread labelled with its own comm
+        assert!(html.contains(r#"lanes: [["other 7","other 7"],["app 10","app 10"],["app 10","worker 11"]]"#));
+        // Frame ids follow the names: app, idle, load, other, poll, worker
+        assert!(html.contains(r#"stacks: ["0;2","5;4","3;1"]"#));
+        assert!(html.contains("samples: [0,1,0,1,5000,2,1,1,10000,1,0,1,20000,0,2,1]"));
+
+        let plain = generate_flamegraph_with_options(&samples.profile, "Plain", None, &Options::default());
+        assert!(!plain.contains("laneData"));
+    }
+
+    #[test]
+    fn test_flame_chart() {
+        let input = r#"
+app 10/10 [000] 1.000000: 1 cpu-clock:
+        1111 load (/bin/app)
+        1112 main (/bin/app)
+
+worker 10/11 [001] 1.005000: 1 cpu-clock:
+        3333 poll (/bin/app)
+
+app 10/10 [000] 1.010000: 1 cpu-clock:
+        1111 load (/bin/app)
+        1112 main (/bin/app)
+
+app 10/10 [000] 1.020000: 1 cpu-clock:
+        2222 work (/bin/app)
+        1112 main (/bin/app)
+
+app 10/10 [000] 1.100000: 1 cpu-clock:
+        1111 load (/bin/app)
+        1112 main (/bin/app)
+
+"#;
+        let samples = crate::stackcollapse::collapse_perf_samples(input, &Default::default());
+        let timelines = thread_timelines(&samples);
+        assert_eq!(timelines.len(), 2);
+
+        // 10ms interval; the gap before the last sample leaves the thread idle
+        let (thread, app) = &timelines[0];
+        assert_eq!((thread.comm.as_str(), thread.tid.as_str()), ("app", "10"));
+        let folded: Vec<String> = app.stacks.iter().map(|stack| samples.profile.folded(stack)).collect();
+        assert_eq!(folded, ["app;main;load", "app;main;work"]);
+        assert_eq!(app.runs, [(0, 20_000_000, 0), (20_000_000, 30_000_000, 1), (100_000_000, 110_000_000, 0)]);
+        assert_eq!(timelines[1].1.runs, [(5_000_000, 15_000_000, 0)]);
+
+        // Frames continue across touching runs, but not across gaps, and aren't sorted by name
+        let details = FrameDetails::new([&samples.profile]);
+        let (frames, total, depth_max) = layout_timeline(&app.to_viewer(&details.ids[0]), &details);
+        let mut layout: Vec<String> = frames.iter().map(|f| format!("{}@{}:{}-{}", f.name, f.depth, f.start / 1_000_000, f.end / 1_000_000)).collect();
+        layout.sort();
+        assert_eq!(layout, [
+            "@0:0-110", "app@1:0-30", "app@1:100-110", "load@3:0-20", "load@3:100-110",
+            "main@2:0-30", "main@2:100-110", "work@3:20-30",
+        ]);
+        assert_eq!((total, depth_max), (110_000_000, 3));
+        assert_eq!(samples.profile.with_stacks(app.stack_times()).fold().get("app;main;load"), Some(&30_000_000));
+
+        let html = generate_flame_chart(&samples, &Options::default());
+        assert!(html.contains("<h2>app 10/10</h2>") && html.contains("<h2>worker 10/11</h2>"));
+        // Stacks are frame ids: app, load, main, poll, work, worker in name order
+        assert!(html.contains(r#"const frameNames = ["app","load","main","poll","work","worker"];"#));
+        assert!(html.contains(r#"const timeline = { stacks: ["5;3"], runs: [5000000,15000000,0], total: 110000000 };"#));
+        assert!(html.contains(r#"<span class="stat-label">Duration</span>"#));
+        assert!(!html.contains(r#"<option value="inverted""#));
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(json_string("x</y"), r#""x\u003c/y""#);
+    }
+
+    #[test]
+    fn test_frame_info() {
+        let mut stacks = HashMap::new();
+        stacks.insert("main;Foo::bar".to_string(), 10);
+        let mut profile = Profile::from_folded(&stacks);
+        let info = &mut profile.info;
+        info.full_names.insert("Foo::bar".to_string(), "Foo::bar(std::vector<int>&)".to_string());
+        info.recursion.insert("main;Foo::bar".to_string(), 3);
+
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &Options::default());
+        assert!(html.contains(r#"const fullNames = new Map([["Foo::bar","Foo::bar(std::vector\u003cint>&)"]]);"#));
+        assert!(html.contains(r#"const recursionLevels = new Map([["main;Foo::bar",3]]);"#));
+
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &Options::default());
+        assert!(html.contains("const fullNames = new Map([]);"));
+    }
+
+    #[test]
+    fn test_inverted_and_icicle() {
+        let mut stacks = HashMap::new();
+        stacks.insert("main;parse;alloc".to_string(), 10);
+        stacks.insert("main;render;alloc".to_string(), 5);
+
+        let viewer: ViewerStacks = [(vec![1, 2, 0], 10), (vec![1, 3, 0], 5)].into_iter().collect();
+        let inverted = invert_stacks(&viewer);
+        assert_eq!(inverted.get(&vec![0, 2, 1]), Some(&10));
+        assert_eq!(inverted.get(&vec![0, 3, 1]), Some(&5));
+
+        let opts = Options {
+            inverted: true,
+            icicle: true,
+            ..Default::default()
+        };
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &opts);
+        // Leaf at the root, spanning both callers
+        assert!(html.contains(r#"data-name="alloc" data-id="0" data-samples="15" data-pct="100.00" data-depth="1""#));
+        assert!(html.contains(r#"<div class="chart icicle" id="chart">"#));
+        assert!(html.contains(r#"<option value="inverted-icicle" selected>"#));
+        // The viewer re-renders from the original orientation
+        // The viewer re-renders from the original orientation, by frame id (alloc, main,
+        // parse, render in name order)
+        assert!(html.contains(r#"const stackCounts = new Map([["1;2;0",10],["1;3;0",5]]);"#));
+        assert!(html.contains("const initialInverted = true;"));
+
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &Options::default());
+        assert!(html.contains(r#"<div class="chart" id="chart">"#));
+        assert!(html.contains(r#"data-name="main" data-id="1" data-samples="15" data-pct="100.00" data-depth="1""#));
+    }
+
+    #[test]
+    fn test_prune_frames() {
+        let mut stacks = HashMap::new();
+        stacks.insert("main;big".to_string(), 80);
+        stacks.insert("main;mid".to_string(), 4);
+        stacks.insert("main;mid;a".to_string(), 1);
+        stacks.insert("main;mid;b".to_string(), 9);
+        stacks.insert("main;x".to_string(), 1);
+        stacks.insert("main;x;deep".to_string(), 1);
+        stacks.insert("main;y".to_string(), 4);
+        let profile = Profile::from_folded(&stacks);
+        let details = FrameDetails::new([&profile]);
+        let (frames, total, _) = process_stacks(&details.stacks(&profile, 0), &details);
+        let layout = |frames: Vec<Frame>| -> Vec<String> {
+            frames.iter().map(|f| format!("{}@{}:{}-{}", f.name, f.depth, f.start, f.end)).collect()
+        };
+
+        // Narrow frames and everything above them go; the rest stay where they were
+        assert_eq!(layout(prune_frames(&frames, total, 3.0, false)), [
+            "@0:0-100", "main@1:0-100", "big@2:0-80", "mid@2:80-94", "y@2:96-100", "b@3:85-94",
+        ]);
+        assert_eq!(prune_frames(&frames, total, 0.0, false).len(), frames.len());
+
+        // Aggregated frames follow their kept siblings, which close up after the self time
+        let pruned = prune_frames(&frames, total, 3.0, true);
+        assert_eq!(layout(pruned.clone()), [
+            "@0:0-100", "main@1:0-100", "big@2:0-80", "mid@2:80-94", "y@2:94-98",
+            "[1 small frame]@2:98-100", "b@3:84-93", "[1 small frame]@3:93-94",
+        ]);
+        assert!(pruned[5].aggregate && !pruned[4].aggregate);
+
+        let opts = Options { min_width: 3.0, aggregate_small: true, ..Default::default() };
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &opts);
+        assert!(html.contains(r#"<div class="frame aggregate" style="left:98.0000%;width:2.0000%;bottom:40px;background:rgb(71,85,105);" data-name="[1 small frame]""#));
+        assert!(html.contains("const minWidth = 3;\n    const aggregateSmall = true;"));
+        assert!(generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &Options::default()).contains("const minWidth = 0.08;"));
+    }
+
+    #[test]
+    fn test_canvas_renderer() {
+        let mut stacks = HashMap::new();
+        stacks.insert("main;parse".to_string(), 3);
+        stacks.insert("main;idle".to_string(), 1);
+        stacks.insert("tiny".to_string(), 0);
+
+        let opts = Options {
+            renderer: Renderer::Canvas,
+            icicle: true,
+            ..Default::default()
+        };
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &opts);
+        // Frames ordered by depth then start, with zero-width frames left out
+        assert!(html.contains(
+            r#"const frameTable = { names: ["all","main","idle","parse"], ids: [-1,1,0,2], frames: [0,0,4,0,1,0,4,1,2,0,1,2,3,1,4,2] };"#
+        ));
+        assert!(html.contains(r#"<canvas id="chartCanvas"></canvas>"#));
+        assert!(!html.contains(r#"<div class="frame" style="left:0.0000%"#));
+        // The canvas flips itself, so the chart keeps its plain class
+        assert!(html.contains(r#"<div class="chart" id="chart">"#));
+        assert!(html.contains("const initialIcicle = true;"));
+
+        assert_eq!("canvas".parse::<Renderer>(), Ok(Renderer::Canvas));
+        assert!("svg".parse::<Renderer>().is_err());
+    }
+
+    #[test]
+    fn test_themes() {
+        let mut stacks = HashMap::new();
+        stacks.insert("main;work".to_string(), 1);
+
+        let plain = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &Options::default());
+        assert!(plain.contains(r#"<html lang="en" data-theme="dark">"#));
+        assert!(plain.contains(r#"<option value="dark" selected>Dark</option>"#));
+        assert_eq!(plain.matches("<style>").count(), 1);
+
+        let opts = Options {
+            theme: Theme::HighContrast,
+            css: Some(".frame { border-radius: 0; }"),
+            ..Default::default()
+        };
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &opts);
+        assert!(html.contains(r#"<html lang="en" data-theme="high-contrast">"#));
+        assert!(html.contains(r#"<option value="high-contrast" selected>High contrast</option>"#));
+        // Custom styles come after the built-in ones so they win ties
+        assert!(html.contains("</style>\n<style>\n.frame { border-radius: 0; }\n</style>"));
+
+        let entries = vec![
+            FlameGraphEntry { title: "a".to_string(), profile: Profile::from_folded(&stacks) },
+            FlameGraphEntry { title: "b".to_string(), profile: Profile::from_folded(&stacks) },
+        ];
+        let batch = generate_batch_flamegraph_with_options(&entries, &opts);
+        assert!(batch.contains(r#"<html lang="en" data-theme="high-contrast">"#));
+        assert!(batch.contains(r#"<select id="themeSelect_1" class="theme-select">"#));
+        assert!(batch.contains(".frame { border-radius: 0; }"));
+
+        assert_eq!("light".parse::<Theme>(), Ok(Theme::Light));
+        assert!("solarized".parse::<Theme>().is_err());
+    }
+
+    #[test]
+    fn test_color_modes() {
+        let mut profile = Profile::default();
+        let mut frame = |name: &str, module: &str, kind| {
+            let frame = ProfileFrame {
+                module: Some(module.to_string()),
+                kind: Some(kind),
+                language: Some(Language::Native),
+                ..ProfileFrame::named(name)
+            };
+            profile.intern(frame, None)
+        };
+        let main = frame("main", "/bin/app", FrameKind::User);
+        let malloc = frame("malloc", "/usr/lib/libc.so.6", FrameKind::User);
+        let schedule = frame("schedule", "[kernel.kallsyms]", FrameKind::Kernel);
+        let app = profile.intern(ProfileFrame::named("app"), None);
+        profile.stacks.insert(vec![app, main, malloc], 2);
+        profile.stacks.insert(vec![app, main, schedule], 1);
+
+        let opts = Options { color_by: ColorMode::Kind, ..Default::default() };
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &opts);
+        assert!(html.contains(r#"background:rgb(234,88,12);" data-name="schedule""#));
+        assert!(html.contains(r#"background:rgb(59,130,246);" data-name="malloc""#));
+        // Process names have no origin
+        assert!(html.contains(r#"background:rgb(100,116,139);" data-name="app""#));
+        // By frame id: app, main, malloc, schedule in name order
+        assert!(html.contains(r#"const frameOrigins = [null,["app","user","native","/bin/app","","",""],["libc.so.6","user","native","/usr/lib/libc.so.6","","",""],"#));
+        assert!(html.contains(r#"kernel: ["Kernel", "rgb(234, 88, 12)"]"#));
+        assert!(html.contains(r#"<option value="kind" selected>Kind</option>"#));
+        assert!(html.contains(r#"id="colorLegend""#));
+
+        let details = FrameDetails::new([&profile]);
+        let (frames, _, _) = process_stacks(&details.stacks(&profile, 0), &details);
+        let rgb = |name: &str, opts: &Options| frame_rgb(frames.iter().find(|f| f.name == name).unwrap(), &details, opts);
+        let by_module = Options { color_by: ColorMode::Module, ..opts.clone() };
+        assert_eq!(rgb("malloc", &by_module), color_for_name("libc.so.6", Palette::Cool));
+        assert_eq!(rgb("", &by_module), color_for_name("", Palette::Cool));
+        assert_eq!(rgb("main", &Options::default()), color_for_name("main", Palette::Cool));
+
+        assert_eq!("language".parse::<ColorMode>(), Ok(ColorMode::Language));
+        assert!("dso".parse::<ColorMode>().is_err());
+    }
+
+    #[test]
+    fn test_tooltip_details() {
+        let mut profile = Profile::default();
+        let parse = ProfileFrame {
+            module: Some("/build/app".to_string()),
+            kind: Some(FrameKind::Inlined),
+            language: Some(Language::Rust),
+            source: Some(SourceLine { file: "/build/src/parse.rs".to_string(), line: 42 }),
+            ..ProfileFrame::named("app::parse")
+        };
+        let parse = profile.intern(parse, Some(0x4010));
+        let main = profile.intern(ProfileFrame::named("main"), None);
+        profile.stacks.insert(vec![main, parse], 3);
+
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &Options::default());
+        // Frames without an origin (main) get only the depth row
+        assert!(html.contains(r#"const frameOrigins = [["app","inlined","rust","/build/app","/build/src/parse.rs:42","0x4010","vscode://file/build/src/parse.rs:42"],null];"#));
+        assert!(html.contains(r#"<dd class="detail-source" style="display:none"></dd>"#));
+        assert!(html.contains(r#"id="copyName""#));
+        assert!(html.contains("function showFrameDetails(id, depth)"));
+
+        let entries = vec![FlameGraphEntry { profile, title: "One".to_string() }];
+        let html = generate_batch_flamegraph_with_options(&entries, &Options::default());
+        assert!(html.contains(r#"id="copyName_0""#));
+        assert!(html.contains(r#"<dd class="detail-address" style="display:none"></dd>"#));
+    }
+
+    #[test]
+    fn test_editor_links() {
+        let source = SourceLine { file: "/build/app/src/my file.rs".to_string(), line: 7 };
+        let roots = ["/build/app=/home/me/app".parse().unwrap()];
+        let opts = Options { source_roots: &roots, ..Default::default() };
+        assert_eq!(Editor::VsCode.url(&source, &opts), "vscode://file/home/me/app/src/my%20file.rs:7");
+        assert_eq!(Editor::Idea.url(&source, &opts), "idea://open?file=/home/me/app/src/my%20file.rs&line=7");
+
+        let roots = ["/build/app=".parse().unwrap()];
+        let opts = Options { source_roots: &roots, source_rev: "v1.2", ..Default::default() };
+        let web: Editor = "https://git.example.com/blob/{rev}/{path}#L{line}".parse().unwrap();
+        assert_eq!(web.url(&source, &opts), "https://git.example.com/blob/v1.2/src/my%20file.rs#L7");
+
+        assert_eq!("idea".parse::<Editor>(), Ok(Editor::Idea));
+        assert!("emacs".parse::<Editor>().is_err());
+
+        let mut profile = Profile::default();
+        let main = ProfileFrame {
+            kind: Some(FrameKind::User),
+            source: Some(source),
+            ..ProfileFrame::named("main")
+        };
+        let main = profile.intern(main, None);
+        profile.stacks.insert(vec![main], 1);
+        let opts = Options { editor: web, ..opts };
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &opts);
+        assert!(html.contains(r#""https://git.example.com/blob/v1.2/src/my%20file.rs#L7"]]"#));
+        assert!(html.contains(r#"<div class="context-menu-item" id="openEditor" style="display:none">"#));
+        let entries = vec![FlameGraphEntry { profile, title: "One".to_string() }];
+        assert!(generate_batch_flamegraph_with_options(&entries, &Options::default()).contains(r#"id="openEditor_0""#));
+    }
+
+    #[test]
+    fn test_source_view() {
+        let mut profile = Profile::default();
+        let mut frame = |name: &str, line| {
+            let frame = ProfileFrame {
+                kind: Some(FrameKind::User),
+                source: Some(SourceLine { file: "/src/lib.rs".to_string(), line }),
+                ..ProfileFrame::named(name)
+            };
+            profile.intern(frame, None)
+        };
+        let main = frame("main", 1);
+        let work = frame("work", 3);
+        let other = frame("other", 2);
+        profile.stacks.insert(vec![main, work], 5);
+        profile.stacks.insert(vec![main, other], 1);
+        profile.stacks.insert(vec![main], 2);
+
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &Options::default());
+        assert!(html.contains("const sourceFiles = new Map([]);"));
+        assert!(html.contains(r#"<button class="tab" id="sourceTab" style="display:none">Source</button>"#));
+
+        let sources = HashMap::from([("/src/lib.rs".to_string(), "fn main() {\n    other();\n    work();\n}\n".to_string())]);
+        let opts = Options { sources: Some(&sources), ..Default::default() };
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &opts);
+        // Self counts only: main's own two samples, not its callees'
+        assert!(html.contains(r#"const sourceFiles = new Map([["/src/lib.rs",["fn main() {\n    other();\n    work();\n}\n",[1,2,2,1,3,5]]]]);"#));
+        assert!(html.contains(r#"id="showSource""#));
+
+        let entries = vec![FlameGraphEntry { profile, title: "One".to_string() }];
+        let html = generate_batch_flamegraph_with_options(&entries, &opts);
+        assert!(html.contains(r#"[1,2,2,1,3,5]"#));
+        assert!(html.contains(r#"id="sourceRows_0""#));
+    }
+
+    #[test]
+    fn test_sandwich_markup() {
+        let mut stacks = HashMap::new();
+        stacks.insert("main;malloc".to_string(), 10);
+
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Test", None, &Options::default());
+        assert!(html.contains(r#"id="showSandwich""#));
+        assert!(html.contains(r#"<div class="chart icicle" id="sandwichCallees"></div>"#));
+
+        let entries = vec![FlameGraphEntry { profile: Profile::from_folded(&stacks), title: "One".to_string() }];
+        let html = generate_batch_flamegraph_with_options(&entries, &Options::default());
+        assert!(html.contains(r#"id="showSandwich_0""#));
+        assert!(html.contains(r#"<div class="chart" id="sandwichCallers_0"></div>"#));
+    }
+
+    #[test]
+    fn test_function_table() {
+        let mut profile = Profile::default();
+        let app = profile.intern(ProfileFrame::named("app"), None);
+        let main = profile.intern(ProfileFrame::named("main"), None);
+        let malloc = ProfileFrame { module: Some("/usr/lib/libc.so.6".to_string()), ..ProfileFrame::named("malloc") };
+        let malloc = profile.intern(malloc, None);
+        profile.stacks.insert(vec![app, main, malloc], 3);
+        profile.stacks.insert(vec![app, main], 1);
+
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &Options::default());
+        assert!(html.contains(
+            r#"const functionStats = [["malloc",3,3,"libc.so.6",1,2],["main",1,4,"",1,1],["app",0,4,"",0,0]];"#
+        ));
+        assert!(html.contains(r#"<tbody id="functionRows"></tbody>"#));
+    }
+
+    #[test]
+    fn test_same_name_frames() {
+        let mut profile = Profile::default();
+        let app = profile.intern(ProfileFrame::named("app"), None);
+        let mut helper = |module: &str, line| {
+            let frame = ProfileFrame {
+                module: Some(module.to_string()),
+                kind: Some(FrameKind::User),
+                source: Some(SourceLine { file: "/src/helper.c".to_string(), line }),
+                ..ProfileFrame::named("helper")
+            };
+            profile.intern(frame, None)
+        };
+        let lib = helper("/usr/lib/libfoo.so", 10);
+        let own = helper("/bin/app", 20);
+        profile.stacks.insert(vec![app, lib], 3);
+        profile.stacks.insert(vec![app, own], 1);
+
+        // Each helper keeps its own frame, origin and function table row
+        let html = generate_flamegraph_with_options(&profile, "Test", None, &Options::default());
+        assert!(html.contains(r#"data-name="helper" data-id="1" data-samples="1""#));
+        assert!(html.contains(r#"data-name="helper" data-id="2" data-samples="3""#));
+        assert!(html.contains(r#"const frameNames = ["app","helper","helper"];"#));
+        assert!(html.contains(r#"["app","user","","/bin/app","/src/helper.c:20","",""#));
+        assert!(html.contains(r#"["libfoo.so","user","","/usr/lib/libfoo.so","/src/helper.c:10","",""#));
+        assert!(html.contains(r#"const functionStats = [["helper",3,3,"libfoo.so",1,2],["helper",1,1,"app",1,1],"#));
+        assert!(html.contains(r#"const stackCounts = new Map([["0;1",1],["0;2",3]]);"#));
+
+        // Batch graphs share the ids of equal frames
+        let entries = vec![
+            FlameGraphEntry { profile: profile.clone(), title: "One".to_string() },
+            FlameGraphEntry { profile, title: "Two".to_string() },
+        ];
+        let html = generate_batch_flamegraph_with_options(&entries, &Options::default());
+        assert!(html.contains(r#"const frameNames = ["app","helper","helper"];"#));
+    }
+
+    #[test]
+    fn test_blocked_units_and_palette() {
+        let mut stacks = HashMap::new();
+        stacks.insert("app;read".to_string(), 1_234_567_890);
+
+        let opts = Options {
+            palette: Palette::OffCpu,
+            units: Units::BlockedNanos,
+            ..Default::default()
+        };
+        let html = generate_flamegraph_with_options(&Profile::from_folded(&stacks), "Off-CPU", None, &opts);
+
+        assert!(html.contains("Total Blocked"));
+        assert!(html.contains("1,234.57 ms"));
+        assert!(html.contains(r#"<option value="offcpu" selected>Off-CPU</option>"#));
+        assert!(html.contains("const valueUnit = 'ns';"));
+
+        let batch = generate_batch_flamegraph_with_options(
+            &[FlameGraphEntry { profile: Profile::from_folded(&stacks), title: "a".to_string() }],
+            &opts,
+        );
+        assert!(batch.contains("<dt>Blocked</dt>"));
+    }
+
     #[test]
     fn test_color_generation() {
-        let (r1, g1, b1) = color_for_name("function_a");
-        let (r2, g2, b2) = color_for_name("function_a");
+        let (r1, g1, b1) = color_for_name("function_a", Palette::Cool);
+        let (r2, g2, b2) = color_for_name("function_a", Palette::Cool);
         
         // Same name should produce same color
         assert_eq!((r1, g1, b1), (r2, g2, b2));
     }
 
+    #[test]
+    fn test_off_wake_separator_color() {
+        assert_eq!(color_for_name(OFF_WAKE_SEPARATOR, Palette::OffCpu), (100, 116, 139));
+        assert_ne!(color_for_name("read", Palette::OffCpu), (100, 116, 139));
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(1500, Units::Samples), "1,500");
+        assert_eq!(format_value(2_500_000, Units::BlockedNanos), "2.50 ms");
+        assert_eq!(format_value(4_999, Units::BlockedNanos), "0.00 ms");
+    }
+
     #[test]
     fn test_format_samples() {
         assert_eq!(format_samples(1), "1");
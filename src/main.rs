@@ -1,84 +1,448 @@
 mod stackcollapse;
 mod flamegraph;
 mod perfutils;
+mod rules;
 mod cli;
 
-use std::{fs, path::Path};
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, path::Path};
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Chart, Cli, Commands, SampleRange, SplitBy, StackShaping};
+use stackcollapse::SourceRoot;
 
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Gen { output, files } => {
-            gen_flamegraphs(&files, &output);
+        Commands::Gen {
+            output,
+            range,
+            shaping,
+            rust_elide_generics,
+            template_depth,
+            srcline,
+            jit_source,
+            jit_tiers,
+            hide_interpreter_frames,
+            fold_jvm_internals,
+            chart,
+            inverted,
+            icicle,
+            renderer,
+            min_width,
+            aggregate_small,
+            heatmap,
+            lanes,
+            color_by,
+            theme,
+            css,
+            editor_url,
+            source_root,
+            source_rev,
+            annotate_source,
+            split_by,
+            files,
+        } => {
+            let mut opts = stackcollapse::Options {
+                rust_elide_generics,
+                template_depth,
+                srcline_in_input: srcline,
+                jit_source,
+                jit_tiers,
+                hide_interpreter_frames,
+                fold_jvm_internals,
+                ..Default::default()
+            };
+            shape_stacks(shaping, &mut opts);
+            let off_cpu = opts.off_cpu;
+
+            let css = css.map(|path| match fs::read_to_string(&path) {
+                Ok(css) => css,
+                Err(e) => {
+                    eprintln!("Failed to read --css file {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            });
+
+            let mut render_opts = flamegraph::Options {
+                inverted,
+                icicle,
+                renderer,
+                min_width,
+                aggregate_small,
+                color_by,
+                theme,
+                css: css.as_deref(),
+                editor: editor_url,
+                source_roots: &source_root,
+                source_rev: &source_rev,
+                ..Default::default()
+            };
+            if off_cpu {
+                render_opts.palette = flamegraph::Palette::OffCpu;
+                render_opts.units = flamegraph::Units::BlockedNanos;
+            }
+
+            select_samples(range, &mut opts);
+
+            if chart == Chart::Time {
+                let conflict = [
+                    (off_cpu, "--off-cpu/--off-wake"),
+                    (inverted, "--inverted"),
+                    (heatmap, "--heatmap"),
+                    (lanes, "--lanes"),
+                    (annotate_source, "--annotate-source"),
+                    (split_by.is_some(), "--split-by"),
+                ]
+                .into_iter()
+                .find_map(|(set, flag)| set.then_some(flag));
+                if let Some(flag) = conflict {
+                    eprintln!("--chart time can't be combined with {}", flag);
+                    std::process::exit(1);
+                }
+                if files.len() > 1 {
+                    eprintln!("--chart time takes a single input file");
+                    std::process::exit(1);
+                }
+                if renderer == flamegraph::Renderer::Canvas {
+                    eprintln!("--renderer canvas only applies to a single flamegraph; ignoring it");
+                }
+                gen_flame_chart(&files[0], &output, &opts, &render_opts);
+                return;
+            }
+
+            if heatmap && (files.len() > 1 || split_by.is_some()) {
+                eprintln!("--heatmap only applies to a single flamegraph; ignoring it");
+            }
+            if lanes && (files.len() > 1 || split_by.is_some()) {
+                eprintln!("--lanes only applies to a single flamegraph; ignoring it");
+            }
+            if renderer == flamegraph::Renderer::Canvas && (files.len() > 1 || split_by.is_some()) {
+                eprintln!("--renderer canvas only applies to a single flamegraph; ignoring it");
+            }
+
+            match split_by {
+                Some(SplitBy::Cpu) => gen_per_cpu_flamegraphs(&files, &output, &opts, &render_opts, annotate_source),
+                None => gen_flamegraphs(&files, &output, &opts, &render_opts, heatmap, lanes, annotate_source),
+            }
+        }
+        Commands::Collapse { output, range, shaping, files } => {
+            let mut opts = stackcollapse::Options::default();
+            select_samples(range, &mut opts);
+            shape_stacks(shaping, &mut opts);
+            let folded = folded_text(&collapse_files(&files, &opts));
+            match output {
+                Some(path) => write_output(&path, folded),
+                None => print!("{}", folded),
+            }
+        }
+        Commands::Top { limit, range, shaping, files } => {
+            let mut opts = stackcollapse::Options::default();
+            select_samples(range, &mut opts);
+            shape_stacks(shaping, &mut opts);
+            let profile = collapse_files(&files, &opts);
+            let total: u64 = profile.stacks.values().sum();
+            let pct = |n: u64| if total == 0 { 0.0 } else { n as f64 / total as f64 * 100.0 };
+            println!("{:>10} {:>8} {:>10} {:>8}  Function", "Self", "Self %", "Total", "Total %");
+            for f in stackcollapse::function_stats(&profile).iter().take(limit) {
+                // Tell same-named functions of different modules apart
+                let module = profile.frames[f.frame].module.as_deref().map(|m| m.rsplit('/').next().unwrap_or(m));
+                println!(
+                    "{:>10} {:>7.2}% {:>10} {:>7.2}%  {}{}",
+                    f.self_count,
+                    pct(f.self_count),
+                    f.total,
+                    pct(f.total),
+                    f.name,
+                    module.map_or(String::new(), |m| format!(" ({})", m))
+                );
+            }
+        }
+    }
+}
+
+/// Apply the --cpu, --start and --end options.
+fn select_samples(range: SampleRange, opts: &mut stackcollapse::Options) {
+    opts.start = range.start;
+    opts.end = range.end;
+    if let Some(spec) = range.cpu {
+        opts.cpu_filter = match stackcollapse::parse_cpu_list(&spec) {
+            Ok(cpus) => cpus,
+            Err(e) => {
+                eprintln!("Invalid --cpu value: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+}
+
+/// Apply the off-CPU, --rules, --collapse-recursion and stack filter options.
+fn shape_stacks(shaping: StackShaping, opts: &mut stackcollapse::Options) {
+    opts.off_cpu = shaping.off_cpu || shaping.off_wake;
+    opts.off_wake = shaping.off_wake;
+    opts.off_cpu_states = shaping.off_cpu_states;
+    opts.collapse_recursion = shaping.collapse_recursion;
+    opts.filter = rules::StackFilter {
+        focus: shaping.focus,
+        reroot: shaping.reroot,
+        exclude: shaping.exclude,
+        prune: shaping.prune,
+    };
+    if let Some(path) = shaping.rules {
+        opts.rules = match rules::Rules::load(&path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!("Invalid --rules file: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+}
+
+/// A profile as `flg collapse` prints it: one folded stack per line.
+fn folded_text(profile: &stackcollapse::Profile) -> String {
+    let mut folded = stackcollapse::format_collapsed(&profile.fold());
+    if !folded.is_empty() {
+        folded.push('\n');
+    }
+    folded
+}
+
+/// Collapse every input file into one profile.
+fn collapse_files(files: &[String], opts: &stackcollapse::Options) -> stackcollapse::Profile {
+    let mut combined = stackcollapse::Profile::default();
+    for in_filename in files {
+        let raw_text = perfutils::from_file(in_filename);
+        combined.merge(&stackcollapse::collapse_perf_profile(&raw_text, opts));
+    }
+    combined
+}
+
+fn file_title(in_filename: &str) -> &str {
+    Path::new(in_filename)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Flamegraph")
+}
+
+fn write_output(out_filename: &str, html: String) {
+    if let Err(e) = fs::write(out_filename, html) {
+        eprintln!("Failed to write output to {}: {}", out_filename, e);
+        std::process::exit(1);
+    }
+}
+
+/// Read the source files with self samples in `profile` for --annotate-source,
+/// keyed by their path in the profile. Files that can't be read are reported and
+/// left out.
+fn read_sources(profile: &stackcollapse::Profile, roots: &[SourceRoot]) -> HashMap<String, String> {
+    let mut sources = HashMap::new();
+    let mut unreadable = Vec::new();
+    for file in stackcollapse::source_line_stats(profile).into_keys() {
+        let path = SourceRoot::remap(roots, file);
+        match fs::read_to_string(&*path) {
+            Ok(text) => {
+                sources.insert(file.to_string(), text);
+            }
+            Err(e) => unreadable.push(format!("{}: {}", path, e)),
+        }
+    }
+    if !unreadable.is_empty() {
+        eprintln!(
+            "Couldn't read {} of {} source files to annotate (--source-root maps build paths to a checkout):",
+            unreadable.len(),
+            unreadable.len() + sources.len()
+        );
+        for line in unreadable.iter().take(5) {
+            eprintln!("  {}", line);
+        }
+        if unreadable.len() > 5 {
+            eprintln!("  ... and {} more", unreadable.len() - 5);
         }
     }
+    sources
 }
 
-fn gen_flamegraphs(files: &[String], out_filename: &str) {
+fn gen_flamegraphs(
+    files: &[String],
+    out_filename: &str,
+    opts: &stackcollapse::Options,
+    render_opts: &flamegraph::Options,
+    heatmap: bool,
+    lanes: bool,
+    annotate_source: bool,
+) {
     let in_filenames: Vec<&str> = files.iter().map(|s| s.as_str()).collect();
 
     // Single file: generate simple flamegraph
     if in_filenames.len() == 1 {
         let in_filename = in_filenames[0];
-        let default_title = Path::new(in_filename)
-            .file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or("Flamegraph");
+        let default_title = file_title(in_filename);
 
         let raw_text = perfutils::from_file(in_filename);
-        let stacks = stackcollapse::collapse_perf(
-            &raw_text, 
-            &stackcollapse::Options::default()
-        );
 
-        let html = flamegraph::generate_flamegraph(&stacks, default_title, None);
-        if let Err(e) = fs::write(out_filename, html) {
-            eprintln!("Failed to write output to {}: {}", out_filename, e);
-            std::process::exit(1);
-        }
+        let samples = (heatmap || lanes).then(|| stackcollapse::collapse_perf_samples(&raw_text, opts));
+        let collapsed;
+        let profile = match &samples {
+            Some(samples) => &samples.profile,
+            None => {
+                collapsed = stackcollapse::collapse_perf_profile(&raw_text, opts);
+                &collapsed
+            }
+        };
+        let sources = annotate_source.then(|| read_sources(profile, render_opts.source_roots));
+        let render_opts = flamegraph::Options {
+            heatmap: samples.as_ref().filter(|_| heatmap),
+            lanes: samples.as_ref().filter(|_| lanes),
+            sources: sources.as_ref(),
+            ..render_opts.clone()
+        };
+        let html = flamegraph::generate_flamegraph_with_options(profile, default_title, None, &render_opts);
+        write_output(out_filename, html);
         return;
     }
 
     // Multiple files: generate batch flamegraph with Combined
     let mut entries = Vec::new();
-    let mut combined_stacks = HashMap::new();
+    let mut combined = stackcollapse::Profile::default();
 
     for in_filename in &in_filenames {
-        let title = Path::new(in_filename)
-            .file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or("Flamegraph")
-            .to_string();
+        let title = file_title(in_filename).to_string();
 
         let raw_text = perfutils::from_file(in_filename);
-        let stacks = stackcollapse::collapse_perf(
-            &raw_text, 
-            &stackcollapse::Options::default()
-        );
+        let profile = stackcollapse::collapse_perf_profile(&raw_text, opts);
+        combined.merge(&profile);
 
-        // Merge into combined stacks
-        for (stack, count) in &stacks {
-            *combined_stacks.entry(stack.clone()).or_insert(0) += count;
-        }
-
-        entries.push(flamegraph::FlameGraphEntry { stacks, title });
+        entries.push(flamegraph::FlameGraphEntry { profile, title });
     }
 
+    let sources = annotate_source.then(|| read_sources(&combined, render_opts.source_roots));
+    let render_opts = flamegraph::Options { sources: sources.as_ref(), ..render_opts.clone() };
+
     // Add combined flamegraph (always present when 2+ files)
     entries.push(flamegraph::FlameGraphEntry { 
-        stacks: combined_stacks, 
+        profile: combined, 
         title: "Combined".to_string() 
     });
 
-    let html = flamegraph::generate_batch_flamegraph(&entries);
-    if let Err(e) = fs::write(out_filename, html) {
-        eprintln!("Failed to write output to {}: {}", out_filename, e);
+    let html = flamegraph::generate_batch_flamegraph_with_options(&entries, &render_opts);
+    write_output(out_filename, html);
+    
+    eprintln!("Generated {} flamegraphs in {}", entries.len(), out_filename);
+}
+
+/// Generate a flame chart page with one time-ordered graph per thread.
+fn gen_flame_chart(
+    in_filename: &str,
+    out_filename: &str,
+    opts: &stackcollapse::Options,
+    render_opts: &flamegraph::Options,
+) {
+    let raw_text = perfutils::from_file(in_filename);
+    let samples = stackcollapse::collapse_perf_samples(&raw_text, opts);
+    write_output(out_filename, flamegraph::generate_flame_chart(&samples, render_opts));
+}
+
+/// Generate a batch page with one flamegraph per CPU (per input file), followed
+/// by a combined flamegraph over all selected CPUs.
+fn gen_per_cpu_flamegraphs(
+    files: &[String],
+    out_filename: &str,
+    opts: &stackcollapse::Options,
+    render_opts: &flamegraph::Options,
+    annotate_source: bool,
+) {
+    let mut entries = Vec::new();
+    let mut combined = stackcollapse::Profile::default();
+
+    for in_filename in files {
+        let raw_text = perfutils::from_file(in_filename);
+        let per_cpu = stackcollapse::collapse_perf_per_cpu(&raw_text, opts);
+
+        if per_cpu.is_empty() {
+            eprintln!(
+                "No per-CPU samples found in {} (record system-wide or use `perf script -F+cpu`)",
+                in_filename
+            );
+            continue;
+        }
+
+        for (cpu, profile) in per_cpu {
+            combined.merge(&profile);
+
+            let title = if files.len() == 1 {
+                format!("CPU {}", cpu)
+            } else {
+                format!("{} - CPU {}", file_title(in_filename), cpu)
+            };
+            entries.push(flamegraph::FlameGraphEntry { profile, title });
+        }
+    }
+
+    if entries.is_empty() {
+        eprintln!("No samples with CPU information to split");
         std::process::exit(1);
     }
-    
+
+    let sources = annotate_source.then(|| read_sources(&combined, render_opts.source_roots));
+    let render_opts = flamegraph::Options { sources: sources.as_ref(), ..render_opts.clone() };
+
+    entries.push(flamegraph::FlameGraphEntry {
+        profile: combined,
+        title: "All CPUs".to_string(),
+    });
+
+    let html = flamegraph::generate_batch_flamegraph_with_options(&entries, &render_opts);
+    write_output(out_filename, html);
+
     eprintln!("Generated {} flamegraphs in {}", entries.len(), out_filename);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `flg collapse` output for perf script text, with the options of `args`.
+    fn collapse(args: &[&str], input: &str) -> String {
+        let Commands::Collapse { range, shaping, .. } = Cli::parse_from([&["flg", "collapse"], args, &["in.txt"]].concat()).command else {
+            unreachable!()
+        };
+        let mut opts = stackcollapse::Options::default();
+        select_samples(range, &mut opts);
+        shape_stacks(shaping, &mut opts);
+        folded_text(&stackcollapse::collapse_perf_profile(input, &opts))
+    }
+
+    #[test]
+    fn test_collapse_filters() {
+        let input = r#"
+app 10 1.000000: 1 cpu-clock:
+        1111 parse (/bin/app)
+        2222 handle (/bin/app)
+        3333 main (/bin/app)
+
+app 10 1.010000: 1 cpu-clock:
+        4444 write (/bin/app)
+        2222 handle (/bin/app)
+        3333 main (/bin/app)
+
+app 10 1.020000: 1 cpu-clock:
+        5555 idle (/bin/app)
+        3333 main (/bin/app)
+
+"#;
+        assert_eq!(collapse(&[], input), "app;main;handle;parse 1\napp;main;handle;write 1\napp;main;idle 1\n");
+        assert_eq!(collapse(&["--focus", "^handle$"], input), "app;main;handle;parse 1\napp;main;handle;write 1\n");
+        assert_eq!(collapse(&["--focus", "^handle$", "--reroot"], input), "handle;parse 1\nhandle;write 1\n");
+        assert_eq!(collapse(&["--exclude", "^write$"], input), "app;main;handle;parse 1\napp;main;idle 1\n");
+        assert_eq!(collapse(&["--prune", "^handle$"], input), "app;main;handle 2\napp;main;idle 1\n");
+    }
+
+    #[test]
+    fn test_min_width_range() {
+        let parse = |width: &str| Cli::try_parse_from(["flg", "gen", "--min-width", width, "in.txt"]).map(|_| ());
+        assert!(parse("0").is_ok() && parse("2.5").is_ok() && parse("100").is_ok());
+        for width in ["inf", "NaN", "-1", "100.5", "wide"] {
+            assert!(parse(width).is_err(), "{}", width);
+        }
+    }
+}
@@ -45,7 +45,7 @@ pub fn from_file(filepath: &str) -> String {
     };
     drop(fp);
 
-    if bytes_read == 8 && &buf == header.as_bytes() {
+    if bytes_read == 8 && buf == header.as_bytes() {
         from_datafile(filepath)
     } else {
         match fs::read_to_string(filepath) {
@@ -0,0 +1,284 @@
+//! User-defined frame rules, loaded from a TOML file with `--rules`, and stack
+//! filters (`--focus`, `--exclude`, `--prune`).
+//!
+//! Each rule matches a regex against a frame's (tidied) name, and optionally
+//! another against its module, and says what to do with matching frames:
+//!
+//! ```toml
+//! # Hide the tokio scheduler behind a single frame
+//! [[rule]]
+//! match = "^tokio::runtime::"
+//! action = "collapse"
+//! name = "[tokio runtime]"
+//!
+//! # Shorten our own crate's paths
+//! [[rule]]
+//! match = "^my_app::(.*)"
+//! action = "rename"
+//! name = "app::$1"
+//!
+//! # Drop everything libc does
+//! [[rule]]
+//! match = ".*"
+//! module = "libc\\.so"
+//! action = "drop"
+//! ```
+//!
+//! Rename rules are applied in order, each seeing the previous renames; the first
+//! other rule that matches decides what happens to the frame.
+//!
+//! Rules see one frame at a time as stacks are collapsed; `StackFilter` then sees
+//! each complete folded stack.
+
+use std::fs;
+use std::ops::Range;
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// What to do with a frame matched by a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Replace the matched part of the name (`$1` etc. refer to capture groups)
+    Rename(String),
+    /// Remove the frame from the stack
+    Drop,
+    /// Fold consecutive matching frames into one, named `name` or after the
+    /// outermost of them
+    Collapse(Option<String>),
+    /// Remove every frame below (towards the root of) the outermost match
+    Truncate,
+    /// Remove every frame above (called from) the outermost match, making it the leaf
+    Merge,
+}
+
+/// A single rule.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Regex,
+    module: Option<Regex>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn matches(&self, name: &str, module: &str) -> bool {
+        self.pattern.is_match(name) && self.module.as_ref().is_none_or(|m| m.is_match(module))
+    }
+}
+
+/// An ordered set of rules.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    #[serde(rename = "match")]
+    pattern: String,
+    module: Option<String>,
+    action: ActionKind,
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ActionKind {
+    Rename,
+    Drop,
+    Collapse,
+    Truncate,
+    Merge,
+}
+
+impl Rules {
+    /// Load rules from a TOML file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Parse rules from TOML text.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: RulesFile = toml::from_str(text).map_err(|e| e.to_string())?;
+
+        let mut rules = Vec::with_capacity(file.rule.len());
+        for (i, spec) in file.rule.into_iter().enumerate() {
+            let regex = |pattern: &str| {
+                Regex::new(pattern).map_err(|e| format!("rule {}: {}", i + 1, e))
+            };
+
+            let action = match (spec.action, spec.name) {
+                (ActionKind::Rename, Some(name)) => Action::Rename(name),
+                (ActionKind::Rename, None) => {
+                    return Err(format!("rule {}: rename needs a `name`", i + 1));
+                }
+                (ActionKind::Collapse, name) => Action::Collapse(name),
+                (_, Some(_)) => {
+                    return Err(format!("rule {}: only rename and collapse take a `name`", i + 1));
+                }
+                (ActionKind::Drop, None) => Action::Drop,
+                (ActionKind::Truncate, None) => Action::Truncate,
+                (ActionKind::Merge, None) => Action::Merge,
+            };
+
+            rules.push(Rule {
+                pattern: regex(&spec.pattern)?,
+                module: spec.module.as_deref().map(regex).transpose()?,
+                action,
+            });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply the rename rules to a frame, returning the new name along with the
+    /// index and action of the first other rule matching it.
+    pub fn apply(&self, name: String, module: &str) -> (String, Option<(usize, &Action)>) {
+        let mut name = name;
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(&name, module) {
+                continue;
+            }
+            match &rule.action {
+                Action::Rename(replacement) => {
+                    name = rule.pattern.replace_all(&name, replacement.as_str()).into_owned();
+                }
+                action => return (name, Some((i, action))),
+            }
+        }
+
+        (name, None)
+    }
+}
+
+/// Whole-stack filters, matched against each frame of a folded stack
+/// (including the process name frame).
+#[derive(Debug, Clone, Default)]
+pub struct StackFilter {
+    /// Keep only stacks with a matching frame
+    pub focus: Option<Regex>,
+    /// With `focus`, start kept stacks at the outermost matching frame
+    pub reroot: bool,
+    /// Drop stacks with a matching frame
+    pub exclude: Option<Regex>,
+    /// Cut stacks above (leaf side of) the outermost matching frame
+    pub prune: Option<Regex>,
+}
+
+impl StackFilter {
+    pub fn is_empty(&self) -> bool {
+        self.focus.is_none() && self.exclude.is_none() && self.prune.is_none()
+    }
+
+    /// Filter a stack given by its frame names, returning the range of frames to
+    /// keep or `None` if it should be dropped.
+    pub fn select(&self, frames: &[&str]) -> Option<Range<usize>> {
+        let position = |frames: &[&str], regex: &Regex| frames.iter().position(|f| regex.is_match(f));
+        let mut range = 0..frames.len();
+
+        if let Some(exclude) = &self.exclude
+            && position(frames, exclude).is_some()
+        {
+            return None;
+        }
+
+        if let Some(focus) = &self.focus {
+            let at = position(frames, focus)?;
+            if self.reroot {
+                range.start = at;
+            }
+        }
+
+        if let Some(prune) = &self.prune
+            && let Some(at) = position(&frames[range.clone()], prune)
+        {
+            range.end = range.start + at + 1;
+        }
+
+        Some(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_apply() {
+        let rules = Rules::parse(
+            r#"
+[[rule]]
+match = "^my_app::(.*)"
+action = "rename"
+name = "app::$1"
+
+[[rule]]
+match = "^app::internal"
+action = "drop"
+
+[[rule]]
+match = "^read$"
+module = "libc"
+action = "merge"
+"#,
+        )
+        .unwrap();
+
+        let (name, action) = rules.apply("my_app::run".to_string(), "/bin/app");
+        assert_eq!((name.as_str(), action), ("app::run", None));
+
+        let (name, action) = rules.apply("my_app::internal::poll".to_string(), "/bin/app");
+        assert_eq!(name, "app::internal::poll");
+        assert_eq!(action, Some((1, &Action::Drop)));
+
+        assert_eq!(rules.apply("read".to_string(), "/usr/lib/libc.so.6").1, Some((2, &Action::Merge)));
+        assert_eq!(rules.apply("read".to_string(), "/bin/app").1, None);
+    }
+
+    #[test]
+    fn test_stack_filter() {
+        let regex = |r: &str| Some(Regex::new(r).unwrap());
+        let stack = ["app", "main", "server::handle", "parse", "alloc"];
+        let idle = ["app", "main", "idle"];
+
+        let filter = StackFilter { exclude: regex("^alloc$"), ..Default::default() };
+        assert_eq!(filter.select(&stack), None);
+        assert_eq!(filter.select(&idle), Some(0..3));
+
+        let filter = StackFilter { focus: regex("^server::"), ..Default::default() };
+        assert_eq!(filter.select(&stack), Some(0..5));
+        assert_eq!(filter.select(&idle), None);
+
+        let filter = StackFilter { focus: regex("^server::"), reroot: true, ..Default::default() };
+        assert_eq!(filter.select(&stack), Some(2..5));
+
+        let filter = StackFilter { prune: regex("^parse$"), ..Default::default() };
+        assert_eq!(filter.select(&stack), Some(0..4));
+
+        let filter = StackFilter { focus: regex("^server::"), reroot: true, prune: regex("^parse$"), ..Default::default() };
+        assert_eq!(filter.select(&stack), Some(2..4));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Rules::parse("[[rule]]\nmatch = \"x\"\naction = \"rename\"").is_err());
+        assert!(Rules::parse("[[rule]]\nmatch = \"x\"\naction = \"drop\"\nname = \"y\"").is_err());
+        assert!(Rules::parse("[[rule]]\nmatch = \"(\"\naction = \"drop\"").is_err());
+        assert!(Rules::parse("[[rule]]\nmatch = \"x\"\naction = \"explode\"").is_err());
+        assert!(Rules::parse("").unwrap().is_empty());
+    }
+}
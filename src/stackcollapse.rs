@@ -1,13 +1,14 @@
 //! Stack collapse for perf script output.
 //!
-//! Parses multiline stacks generated by `perf script` and outputs semicolon-separated
-//! stacks followed by a space and a count. Identical stacks are collapsed with their
-//! counts summed.
+//! Parses multiline stacks generated by `perf script` into a `Profile`: each
+//! distinct frame with its module, kind, source line and address, and identical
+//! stacks collapsed with their counts summed. `Profile::fold` exports it as
+//! semicolon-separated stacks followed by a space and a count.
 //!
 //! # Example
 //!
 //! ```
-//! use stackcollapse_perf::{collapse_perf, Options};
+//! use stackcollapse_perf::{collapse_perf_profile, format_collapsed, Options};
 //!
 //! let input = r#"
 //! swapper     0 [000] 158665.570607: cpu-clock:
@@ -17,18 +18,14 @@
 //! "#;
 //!
 //! let opts = Options::default();
-//! let result = collapse_perf(input, &opts);
+//! let profile = collapse_perf_profile(input, &opts);
+//! let folded = format_collapsed(&profile.fold());
 //! ```
 
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::process::Command;
 
-pub struct Stack {
-    stack: String,
-    num_occurances: u64,
-    width: u64,
-}
+use crate::rules::{Action, Rules, StackFilter};
 
 /// Configuration options for stack collapsing.
 #[derive(Debug, Clone)]
@@ -47,8 +44,29 @@ pub struct Options {
     pub include_addrs: bool,
     /// Condense Java signatures
     pub tidy_java: bool,
+    /// In Java processes, fold runs of JVM (`libjvm.so`) frames into one frame,
+    /// named after the GC or compiler thread they run on
+    pub fold_jvm_internals: bool,
     /// Clean up function names
     pub tidy_generic: bool,
+    /// Demangle and tidy Rust symbols when the profile contains any
+    pub tidy_rust: bool,
+    /// Replace generic parameters in tidied Rust symbols with `<…>`
+    pub rust_elide_generics: bool,
+    /// Simplify C++ names: drop return types, parameter lists and ABI tags
+    pub tidy_cpp: bool,
+    /// Template argument nesting kept in tidied C++ names; deeper lists become `<…>`
+    pub template_depth: usize,
+    /// Clean up Python (`-X perf`) and Node (`--perf-basic-prof`) frames from perf maps
+    pub tidy_jit: bool,
+    /// Keep the file (and line, for Node) of tidied JIT frames as `func:file:line`
+    pub jit_source: bool,
+    /// Annotate tidied JIT frames with their tier: `_[b]` for bytecode
+    /// interpreted, `_[j]` for compiled
+    pub jit_tiers: bool,
+    /// Hide CPython interpreter frames (`_PyEval_EvalFrameDefault`, ...) that sit
+    /// between two Python frames
+    pub hide_interpreter_frames: bool,
     /// Event type filter (empty string means use first encountered)
     pub event_filter: String,
     /// Use addr2line for inline expansion
@@ -57,6 +75,28 @@ pub struct Options {
     pub show_context: bool,
     /// Parse source line info from `perf script -F+srcline`
     pub srcline_in_input: bool,
+    /// Only keep samples taken on these CPUs (empty means all CPUs); in off-CPU
+    /// mode, blocked time goes by the CPU and time the thread was switched out
+    pub cpu_filter: Vec<u32>,
+    /// Drop samples taken before this point in the recording
+    pub start: Option<TimeBound>,
+    /// Drop samples taken after this point in the recording
+    pub end: Option<TimeBound>,
+    /// Treat `sched:sched_switch` events as off-CPU time: pair each thread's
+    /// switch-out with its next switch-in and weight the stack by nanoseconds blocked
+    pub off_cpu: bool,
+    /// In off-CPU mode, root each stack at a frame naming the blocked state
+    /// (`[sleep]`, `[io]`, `[preempted]`, ...)
+    pub off_cpu_states: bool,
+    /// In off-CPU mode, also read `sched:sched_wakeup` events and stack the waker's
+    /// stack (leaf first) above the blocked stack, separated by `OFF_WAKE_SEPARATOR`
+    pub off_wake: bool,
+    /// User rules applied to each frame after the built-in tidying
+    pub rules: Rules,
+    /// Fold recursive calls into one frame, recording how many levels were folded
+    pub collapse_recursion: Option<Recursion>,
+    /// Focus, exclude and prune filters applied to each complete stack
+    pub filter: StackFilter,
 }
 
 impl Default for Options {
@@ -69,17 +109,36 @@ impl Default for Options {
             include_tid: false,
             include_addrs: false,
             tidy_java: true,
+            fold_jvm_internals: false,
             tidy_generic: true,
+            tidy_rust: true,
+            rust_elide_generics: false,
+            tidy_cpp: true,
+            template_depth: 1,
+            tidy_jit: true,
+            jit_source: false,
+            jit_tiers: false,
+            hide_interpreter_frames: false,
             event_filter: String::new(),
             show_inline: false,
             show_context: false,
             srcline_in_input: false,
+            cpu_filter: Vec::new(),
+            start: None,
+            end: None,
+            off_cpu: false,
+            off_cpu_states: false,
+            off_wake: false,
+            rules: Rules::default(),
+            collapse_recursion: None,
+            filter: StackFilter::default(),
         }
     }
 }
 
 impl Options {
     /// Enable all annotations (kernel and JIT).
+    #[allow(dead_code)]
     pub fn with_all_annotations(mut self) -> Self {
         self.annotate_kernel = true;
         self.annotate_jit = true;
@@ -87,22 +146,600 @@ impl Options {
     }
 }
 
+/// A point in time within a recording, used to select a window of samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeBound {
+    /// Absolute perf timestamp in seconds, written as `@158665.5`
+    Absolute(f64),
+    /// Seconds after the first sample, written as `5`, `5s` or `250ms`
+    Relative(f64),
+    /// Percentage of the recording's duration, written as `25%`
+    Percent(f64),
+}
+
+impl std::str::FromStr for TimeBound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let number = |v: &str| {
+            v.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite() && *n >= 0.0)
+                .ok_or_else(|| format!("invalid time '{}'", s))
+        };
+
+        if let Some(abs) = s.strip_prefix('@') {
+            Ok(TimeBound::Absolute(number(abs)?))
+        } else if let Some(pct) = s.strip_suffix('%') {
+            let pct = number(pct)?;
+            if pct > 100.0 {
+                return Err(format!("percentage out of range '{}'", s));
+            }
+            Ok(TimeBound::Percent(pct))
+        } else if let Some(ms) = s.strip_suffix("ms") {
+            Ok(TimeBound::Relative(number(ms)? / 1000.0))
+        } else {
+            Ok(TimeBound::Relative(number(s.strip_suffix('s').unwrap_or(s))?))
+        }
+    }
+}
+
+impl TimeBound {
+    /// Resolve to an absolute timestamp given the first and last sample times.
+    fn resolve(self, first: f64, last: f64) -> f64 {
+        match self {
+            TimeBound::Absolute(t) => t,
+            TimeBound::Relative(offset) => first + offset,
+            TimeBound::Percent(pct) => first + (last - first) * pct / 100.0,
+        }
+    }
+}
+
 /// Result of stack collapsing: maps folded stack strings to their counts.
 pub type CollapsedStacks = HashMap<String, u64>;
 
+/// Details gathered while collapsing that belong to folded names and paths rather
+/// than to single frames, shown in the viewer's tooltip.
+#[derive(Debug, Clone, Default)]
+pub struct FrameInfo {
+    /// Untidied names of frames whose names were shortened, keyed by the shortened name
+    pub full_names: HashMap<String, String>,
+    /// Most levels of recursion folded into a frame, keyed by the frame's folded path
+    pub recursion: HashMap<String, u64>,
+}
+
+impl FrameInfo {
+    /// Add the details from another profile, keeping existing full names.
+    pub fn merge(&mut self, other: &FrameInfo) {
+        for (name, full) in &other.full_names {
+            self.full_names.entry(name.clone()).or_insert_with(|| full.clone());
+        }
+        for (path, &levels) in &other.recursion {
+            let entry = self.recursion.entry(path.clone()).or_insert(0);
+            *entry = (*entry).max(levels);
+        }
+    }
+}
+
+/// Index of a frame in `Profile::frames`.
+pub type FrameId = usize;
+
+/// A position in a source file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+impl SourceLine {
+    /// Parse `file:line` as printed by addr2line and `perf script -F+srcline`,
+    /// ignoring a trailing `(discriminator N)`. Unknown positions (`??:0`) give `None`.
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.split(" (discriminator").next().unwrap_or(s).trim();
+        let (file, line) = s.rsplit_once(':')?;
+        let line = line.trim().parse().ok().filter(|&line| line > 0)?;
+        (!file.is_empty() && file != "??").then(|| SourceLine { file: file.to_string(), line })
+    }
+}
+
+/// Where the sources of a build directory are now, from `--source-root BUILD_DIR=LOCAL_DIR`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRoot {
+    pub from: String,
+    pub to: String,
+}
+
+impl SourceRoot {
+    /// `file` moved by the first of `roots` whose build directory contains it.
+    pub fn remap<'a>(roots: &[SourceRoot], file: &'a str) -> std::borrow::Cow<'a, str> {
+        roots
+            .iter()
+            .find_map(|root| {
+                let rest = file.strip_prefix(root.from.trim_end_matches('/'))?;
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                // An empty local directory leaves paths relative to the build directory
+                Some(if root.to.is_empty() {
+                    rest.trim_start_matches('/').to_string()
+                } else {
+                    format!("{}{}", root.to.trim_end_matches('/'), rest)
+                })
+            })
+            .map_or(std::borrow::Cow::Borrowed(file), std::borrow::Cow::Owned)
+    }
+}
+
+impl std::str::FromStr for SourceRoot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(SourceRoot { from: from.to_string(), to: to.to_string() }),
+            _ => Err(format!("expected BUILD_DIR=LOCAL_DIR, got '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// One distinct frame of a profile: a function together with where its code came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Frame {
+    /// Name after tidying and the user's rules
+    pub name: String,
+    /// Path of the binary, shared library or perf map the code is in
+    pub module: Option<String>,
+    /// `None` for frames flg adds itself (process names, off-CPU states) and
+    /// frames read back from folded text
+    pub kind: Option<FrameKind>,
+    pub language: Option<Language>,
+    /// Source position, from `perf script -F+srcline` or addr2line
+    pub source: Option<SourceLine>,
+}
+
+impl Frame {
+    /// A frame known only by its name.
+    pub fn named(name: impl Into<String>) -> Self {
+        Frame { name: name.into(), module: None, kind: None, language: None, source: None }
+    }
+
+    /// The name as written in folded text, where `;` separates frames.
+    pub fn folded_name(&self) -> std::borrow::Cow<'_, str> {
+        if self.name.contains(';') {
+            self.name.replace(';', ":").into()
+        } else {
+            self.name.as_str().into()
+        }
+    }
+}
+
+/// A collapsed profile with its frames kept structured: each distinct frame is
+/// stored once and stacks refer to frames by id.
+///
+/// Folded text is one export of it (`Profile::fold`); the renderers use the
+/// frames for their tooltips, colour modes and function table.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub frames: Vec<Frame>,
+    /// Address each frame was first sampled at, where perf printed one
+    pub addresses: Vec<Option<u64>>,
+    /// Weight of each distinct stack, root first
+    pub stacks: HashMap<Vec<FrameId>, u64>,
+    pub info: FrameInfo,
+    frame_ids: HashMap<Frame, FrameId>,
+}
+
+impl Profile {
+    /// Id of `frame`, adding it to the profile if it is new.
+    pub fn intern(&mut self, frame: Frame, address: Option<u64>) -> FrameId {
+        if let Some(&id) = self.frame_ids.get(&frame) {
+            return id;
+        }
+        self.frames.push(frame.clone());
+        self.addresses.push(address);
+        self.frame_ids.insert(frame, self.frames.len() - 1);
+        self.frames.len() - 1
+    }
+
+    pub fn name(&self, id: FrameId) -> &str {
+        &self.frames[id].name
+    }
+
+    /// A stack as one line of folded text, without the count.
+    pub fn folded(&self, stack: &[FrameId]) -> String {
+        let names: Vec<_> = stack.iter().map(|&id| self.frames[id].folded_name()).collect();
+        names.join(";")
+    }
+
+    /// Export as folded stacks. Stacks that differ only in frame details merge.
+    pub fn fold(&self) -> CollapsedStacks {
+        let mut collapsed = CollapsedStacks::new();
+        for (stack, &count) in &self.stacks {
+            *collapsed.entry(self.folded(stack)).or_insert(0) += count;
+        }
+        collapsed
+    }
+
+    /// Read folded stacks back; their frames have nothing but names. Tests use it
+    /// to build profiles.
+    #[cfg(test)]
+    pub fn from_folded(collapsed: &CollapsedStacks) -> Self {
+        let mut profile = Profile::default();
+        for (stack, &count) in collapsed {
+            let stack: Vec<FrameId> = stack
+                .split(';')
+                .map(|name| profile.intern(Frame::named(name), None))
+                .collect();
+            *profile.stacks.entry(stack).or_insert(0) += count;
+        }
+        profile
+    }
+
+    /// Add another profile's stacks and details to this one.
+    pub fn merge(&mut self, other: &Profile) {
+        let ids: Vec<FrameId> = other
+            .frames
+            .iter()
+            .zip(&other.addresses)
+            .map(|(frame, &address)| self.intern(frame.clone(), address))
+            .collect();
+        for (stack, &count) in &other.stacks {
+            let stack = stack.iter().map(|&id| ids[id]).collect();
+            *self.stacks.entry(stack).or_insert(0) += count;
+        }
+        self.info.merge(&other.info);
+    }
+
+    /// A profile sharing this one's frames and details but with other stacks.
+    pub fn with_stacks(&self, stacks: HashMap<Vec<FrameId>, u64>) -> Self {
+        Profile {
+            frames: self.frames.clone(),
+            addresses: self.addresses.clone(),
+            stacks,
+            info: self.info.clone(),
+            frame_ids: self.frame_ids.clone(),
+        }
+    }
+}
+
+/// What sort of code a frame was running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    /// Ordinary user-space code
+    User,
+    Kernel,
+    /// Code compiled at run time, symbolized from a `/tmp/perf-<pid>.map`
+    Jit,
+    /// A function inlined into its caller
+    Inlined,
+    /// A frame perf couldn't symbolize
+    Unknown,
+}
+
+impl FrameKind {
+    pub const ALL: [FrameKind; 5] = [
+        FrameKind::User,
+        FrameKind::Kernel,
+        FrameKind::Jit,
+        FrameKind::Inlined,
+        FrameKind::Unknown,
+    ];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            FrameKind::User => "user",
+            FrameKind::Kernel => "kernel",
+            FrameKind::Jit => "jit",
+            FrameKind::Inlined => "inlined",
+            FrameKind::Unknown => "unknown",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FrameKind::User => "User",
+            FrameKind::Kernel => "Kernel",
+            FrameKind::Jit => "JIT",
+            FrameKind::Inlined => "Inlined",
+            FrameKind::Unknown => "Unknown",
+        }
+    }
+}
+
+/// Language a frame's function was written in, guessed from its symbol, its
+/// module and the runtime that produced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    Rust,
+    Cpp,
+    Java,
+    Python,
+    JavaScript,
+    /// Other native code: C, or anything without a telltale symbol
+    Native,
+}
+
+impl Language {
+    pub const ALL: [Language; 6] = [
+        Language::Rust,
+        Language::Cpp,
+        Language::Java,
+        Language::Python,
+        Language::JavaScript,
+        Language::Native,
+    ];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Language::Rust => "rust",
+            Language::Cpp => "cpp",
+            Language::Java => "java",
+            Language::Python => "python",
+            Language::JavaScript => "javascript",
+            Language::Native => "native",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Language::Rust => "Rust",
+            Language::Cpp => "C++",
+            Language::Java => "Java",
+            Language::Python => "Python",
+            Language::JavaScript => "JavaScript",
+            Language::Native => "C / native",
+        }
+    }
+}
+
+/// Totals for one function across a profile's stacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    /// Folded name
+    pub name: String,
+    /// The function's first frame in the profile, for its module and source
+    pub frame: FrameId,
+    /// Count of stacks with the function as their leaf
+    pub self_count: u64,
+    /// Count of stacks with the function anywhere on them, once per stack however
+    /// often it recurses
+    pub total: u64,
+    /// Number of distinct functions calling it
+    pub call_sites: usize,
+}
+
+/// A function as `function_stats` tells them apart: a name in a module.
+type FunctionKey<'a> = (&'a str, Option<&'a str>);
+
+/// Aggregate a profile's stacks per function, hottest (by self count) first.
+pub fn function_stats(profile: &Profile) -> Vec<FunctionStats> {
+    let key = |id: FrameId| -> FunctionKey<'_> {
+        let frame = &profile.frames[id];
+        (&frame.name, frame.module.as_deref())
+    };
+    let mut stats: HashMap<FunctionKey<'_>, (FrameId, u64, u64, HashSet<FunctionKey<'_>>)> = HashMap::new();
+
+    for (stack, &count) in &profile.stacks {
+        let frames: Vec<FrameId> = stack
+            .iter()
+            .copied()
+            .filter(|&id| profile.name(id) != OFF_WAKE_SEPARATOR)
+            .collect();
+        let mut seen = HashSet::new();
+        for (i, &id) in frames.iter().enumerate() {
+            let function = key(id);
+            let entry = stats.entry(function).or_insert_with(|| (id, 0, 0, HashSet::new()));
+            entry.0 = entry.0.min(id);
+            if seen.insert(function) {
+                entry.2 += count;
+            }
+            if i + 1 == frames.len() {
+                entry.1 += count;
+            }
+            if i > 0 {
+                entry.3.insert(key(frames[i - 1]));
+            }
+        }
+    }
+
+    let mut stats: Vec<FunctionStats> = stats
+        .into_values()
+        .map(|(frame, self_count, total, callers)| FunctionStats {
+            name: profile.frames[frame].folded_name().into_owned(),
+            frame,
+            self_count,
+            total,
+            call_sites: callers.len(),
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        (b.self_count, b.total)
+            .cmp(&(a.self_count, a.total))
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| profile.frames[a.frame].module.cmp(&profile.frames[b.frame].module))
+    });
+    stats
+}
+
+/// Self counts of a profile per source file and line, from its stacks' leaf frames.
+pub fn source_line_stats(profile: &Profile) -> BTreeMap<&str, BTreeMap<u32, u64>> {
+    let mut stats: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::new();
+    for (stack, &count) in &profile.stacks {
+        if let Some(&leaf) = stack.last()
+            && let Some(source) = &profile.frames[leaf].source
+        {
+            *stats.entry(&source.file).or_default().entry(source.line).or_insert(0) += count;
+        }
+    }
+    stats
+}
+
+/// Which repeated frames `Options::collapse_recursion` folds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recursion {
+    /// A frame calling itself directly (`parse;parse;parse`)
+    Direct,
+    /// Any frame already on the stack; the calls in between are dropped
+    /// (`expr;term;expr;term` becomes `expr;term`)
+    All,
+}
+
+impl std::str::FromStr for Recursion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(Recursion::Direct),
+            "all" => Ok(Recursion::All),
+            _ => Err(format!("expected 'direct' or 'all', got '{}'", s)),
+        }
+    }
+}
+
+/// A single sample, in the order it appeared in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Timestamp in seconds, if `perf script` printed one
+    pub time: Option<f64>,
+    /// Index into `SampleTable::stacks`
+    pub stack: usize,
+    /// Sample period (or 1 when perf did not print one)
+    pub weight: u64,
+    /// Index into `SampleTable::threads`
+    pub thread: usize,
+}
+
+/// A thread samples were taken on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Thread {
+    pub pid: String,
+    pub tid: String,
+    /// Command name when the thread was first sampled
+    pub comm: String,
+}
+
+/// Per-sample view of a profile: the distinct stacks plus one entry per sample.
+#[derive(Debug, Clone, Default)]
+pub struct SampleTable {
+    /// Distinct stacks, root first, made of frames of `profile`
+    pub stacks: Vec<Vec<FrameId>>,
+    pub samples: Vec<Sample>,
+    pub threads: Vec<Thread>,
+    /// All samples collapsed, with the frames `stacks` refer to
+    pub profile: Profile,
+    stack_ids: HashMap<Vec<FrameId>, usize>,
+    thread_ids: HashMap<(String, String), usize>,
+}
+
+impl SampleTable {
+    fn push(&mut self, stack: &[FrameId], time: Option<f64>, weight: u64, thread: &Thread) {
+        let id = match self.stack_ids.get(stack) {
+            Some(&id) => id,
+            None => {
+                self.stacks.push(stack.to_vec());
+                self.stack_ids.insert(stack.to_vec(), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+        let key = (thread.pid.clone(), thread.tid.clone());
+        let thread = match self.thread_ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                self.threads.push(thread.clone());
+                self.thread_ids.insert(key, self.threads.len() - 1);
+                self.threads.len() - 1
+            }
+        };
+        self.samples.push(Sample { time, stack: id, weight, thread });
+    }
+
+    /// First and last sample timestamps.
+    pub fn time_range(&self) -> Option<(f64, f64)> {
+        self.samples
+            .iter()
+            .filter_map(|s| s.time)
+            .fold(None, |range, t| match range {
+                None => Some((t, t)),
+                Some((lo, hi)) => Some((f64::min(lo, t), f64::max(hi, t))),
+            })
+    }
+}
+
+/// Event that off-CPU mode reads thread switches from.
+const SCHED_SWITCH_EVENT: &str = "sched:sched_switch";
+
+/// Events that off-wake mode reads wakeups from.
+const SCHED_WAKEUP_EVENTS: [&str; 2] = ["sched:sched_wakeup", "sched:sched_waking"];
+
+/// Frame separating the blocked stack from the waker stack in off-wake stacks.
+pub const OFF_WAKE_SEPARATOR: &str = "--";
+
+/// A thread that has been switched out and not yet switched back in.
+struct BlockedThread {
+    stack: Vec<FrameId>,
+    /// Recursion folded into each frame of `stack`
+    levels: Vec<u64>,
+    time: f64,
+    cpu: Option<u32>,
+    thread: Thread,
+}
+
+/// A function parsed from a stack line, on its way onto the stack.
+struct ParsedFrame {
+    func: String,
+    kind: FrameKind,
+    language: Option<Language>,
+    source: Option<SourceLine>,
+    address: Option<u64>,
+}
+
+/// Functions addr2line found at an address, outermost first, with their source lines.
+type InlineFrames = Vec<(String, Option<SourceLine>)>;
+
 /// Internal state for the parser.
 struct Parser<'a> {
     opts: &'a Options,
-    collapsed: CollapsedStacks,
-    stack: Vec<String>,
+    profile: Profile,
+    per_cpu: Option<BTreeMap<u32, HashMap<Vec<FrameId>, u64>>>,
+    samples: Option<SampleTable>,
+    /// Frames of the stack being read, leaf first
+    stack: Vec<FrameId>,
     pname: Option<String>,
     pid: String,
     tid: String,
+    comm: String,
+    cpu: Option<u32>,
+    time: Option<f64>,
     period: u64,
+    event: String,
+    trace_args: String,
+    blocked: HashMap<String, BlockedThread>,
+    wakers: HashMap<String, Vec<FrameId>>,
+    /// Off-CPU switch-outs seen, and how many were paired with a switch-in
+    switch_outs: usize,
+    paired_switches: usize,
+    time_window: Option<(f64, f64)>,
+    rust_symbols: bool,
+    /// Interpreter frames (with their modules) held back until we know whether a
+    /// Python frame follows
+    held_frames: Vec<(ParsedFrame, String)>,
+    /// Whether the last frame pushed (ignoring held ones) was a Python frame
+    after_python: bool,
+    /// Rule whose collapse produced the last frame pushed
+    collapsing: Option<usize>,
+    /// Stack length to cut back to once the stack is complete (truncate rules)
+    truncate_at: Option<usize>,
     event_filter: String,
     event_defaulted: bool,
     event_warning_printed: bool,
-    inline_cache: HashMap<(String, String), String>,
+    inline_cache: HashMap<(String, String), InlineFrames>,
     nm_cache: HashMap<String, String>,
 }
 
@@ -110,13 +747,34 @@ impl<'a> Parser<'a> {
     fn new(opts: &'a Options) -> Self {
         Self {
             opts,
-            collapsed: HashMap::new(),
+            profile: Profile::default(),
+            per_cpu: None,
+            samples: None,
             stack: Vec::new(),
             pname: None,
             pid: String::new(),
             tid: String::new(),
+            comm: String::new(),
+            cpu: None,
+            time: None,
             period: 1,
-            event_filter: opts.event_filter.clone(),
+            event: String::new(),
+            trace_args: String::new(),
+            blocked: HashMap::new(),
+            wakers: HashMap::new(),
+            switch_outs: 0,
+            paired_switches: 0,
+            time_window: None,
+            rust_symbols: false,
+            held_frames: Vec::new(),
+            after_python: false,
+            collapsing: None,
+            truncate_at: None,
+            event_filter: if opts.off_cpu && opts.event_filter.is_empty() {
+                SCHED_SWITCH_EVENT.to_string()
+            } else {
+                opts.event_filter.clone()
+            },
             event_defaulted: false,
             event_warning_printed: false,
             inline_cache: HashMap::new(),
@@ -125,25 +783,167 @@ impl<'a> Parser<'a> {
     }
 
     fn remember_stack(&mut self) {
-        if self.stack.is_empty() {
+        // Held interpreter frames sit below the last Python frame, keep them
+        self.flush_held_frames();
+        if let Some(len) = self.truncate_at.take() {
+            self.stack.truncate(len);
+        }
+
+        // Off-CPU mode still needs stackless switches to pair switch-ins
+        if self.stack.is_empty() && !self.opts.off_cpu {
             return;
         }
 
         let mut full_stack = self.stack.clone();
         full_stack.reverse();
 
-        if self.opts.include_pname {
-            if let Some(ref pname) = self.pname {
-                full_stack.insert(0, pname.clone());
+        if self.opts.include_pname
+            && let Some(ref pname) = self.pname
+        {
+            full_stack.insert(0, self.profile.intern(Frame::named(pname.clone()), None));
+        }
+
+        // Noted once the filter has picked the frames that get drawn
+        let mut levels = Vec::new();
+        if let Some(mode) = self.opts.collapse_recursion {
+            let frames = &self.profile.frames;
+            (full_stack, levels) = fold_recursion(full_stack, mode, |&id| frames[id].name.as_str());
+        }
+
+        if self.opts.off_cpu && SCHED_WAKEUP_EVENTS.contains(&self.event.as_str()) {
+            self.remember_wakeup(full_stack);
+        } else if self.opts.off_cpu {
+            self.remember_switch(full_stack, levels);
+        } else {
+            self.record(full_stack, &levels, self.time, self.cpu, self.period, self.thread());
+        }
+    }
+
+    /// The thread of the current event.
+    fn thread(&self) -> Thread {
+        Thread { pid: self.pid.clone(), tid: self.tid.clone(), comm: self.comm.clone() }
+    }
+
+    /// Add a weighted stack to every output the parser is collecting.
+    ///
+    /// `levels` gives, for the leading frames of the stack, how many levels of
+    /// recursion `Options::collapse_recursion` folded into each.
+    fn record(&mut self, stack: Vec<FrameId>, levels: &[u64], time: Option<f64>, cpu: Option<u32>, weight: u64, thread: Thread) {
+        if !self.selected(time, cpu) {
+            return;
+        }
+
+        let range = if self.opts.filter.is_empty() {
+            0..stack.len()
+        } else {
+            let names: Vec<&str> = stack.iter().map(|&id| self.profile.name(id)).collect();
+            match self.opts.filter.select(&names) {
+                Some(range) => range,
+                None => return,
+            }
+        };
+
+        // Keyed by the path the viewer draws, so after --reroot and --prune
+        for (i, &n) in levels.iter().enumerate().take(range.end).skip(range.start) {
+            if n > 1 {
+                let Profile { frames, info, .. } = &mut self.profile;
+                let path: Vec<_> = stack[range.start..=i].iter().map(|&id| frames[id].folded_name()).collect();
+                let entry = info.recursion.entry(path.join(";")).or_insert(0);
+                *entry = (*entry).max(n);
             }
         }
 
-        let key = full_stack.join(";");
-        *self.collapsed.entry(key).or_insert(0) += self.period;
+        let stack = if range.len() == stack.len() { stack } else { stack[range].to_vec() };
+
+        if let (Some(per_cpu), Some(cpu)) = (self.per_cpu.as_mut(), cpu) {
+            *per_cpu.entry(cpu).or_default().entry(stack.clone()).or_insert(0) += weight;
+        }
+
+        if let Some(samples) = self.samples.as_mut() {
+            samples.push(&stack, time, weight, &thread);
+        }
+
+        *self.profile.stacks.entry(stack).or_insert(0) += weight;
+    }
+
+    /// Whether a sample at `time` on `cpu` passes the CPU filter and time window.
+    /// Samples without a cpu field or a timestamp never match them.
+    fn selected(&self, time: Option<f64>, cpu: Option<u32>) -> bool {
+        let cpu_ok = self.opts.cpu_filter.is_empty() || cpu.is_some_and(|cpu| self.opts.cpu_filter.contains(&cpu));
+        let time_ok = self.time_window.is_none_or(|(start, end)| time.is_some_and(|t| t >= start && t <= end));
+        cpu_ok && time_ok
+    }
+
+    /// Handle a `sched_switch` sample in off-CPU mode.
+    ///
+    /// The sampled stack belongs to the thread being switched out. It is held until
+    /// the same thread is switched back in, then recorded with the blocked time in
+    /// nanoseconds as its weight.
+    fn remember_switch(&mut self, mut stack: Vec<FrameId>, mut levels: Vec<u64>) {
+        let Some(time) = self.time else {
+            return;
+        };
+
+        if let Some(next_pid) = trace_field(&self.trace_args, "next_pid")
+            && let Some(blocked) = self.blocked.remove(next_pid)
+        {
+            self.paired_switches += 1;
+            let blocked_ns = ((time - blocked.time) * 1e9).round().max(0.0) as u64;
+            let stack = match self.wakers.remove(next_pid) {
+                Some(waker) if self.opts.off_wake => {
+                    let mut stack = blocked.stack;
+                    stack.push(self.profile.intern(Frame::named(OFF_WAKE_SEPARATOR), None));
+                    stack.extend(waker.iter().rev());
+                    stack
+                }
+                _ => blocked.stack,
+            };
+            if blocked_ns > 0 {
+                self.record(stack, &blocked.levels, Some(blocked.time), blocked.cpu, blocked_ns, blocked.thread);
+            }
+        }
+
+        // The idle task (pid 0) runs on every CPU, so its switches can't be paired
+        let prev_pid = trace_field(&self.trace_args, "prev_pid").unwrap_or(&self.tid);
+        if prev_pid == "0" {
+            return;
+        }
+
+        if stack.is_empty() {
+            return;
+        }
+
+        if let Some(state) = trace_field(&self.trace_args, "prev_state")
+            && self.opts.off_cpu_states
+        {
+            let state = Frame::named(format!("[{}]", blocked_state_name(state)));
+            stack.insert(0, self.profile.intern(state, None));
+            if !levels.is_empty() {
+                levels.insert(0, 1);
+            }
+        }
+
+        // Any wakeup seen before this switch-out belongs to an earlier sleep
+        self.wakers.remove(prev_pid);
+
+        let prev_pid = prev_pid.to_string();
+        let thread = self.thread();
+        self.switch_outs += 1;
+        self.blocked.insert(prev_pid, BlockedThread { stack, levels, time, cpu: self.cpu, thread });
+    }
+
+    /// Handle a `sched_wakeup` sample in off-wake mode: remember the waker's stack
+    /// until the woken thread is switched back in.
+    fn remember_wakeup(&mut self, stack: Vec<FrameId>) {
+        if let Some(target) = trace_field(&self.trace_args, "pid")
+            && !stack.is_empty()
+        {
+            self.wakers.insert(target.to_string(), stack);
+        }
     }
 
     /// Attempt to resolve inlined functions using addr2line.
-    fn resolve_inline(&mut self, pc: &str, rawfunc: &str, module: &str) -> Option<String> {
+    fn resolve_inline(&mut self, pc: &str, rawfunc: &str, module: &str) -> Option<InlineFrames> {
         let cache_key = (pc.to_string(), module.to_string());
         if let Some(cached) = self.inline_cache.get(&cache_key) {
             return if cached.is_empty() {
@@ -169,7 +969,7 @@ impl<'a> Parser<'a> {
                 self.inline_cache.insert(cache_key, result.clone());
                 return Some(result);
             }
-            self.inline_cache.insert(cache_key, String::new());
+            self.inline_cache.insert(cache_key, Vec::new());
             return None;
         }
 
@@ -179,29 +979,34 @@ impl<'a> Parser<'a> {
         while let Some(&func_line) = iter.next() {
             let func = func_line.trim();
             if let Some(&loc_line) = iter.next() {
+                let source = SourceLine::parse(loc_line);
                 let loc = loc_line.trim().replace(" (discriminator ", ":");
                 let loc = loc.trim_end_matches(')');
                 
                 if self.opts.show_context {
-                    funcs.push(format!("{}:{}", func, loc));
+                    funcs.push((format!("{}:{}", func, loc), source));
                 } else {
-                    funcs.push(func.to_string());
+                    funcs.push((func.to_string(), source));
                 }
             }
         }
 
         funcs.reverse();
-        let result = funcs.join(";");
-        self.inline_cache.insert(cache_key, result.clone());
+        if let [(func, _)] = &funcs[..]
+            && (func == "??" || func == "??:??:0")
+        {
+            funcs.clear();
+        }
+        self.inline_cache.insert(cache_key, funcs.clone());
 
-        if result.is_empty() || result == "??" || result == "??:??:0" {
+        if funcs.is_empty() {
             None
         } else {
-            Some(result)
+            Some(funcs)
         }
     }
 
-    fn try_nm_fallback(&mut self, pc: &str, rawfunc: &str, module: &str) -> Option<String> {
+    fn try_nm_fallback(&mut self, _pc: &str, rawfunc: &str, module: &str) -> Option<InlineFrames> {
         // Parse func+0xoffset format
         let parts: Vec<&str> = rawfunc.splitn(2, "+0x").collect();
         if parts.len() != 2 {
@@ -243,12 +1048,16 @@ impl<'a> Parser<'a> {
         // Find the position after comm and pid/tid
         let re_result = parse_event_header(line);
         
-        if let Some((comm, pid, tid, period, event)) = re_result {
+        if let Some(header) = re_result {
             // Event filtering
+            let is_wakeup = self.opts.off_cpu
+                && self.opts.off_wake
+                && SCHED_WAKEUP_EVENTS.contains(&header.event.as_str());
+
             if self.event_filter.is_empty() {
-                self.event_filter = event.clone();
+                self.event_filter = header.event.clone();
                 self.event_defaulted = true;
-            } else if event != self.event_filter {
+            } else if header.event != self.event_filter && !is_wakeup {
                 if self.event_defaulted && !self.event_warning_printed {
                     eprintln!("Filtering for events of type: {}", self.event_filter);
                     self.event_warning_printed = true;
@@ -256,16 +1065,27 @@ impl<'a> Parser<'a> {
                 return false;
             }
 
-            self.pid = pid;
-            self.tid = tid;
-            self.period = period;
+            // Off-CPU mode needs every switch to pair them up, and `record` filters
+            // the blocked records by where and when they blocked instead
+            if !self.opts.off_cpu && !self.selected(header.time, header.cpu) {
+                return false;
+            }
+
+            self.pid = header.pid;
+            self.tid = header.tid;
+            self.cpu = header.cpu;
+            self.time = header.time;
+            self.period = header.period;
+            self.event = header.event;
+            self.trace_args = header.trace_args;
+            self.comm = header.comm.clone();
 
             let pname = if self.opts.include_tid {
-                format!("{}-{}/{}", comm, self.pid, self.tid)
+                format!("{}-{}/{}", header.comm, self.pid, self.tid)
             } else if self.opts.include_pid {
-                format!("{}-{}", comm, self.pid)
+                format!("{}-{}", header.comm, self.pid)
             } else {
-                comm
+                header.comm
             };
 
             self.pname = Some(pname.replace(' ', "_"));
@@ -275,6 +1095,20 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Guess the language of a natively compiled frame from its symbol. JIT code
+    /// is left to the runtime-specific parsing.
+    fn native_language(&self, func: &str, module: &str) -> Option<Language> {
+        if is_jit_module(module) {
+            None
+        } else if self.rust_symbols && is_rust_frame(func) {
+            Some(Language::Rust)
+        } else if is_cpp_func(func) {
+            Some(Language::Cpp)
+        } else {
+            Some(Language::Native)
+        }
+    }
+
     fn parse_stack_line(&mut self, line: &str, next_line: Option<&str>) {
         // Parse lines like:
         // "        ffffffff8103ce3b native_safe_halt ([kernel.kallsyms])"
@@ -286,14 +1120,27 @@ impl<'a> Parser<'a> {
         let parts = parse_stack_frame(trimmed);
         
         if let Some((pc, rawfunc, module)) = parts {
+            let address = u64::from_str_radix(pc.trim_start_matches("0x"), 16).ok();
+
             // Try inline resolution if enabled
-            if self.opts.show_inline && !is_special_module(&module) {
-                if let Some(inline_result) = self.resolve_inline(&pc, &rawfunc, &module) {
-                    for func in inline_result.split(';') {
-                        self.stack.push(func.to_string());
-                    }
-                    return;
-                }
+            if self.opts.show_inline
+                && !is_special_module(&module)
+                && let Some(inline_result) = self.resolve_inline(&pc, &rawfunc, &module)
+            {
+                // addr2line lists the function holding the code first, then what it inlined
+                let frames = inline_result
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (func, source))| ParsedFrame {
+                        kind: if i > 0 { FrameKind::Inlined } else { frame_kind(&module) },
+                        language: self.native_language(&func, &module),
+                        func,
+                        source,
+                        address,
+                    })
+                    .collect();
+                self.push_frames(frames, &module, false, false);
+                return;
             }
 
             // Strip offset from function name (Linux 4.8+)
@@ -307,11 +1154,16 @@ impl<'a> Parser<'a> {
             // Process inline arrows (func1->func2->func3)
             let mut funcs = Vec::new();
             let mut is_unknown = false;
+            let mut is_python = false;
+            let is_interpreter = is_python_interpreter_func(&rawfunc);
+            let is_java = self.opts.tidy_java && self.pname.as_deref().is_some_and(is_java_process);
 
             for part in rawfunc.split("->") {
                 let mut func = part.to_string();
+                let unknown = func == "[unknown]";
+                let inlined = !funcs.is_empty();
 
-                if func == "[unknown]" {
+                if unknown {
                     is_unknown = true;
                     if module != "[unknown]" {
                         // Use module name instead
@@ -331,17 +1183,54 @@ impl<'a> Parser<'a> {
                     };
                 }
 
-                if self.opts.tidy_generic {
-                    func = tidy_generic_func(&func);
-                }
+                // Name to show in place of a shortened one
+                let mut full_name = None;
+                let mut source = None;
+                let mut tier = None;
+                let mut language = if unknown { None } else { self.native_language(&func, &module) };
 
-                if self.opts.tidy_java && self.pname.as_ref().map_or(false, |p| p.starts_with("java")) {
+                let runtime_frame = if self.opts.tidy_jit && is_jit_module(&module) {
+                    parse_runtime_frame(&func)
+                } else {
+                    None
+                };
+
+                if let Some(frame) = runtime_frame {
+                    is_python |= frame.python;
+                    language = Some(if frame.python { Language::Python } else { Language::JavaScript });
+                    if self.opts.jit_tiers {
+                        tier = Some(if frame.interpreted { "_[b]" } else { "_[j]" });
+                    }
+                    source = frame.source.and_then(SourceLine::parse);
+                    func = match frame.source {
+                        Some(source) if self.opts.jit_source => format!("{}:{}", frame.name, source),
+                        _ => frame.name.to_string(),
+                    };
+                } else if is_java && is_java_frame(&func, &module) {
+                    language = Some(Language::Java);
                     func = tidy_java_func(&func);
+                    if func == "Interpreter" {
+                        tier = Some("_[b]");
+                    }
+                } else if self.rust_symbols && is_rust_frame(&func) {
+                    let tidied = tidy_rust_func(&func, self.opts.rust_elide_generics);
+                    if self.opts.rust_elide_generics {
+                        full_name = Some(tidy_rust_func(&func, false));
+                    }
+                    func = tidied;
+                } else if self.opts.tidy_cpp && is_cpp_func(&func) {
+                    let tidied = tidy_cpp_func(&func, self.opts.template_depth);
+                    full_name = Some(std::mem::replace(&mut func, tidied));
+                } else if self.opts.tidy_generic {
+                    func = tidy_generic_func(&func);
                 }
+                let tidied_len = func.len();
 
                 // Annotations
-                if !funcs.is_empty() && !func.contains("_[i]") {
+                if inlined && !func.contains("_[i]") {
                     func.push_str("_[i]"); // inlined
+                } else if let Some(tier) = tier {
+                    func.push_str(tier);
                 } else if self.opts.annotate_kernel && is_kernel_module(&module) {
                     func.push_str("_[k]");
                 } else if self.opts.annotate_jit && is_jit_module(&module) && !func.contains("_[j]") {
@@ -349,24 +1238,134 @@ impl<'a> Parser<'a> {
                 }
 
                 // Source line handling
-                if self.opts.srcline_in_input && !is_unknown {
-                    if let Some(srcline) = next_line {
-                        let srcline = srcline.trim();
-                        // Remove bracketed parts and trim
-                        let srcline = remove_brackets(srcline);
-                        if !srcline.is_empty() {
-                            func = format!("{}:{}", func, srcline);
-                        }
+                if self.opts.srcline_in_input
+                    && !is_unknown
+                    && let Some(srcline) = next_line
+                {
+                    let srcline = srcline.trim();
+                    // Remove bracketed parts and trim
+                    let srcline = remove_brackets(srcline);
+                    if !srcline.is_empty() {
+                        source = SourceLine::parse(&srcline);
+                        func = format!("{}:{}", func, srcline);
                     }
                 }
 
-                funcs.push(func);
+                if let Some(full_name) = full_name.filter(|full| full[..] != func[..tidied_len]) {
+                    // Keep annotations and source lines on the full name too
+                    let full_name = format!("{}{}", full_name, &func[tidied_len..]);
+                    let name = func.replace(';', ":");
+                    self.profile.info.full_names.entry(name).or_insert(full_name);
+                }
+
+                let kind = if inlined {
+                    FrameKind::Inlined
+                } else if unknown {
+                    FrameKind::Unknown
+                } else {
+                    frame_kind(&module)
+                };
+                funcs.push(ParsedFrame { func, kind, language, source, address });
+            }
+
+            if is_java && self.opts.fold_jvm_internals && module.ends_with("libjvm.so") {
+                let folded = self.pname.as_deref().and_then(jvm_thread_frame).unwrap_or("[jvm]");
+                if self.stack.last().is_some_and(|&last| self.profile.name(last) == folded) {
+                    return;
+                }
+                funcs = vec![ParsedFrame {
+                    func: folded.to_string(),
+                    kind: FrameKind::User,
+                    language: Some(Language::Cpp),
+                    source: None,
+                    address: None,
+                }];
+            }
+
+            self.push_frames(funcs, &module, is_python, is_interpreter);
+        }
+    }
+
+    /// Push the functions of one frame (leaf first), holding back CPython
+    /// interpreter frames until the next frame shows whether they sit between
+    /// two Python frames.
+    fn push_frames(&mut self, frames: Vec<ParsedFrame>, module: &str, is_python: bool, is_interpreter: bool) {
+        if self.opts.hide_interpreter_frames {
+            if is_interpreter && self.after_python {
+                self.held_frames.extend(frames.into_iter().map(|frame| (frame, module.to_string())));
+                return;
+            }
+            if is_python {
+                self.held_frames.clear();
+            } else {
+                self.flush_held_frames();
             }
+            self.after_python = is_python;
+        }
+        for frame in frames {
+            self.push_frame(frame, module);
+        }
+    }
+
+    fn flush_held_frames(&mut self) {
+        for (frame, module) in std::mem::take(&mut self.held_frames) {
+            self.push_frame(frame, &module);
+        }
+    }
+
+    /// Push a single function onto the stack, applying the user's rules.
+    fn push_frame(&mut self, frame: ParsedFrame, module: &str) {
+        let opts = self.opts;
+        let (func, matched) = if opts.rules.is_empty() {
+            (frame.func, None)
+        } else {
+            opts.rules.apply(frame.func, module)
+        };
+        if let Some((_, Action::Drop)) = matched {
+            return;
+        }
+
+        let func = match matched {
+            Some((_, Action::Collapse(Some(name)))) => name.clone(),
+            _ => func,
+        };
+        let module = (!module.is_empty() && module != "[unknown]").then(|| module.to_string());
+        let id = self.profile.intern(
+            Frame {
+                name: func,
+                module,
+                kind: Some(frame.kind),
+                language: frame.language,
+                source: frame.source,
+            },
+            frame.address,
+        );
 
-            for func in funcs {
-                self.stack.push(func);
+        match matched {
+            None => self.stack.push(id),
+            Some((_, Action::Drop)) => unreachable!("dropped frames return early"),
+            Some((rule, Action::Collapse(_))) => {
+                // Stacks arrive leaf first, so the latest frame is the outermost
+                match self.stack.last_mut() {
+                    Some(last) if self.collapsing == Some(rule) => *last = id,
+                    _ => self.stack.push(id),
+                }
+            }
+            Some((_, Action::Truncate)) => {
+                self.stack.push(id);
+                self.truncate_at = Some(self.stack.len());
             }
+            Some((_, Action::Merge)) => {
+                self.stack.clear();
+                self.stack.push(id);
+                self.truncate_at = None;
+            }
+            Some((_, Action::Rename(_))) => unreachable!("renames are applied by Rules::apply"),
         }
+        self.collapsing = match matched {
+            Some((rule, Action::Collapse(_))) => Some(rule),
+            _ => None,
+        };
     }
 
     fn finish(&mut self) {
@@ -374,18 +1373,169 @@ impl<'a> Parser<'a> {
         if self.pname.is_some() && !self.stack.is_empty() {
             self.remember_stack();
         }
+
+        if self.only_switch_outs() {
+            eprintln!(
+                "Warning: none of {} sched_switch switch-outs was followed by a switch-in of the same thread; \
+                 a switch-in is logged by the task it replaces, so record system-wide \
+                 (perf record -a -g -e sched:sched_switch ...)",
+                self.switch_outs
+            );
+        }
     }
-}
 
-/// Parse an event header line, returning (comm, pid, tid, period, event).
-fn parse_event_header(line: &str) -> Option<(String, String, String, u64, String)> {
-    // This is a simplified parser for the common formats
-    // Looking for patterns like:
-    //   comm pid[/tid] [cpu] time: [period] event:
+    /// Whether off-CPU mode saw threads switched out but never back in, which is
+    /// what a recording limited to some tasks looks like.
+    fn only_switch_outs(&self) -> bool {
+        self.opts.off_cpu && self.switch_outs > 0 && self.paired_switches == 0
+    }
 
-    let line = line.trim();
+    /// Feed a whole `perf script` output through the parser.
+    fn run(&mut self, input: &str) {
+        if self.opts.start.is_some() || self.opts.end.is_some() {
+            self.time_window = resolve_time_window(input, self.opts);
+        }
+        self.rust_symbols = self.opts.tidy_rust && has_rust_symbols(input);
+
+        let lines: Vec<&str> = input.lines().collect();
+        let mut i = 0;
     
-    // Find the colon that ends the event type
+        while i < lines.len() {
+            let line = lines[i];
+        
+            // Skip comments
+            if line.starts_with('#') {
+                i += 1;
+                continue;
+            }
+        
+            // Empty line: end of stack
+            if line.trim().is_empty() {
+                if self.pname.is_some() {
+                    self.remember_stack();
+                }
+                self.stack.clear();
+                self.held_frames.clear();
+                self.after_python = false;
+                self.collapsing = None;
+                self.truncate_at = None;
+                self.pname = None;
+                i += 1;
+                continue;
+            }
+        
+            // Try to parse as event record
+            if !line.starts_with(char::is_whitespace) {
+                if !self.parse_event_record(line) {
+                    // Filtered out, skip until next blank line
+                    self.pname = None;
+                }
+                i += 1;
+                continue;
+            }
+        
+            // Stack line
+            if self.pname.is_some() {
+                // perf prints no source line for frames it can't resolve, such as
+                // kernel ones, so the next line may already be the caller
+                let next_line = lines
+                    .get(i + 1)
+                    .copied()
+                    .filter(|next| self.opts.srcline_in_input && is_srcline(next));
+            
+                self.parse_stack_line(line, next_line);
+            
+                // Skip the srcline if we processed it
+                if self.opts.srcline_in_input && next_line.is_some() {
+                    i += 1;
+                }
+            }
+        
+            i += 1;
+        }
+        
+        self.finish();
+    }
+}
+
+/// Fold recursion out of a stack (root first), taking frames with the same `key`
+/// as the same function. Returns the folded stack and, for each of its frames, the
+/// levels folded into it (1 for a frame that absorbed no repeats).
+fn fold_recursion<T, K: PartialEq>(stack: Vec<T>, mode: Recursion, key: impl Fn(&T) -> K) -> (Vec<T>, Vec<u64>) {
+    let mut kept: Vec<T> = Vec::with_capacity(stack.len());
+    let mut levels: Vec<u64> = Vec::with_capacity(stack.len());
+
+    for frame in stack {
+        let seen = match mode {
+            Recursion::Direct => kept.last().is_some_and(|last| key(last) == key(&frame)).then(|| kept.len() - 1),
+            Recursion::All => kept.iter().position(|f| key(f) == key(&frame)),
+        };
+        match seen {
+            Some(i) => {
+                // Calls made between the two occurrences are part of the cycle
+                kept.truncate(i + 1);
+                levels.truncate(i + 1);
+                levels[i] += 1;
+            }
+            None => {
+                kept.push(frame);
+                levels.push(1);
+            }
+        }
+    }
+
+    (kept, levels)
+}
+
+/// Fields parsed from a `perf script` event header line.
+#[derive(Debug, Clone, PartialEq)]
+struct EventHeader {
+    comm: String,
+    pid: String,
+    tid: String,
+    /// CPU the sample was taken on, when `perf script` printed the `[cpu]` field
+    cpu: Option<u32>,
+    /// Sample timestamp in seconds
+    time: Option<f64>,
+    period: u64,
+    event: String,
+    /// Tracepoint payload printed after the event name (empty for sampling events)
+    trace_args: String,
+}
+
+/// Split a header line after the event name, returning (header, tracepoint payload).
+///
+/// Tracepoint events print their fields after the event name and those may contain
+/// colons themselves, e.g. `... 1.0: sched:sched_switch: prev_comm=a ==> next_comm=kworker/0:1`.
+fn split_trace_args(line: &str) -> (&str, &str) {
+    let mut pos = 0;
+    let mut seen_time = false;
+
+    for token in line.split_whitespace() {
+        let start = pos + line[pos..].find(token).unwrap_or(0);
+        pos = start + token.len();
+
+        if !seen_time {
+            seen_time = token
+                .strip_suffix(':')
+                .is_some_and(|t| t.contains('.') && t.parse::<f64>().is_ok());
+        } else if token.ends_with(':') {
+            return (&line[..pos], line[pos..].trim());
+        }
+    }
+
+    (line, "")
+}
+
+/// Parse an event header line.
+fn parse_event_header(line: &str) -> Option<EventHeader> {
+    // This is a simplified parser for the common formats
+    // Looking for patterns like:
+    //   comm pid[/tid] [cpu] time: [period] event: [trace args]
+
+    let (line, trace_args) = split_trace_args(line.trim());
+    
+    // Find the colon that ends the event type
     let event_end = line.rfind(':')?;
     let before_event_colon = &line[..event_end];
     
@@ -419,7 +1569,7 @@ fn parse_event_header(line: &str) -> Option<(String, String, String, u64, String
     let mut pid = String::from("?");
     let mut tid = String::from("?");
     
-    for part in parts {
+    for part in parts.by_ref() {
         // Check if this looks like pid or pid/tid
         if let Some((p, t)) = part.split_once('/') {
             if p.chars().all(|c| c.is_ascii_digit()) {
@@ -451,8 +1601,83 @@ fn parse_event_header(line: &str) -> Option<(String, String, String, u64, String
     if comm.is_empty() {
         return None;
     }
+
+    // Whatever follows pid/tid is the optional [cpu] and the timestamp
+    let mut cpu = None;
+    let mut time = None;
+    for part in parts {
+        if let Some(inner) = part.strip_prefix('[').and_then(|p| p.strip_suffix(']')) {
+            cpu = inner.parse::<u32>().ok();
+        } else if let Ok(t) = part.trim_end_matches(':').parse::<f64>() {
+            time = Some(t);
+        }
+    }
     
-    Some((comm, pid, tid, period, event))
+    Some(EventHeader {
+        comm,
+        pid,
+        tid,
+        cpu,
+        time,
+        period,
+        event,
+        trace_args: trace_args.to_string(),
+    })
+}
+
+/// Look up a `name=value` field in a tracepoint payload.
+fn trace_field<'a>(args: &'a str, name: &str) -> Option<&'a str> {
+    let mut search = args;
+    while let Some(idx) = search.find(name) {
+        let after = &search[idx + name.len()..];
+        let at_boundary = idx == 0 || search[..idx].ends_with(' ');
+        if at_boundary && let Some(value) = after.strip_prefix('=') {
+            return Some(value.split_whitespace().next().unwrap_or(""));
+        }
+        search = after;
+    }
+    None
+}
+
+/// Human readable name for a `sched_switch` `prev_state` value.
+fn blocked_state_name(state: &str) -> String {
+    match state.chars().next() {
+        Some('S') => "sleep".to_string(),
+        Some('D') => "io".to_string(),
+        Some('R') => "preempted".to_string(),
+        _ => format!("state {}", state),
+    }
+}
+
+/// Find the timestamps of the first and last sample in `perf script` output.
+pub fn sample_time_range(input: &str) -> Option<(f64, f64)> {
+    input
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.starts_with(char::is_whitespace))
+        .filter_map(|line| parse_event_header(line)?.time)
+        .fold(None, |range, t| match range {
+            None => Some((t, t)),
+            Some((lo, hi)) => Some((f64::min(lo, t), f64::max(hi, t))),
+        })
+}
+
+/// Turn the `start`/`end` options into an absolute timestamp window.
+fn resolve_time_window(input: &str, opts: &Options) -> Option<(f64, f64)> {
+    let Some((first, last)) = sample_time_range(input) else {
+        eprintln!("No sample timestamps found; ignoring time window (use `perf script -F+time`)");
+        return None;
+    };
+
+    let start = opts.start.map_or(f64::NEG_INFINITY, |b| b.resolve(first, last));
+    let end = opts.end.map_or(f64::INFINITY, |b| b.resolve(first, last));
+    Some((start, end))
+}
+
+/// Whether a line under a stack frame is its source line rather than the next frame
+/// or the blank line ending the sample.
+fn is_srcline(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && parse_stack_frame(line).is_none()
 }
 
 /// Parse a stack frame line, returning (pc, rawfunc, module).
@@ -489,16 +1714,16 @@ fn strip_offset(func: &str) -> String {
 
 /// Clean up generic function names.
 fn tidy_generic_func(func: &str) -> String {
-    let mut result = func.replace(';', ":");
+    let mut result = func.to_string();
     
     // Remove template/generic parameters unless it looks like a Go method
     if !result.contains(".(") || !result.contains(").") {
         // Not a Go method, remove everything after first paren
         // (unless it's "anonymous namespace")
-        if let Some(idx) = result.find('(') {
-            if !result[idx..].starts_with("(anonymous namespace)") {
-                result = result[..idx].to_string();
-            }
+        if let Some(idx) = result.find('(')
+            && !result[idx..].starts_with("(anonymous namespace)")
+        {
+            result = result[..idx].to_string();
         }
     }
     
@@ -508,18 +1733,437 @@ fn tidy_generic_func(func: &str) -> String {
     result
 }
 
-/// Clean up Java function names.
-fn tidy_java_func(func: &str) -> String {
-    let mut result = func.to_string();
-    
-    // Remove leading 'L' from class names that contain '/'
-    if result.starts_with('L') && result.contains('/') {
-        result = result[1..].to_string();
+/// Check whether any stack frame in `perf script` output carries a Rust symbol.
+fn has_rust_symbols(input: &str) -> bool {
+    input
+        .lines()
+        .filter(|line| line.starts_with(char::is_whitespace))
+        .filter_map(|line| parse_stack_frame(line.trim()))
+        .any(|(_, rawfunc, _)| is_rust_symbol(&strip_offset(&rawfunc)))
+}
+
+/// Check if a function name is recognisably Rust: a v0 or legacy mangling, a
+/// demangled name still carrying its `::h<hash>` suffix, or a closure.
+fn is_rust_symbol(func: &str) -> bool {
+    if func.starts_with("_R") || func.starts_with("_ZN") {
+        // Legacy manglings are only told apart from C++ by their hash
+        return rustc_demangle::try_demangle(func).is_ok_and(|d| {
+            func.starts_with("_R") || strip_rust_hash(&d.to_string()).is_some()
+        });
     }
-    
+    strip_rust_hash(func).is_some() || func.contains("{{closure}}") || func.contains("{closure#")
+}
+
+/// Once a profile is known to contain Rust, decide whether a frame should get Rust
+/// tidying. Demangled Rust paths rarely carry a `(`, unlike C/C++ signatures, but
+/// perf also prints C++ names without their parameters, so those are told apart by
+/// what only C++ writes.
+fn is_rust_frame(func: &str) -> bool {
+    is_rust_symbol(func) || (func.contains("::") && !func.contains('(') && !has_cpp_markers(func))
+}
+
+/// C++ builtin types, which Rust spells differently (`i32`, `u64`, `f64`, `()`).
+const CPP_BUILTIN_TYPES: [&str; 8] = ["int", "long", "short", "unsigned", "signed", "float", "double", "void"];
+
+/// Whether a name has something Rust paths never do: operators, destructors, ABI
+/// tags, lambdas, libstdc++/libc++ internals, `> >`, or C++ builtin types among
+/// template arguments.
+fn has_cpp_markers(func: &str) -> bool {
+    if ["operator", "::~", "[abi:", "{lambda", "std::__", "> >"].iter().any(|m| func.contains(m)) {
+        return true;
+    }
+    let Some(args) = func.find('<').map(|open| &func[open..]) else {
+        return false;
+    };
+    args.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .any(|word| CPP_BUILTIN_TYPES.contains(&word))
+}
+
+/// Remove a trailing `::h<16 hex digits>` hash, if present.
+fn strip_rust_hash(func: &str) -> Option<&str> {
+    let (path, hash) = func.rsplit_once("::h")?;
+    (hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some(path)
+}
+
+/// Clean up Rust function names: demangle, drop hashes and normalize closures.
+fn tidy_rust_func(func: &str, elide_generics: bool) -> String {
+    let mut result = match rustc_demangle::try_demangle(func) {
+        // The alternate format leaves out hashes and crate disambiguators
+        Ok(demangled) => format!("{:#}", demangled),
+        Err(_) => func.to_string(),
+    };
+
+    if let Some(path) = strip_rust_hash(&result) {
+        result.truncate(path.len());
+    }
+
+    // v0 manglings number closures (`{closure#0}`), legacy ones don't
+    while let Some(start) = result.find("{closure#") {
+        let Some(len) = result[start..].find('}') else { break };
+        result.replace_range(start..start + len + 1, "{{closure}}");
+    }
+
+    if elide_generics {
+        result = elide_generic_args(&result, 0);
+    }
+
+    result
+}
+
+/// Replace generic argument lists nested deeper than `max_depth` with `<…>`.
+///
+/// Only brackets following a name count (`Vec<u8>`, `collect::<T>`); leading
+/// qualified paths such as `<T as Trait>::f` are kept.
+fn elide_generic_args(name: &str, max_depth: usize) -> String {
+    let mut result = String::with_capacity(name.len());
+    // For each open bracket, whether it starts a generic argument list
+    let mut open: Vec<bool> = Vec::new();
+    let mut depth = 0;
+    // Number of open brackets when output was suppressed
+    let mut hidden_at: Option<usize> = None;
+    // `open.len()` at each open parenthesis: a `>` directly inside one is a comparison
+    let mut parens: Vec<usize> = Vec::new();
+    let mut prev: Option<char> = None;
+
+    for (i, c) in name.char_indices() {
+        // `operator<`, `operator>>=` and friends are names, not brackets
+        let operator = name[..i]
+            .trim_end_matches(['<', '>', '='])
+            .ends_with("operator");
+        match c {
+            '<' | '>' if operator && hidden_at.is_none() => result.push(c),
+            '<' | '>' if operator => {}
+            '<' => {
+                let generic = prev.is_some_and(|p| p.is_alphanumeric() || p == '_' || p == ':');
+                if generic {
+                    depth += 1;
+                }
+                if hidden_at.is_none() {
+                    result.push('<');
+                    if generic && depth > max_depth {
+                        result.push('…');
+                        hidden_at = Some(open.len());
+                    }
+                }
+                open.push(generic);
+            }
+            '(' => {
+                parens.push(open.len());
+                if hidden_at.is_none() {
+                    result.push(c);
+                }
+            }
+            ')' => {
+                // Brackets left open inside the parentheses were comparisons
+                let outer = parens.pop().unwrap_or(0);
+                while open.len() > outer {
+                    if open.pop() == Some(true) {
+                        depth -= 1;
+                    }
+                }
+                if hidden_at.is_some_and(|at| at >= open.len()) {
+                    hidden_at = None;
+                }
+                if hidden_at.is_none() {
+                    result.push(c);
+                }
+            }
+            // `->` in fn pointer types is not a closing bracket
+            '>' if prev != Some('-') && open.len() > parens.last().copied().unwrap_or(0) => {
+                if open.pop() == Some(true) {
+                    depth -= 1;
+                }
+                if hidden_at == Some(open.len()) {
+                    hidden_at = None;
+                }
+                if hidden_at.is_none() {
+                    result.push('>');
+                }
+            }
+            _ if hidden_at.is_none() => result.push(c),
+            _ => {}
+        }
+        prev = Some(c);
+    }
+
+    result
+}
+
+/// Check if a function name looks like a demangled C++ name (and not a Go method).
+fn is_cpp_func(func: &str) -> bool {
+    (func.contains("::") || func.contains("operator") || func.contains("[abi:"))
+        && !(func.contains(".(") && func.contains(")."))
+}
+
+/// Clean up C++ function names: drop the return type, parameter list, qualifiers
+/// and ABI tags, shorten lambdas and collapse templates nested deeper than `template_depth`.
+fn tidy_cpp_func(func: &str, template_depth: usize) -> String {
+    let mut result = func.replace(['"', '\''], "");
+
+    while let Some(start) = result.find("[abi:") {
+        let Some(len) = result[start..].find(']') else { break };
+        result.replace_range(start..start + len + 1, "");
+    }
+
+    // Inline namespaces of libc++ and libstdc++
+    result = result.replace("std::__1::", "std::").replace("std::__cxx11::", "std::");
+
+    result = strip_cpp_signature(&result);
+
+    // `{lambda(int)#1}` -> `{lambda#1}`
+    let mut from = 0;
+    while let Some(pos) = result[from..].find("{lambda(") {
+        let open = from + pos + "{lambda".len();
+        match matching_paren(&result[open..]) {
+            Some(close) => result.replace_range(open..open + close + 1, ""),
+            None => break,
+        }
+        from = open;
+    }
+
+    // A lambda's call operator is named after the lambda itself
+    if let Some(owner) = result.strip_suffix("::operator()") {
+        let last = owner.rsplit("::").next().unwrap_or(owner);
+        if last.starts_with("{lambda") || last.starts_with("$_") {
+            result.truncate(owner.len());
+        }
+    }
+
+    let result = elide_generic_args(&result, template_depth);
+    // Better an untidy name than an empty frame
+    if result.is_empty() { func.to_string() } else { result }
+}
+
+/// Strip a demangled C++ signature down to the qualified function name.
+///
+/// Parameter lists of enclosing functions (`foo(int)::{lambda()#1}`) are dropped too.
+fn strip_cpp_signature(sig: &str) -> String {
+    let mut result = String::with_capacity(sig.len());
+    // Open brackets; a `>` only closes a `<`, so `Bar<(1>2)>` stays balanced
+    let mut open: Vec<char> = Vec::new();
+    let mut i = 0;
+
+    while let Some(c) = sig[i..].chars().next() {
+        let rest = &sig[i..];
+
+        if let Some(len) = cpp_operator_len(sig, i) {
+            result.push_str(&rest[..len]);
+            i += len;
+            continue;
+        }
+
+        if open.is_empty() && c == '(' && !rest.starts_with("(anonymous namespace)") {
+            match matching_paren(rest) {
+                Some(close) if rest[close + 1..].starts_with("::") => {
+                    i += close + 1;
+                    continue;
+                }
+                // A function returning a function pointer: `int (*foo(char))(int)`
+                Some(close) if result.is_empty() && (rest[1..].starts_with('*') || rest[1..].starts_with('&')) => {
+                    return strip_cpp_signature(&rest[2..close]);
+                }
+                _ => break,
+            }
+        }
+
+        match c {
+            '<' | '(' | '{' | '[' => open.push(c),
+            '>' if result.ends_with('-') || open.last() != Some(&'<') => {}
+            '>' => {
+                open.pop();
+            }
+            // Unclosed `<`s inside parentheses were comparisons
+            ')' | '}' | ']' => {
+                let opener = match c { ')' => '(', '}' => '{', _ => '[' };
+                if open.contains(&opener) {
+                    while open.pop().is_some_and(|o| o != opener) {}
+                }
+            }
+            // Everything before a top-level space is the return type, except
+            // in `operator< <int>`
+            ' ' if open.is_empty() && !result.trim_end_matches(|c| "<>=!+-*/%^&|~,".contains(c)).ends_with("operator") => {
+                result.clear();
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        result.push(c);
+        i += c.len_utf8();
+    }
+
     result
 }
 
+/// Length of the C++ operator name (`operator()`, `operator<<`, `operator new[]`,
+/// `operator bool`, ...) starting at byte `i`, if there is one.
+fn cpp_operator_len(s: &str, i: usize) -> Option<usize> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let rest = s[i..].strip_prefix("operator")?;
+    if s[..i].chars().next_back().is_some_and(is_ident) || rest.starts_with(is_ident) {
+        return None;
+    }
+
+    let op = if rest.starts_with("()") || rest.starts_with("[]") {
+        2
+    } else if let Some(word) = rest.strip_prefix(' ') {
+        if word.starts_with("new") || word.starts_with("delete") {
+            let len = if word.starts_with("new") { 4 } else { 7 };
+            len + if rest[len..].starts_with("[]") { 2 } else { 0 }
+        } else {
+            // Conversion operator: the type runs up to the parameter list
+            rest.find('(').unwrap_or(rest.len())
+        }
+    } else {
+        rest.find(|c: char| !"<>=!+-*/%^&|~,".contains(c)).unwrap_or(rest.len())
+    };
+
+    Some("operator".len() + op)
+}
+
+/// Byte index of the `)` closing the `(` that `s` starts with.
+fn matching_paren(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A Python or Node frame from a perf map, with the runtime's markers removed.
+#[derive(Debug, PartialEq)]
+struct RuntimeFrame<'a> {
+    name: &'a str,
+    /// `file` for Python, `file:line` for Node
+    source: Option<&'a str>,
+    /// Running in the bytecode interpreter rather than compiled code
+    interpreted: bool,
+    python: bool,
+}
+
+/// Code kinds that V8 prefixes JavaScript functions with in `--perf-basic-prof` maps.
+const V8_CODE_KINDS: [&str; 5] = ["LazyCompile:", "Function:", "JS:", "Script:", "Eval:"];
+
+/// Parse a perf map symbol written by CPython 3.12+ (`py::func:/srv/app.py`) or
+/// V8 (`LazyCompile:*parse /srv/x.js:10`).
+fn parse_runtime_frame(func: &str) -> Option<RuntimeFrame<'_>> {
+    if let Some(rest) = func.strip_prefix("py::") {
+        let (name, source) = match rest.split_once(':') {
+            Some((name, source)) => (name, Some(source)),
+            None => (rest, None),
+        };
+        return Some(RuntimeFrame { name, source, interpreted: true, python: true });
+    }
+
+    let rest = V8_CODE_KINDS.iter().find_map(|kind| func.strip_prefix(kind))?;
+    // Tier markers: `~` Ignition (interpreted), `^` Sparkplug, `+` Maglev, `*` TurboFan
+    let interpreted = rest.starts_with('~');
+    let rest = rest.trim_start_matches(['~', '^', '+', '*']);
+    let (name, source) = match rest.rsplit_once(' ') {
+        Some((name, source)) => (name.trim(), Some(source)),
+        None => (rest, None),
+    };
+    let name = if name.is_empty() { "(anonymous)" } else { name };
+    Some(RuntimeFrame { name, source, interpreted, python: false })
+}
+
+/// CPython functions that run Python code on behalf of other Python code.
+const PYTHON_INTERPRETER_FUNCS: [&str; 9] = [
+    "_PyEval_",
+    "PyEval_",
+    "_PyFunction_",
+    "_PyObject_Call",
+    "PyObject_Call",
+    "_PyObject_Vectorcall",
+    "PyObject_Vectorcall",
+    "method_vectorcall",
+    "py_trampoline_",
+];
+
+/// Check if a function is part of the CPython interpreter loop.
+fn is_python_interpreter_func(func: &str) -> bool {
+    PYTHON_INTERPRETER_FUNCS.iter().any(|prefix| func.starts_with(prefix))
+}
+
+/// Thread names (spaces written as `_`) the JVM gives its own threads, with the
+/// frame their JVM frames fold into.
+const JVM_THREADS: [(&str, &str); 10] = [
+    ("GC_Thread", "[jvm gc]"),
+    ("G1_", "[jvm gc]"),
+    ("ParGC_Thread", "[jvm gc]"),
+    ("ZWorker", "[jvm gc]"),
+    ("ZDriver", "[jvm gc]"),
+    ("Shenandoah", "[jvm gc]"),
+    ("C1_CompilerThre", "[jvm compiler]"),
+    ("C2_CompilerThre", "[jvm compiler]"),
+    ("VM_Thread", "[jvm]"),
+    ("VM_Periodic_Tas", "[jvm]"),
+];
+
+/// Frame that JVM frames on a JVM-internal thread fold into.
+fn jvm_thread_frame(pname: &str) -> Option<&'static str> {
+    JVM_THREADS
+        .iter()
+        .find(|(thread, _)| pname.starts_with(thread))
+        .map(|&(_, frame)| frame)
+}
+
+/// Check if a process name belongs to a JVM, including its internal threads.
+fn is_java_process(pname: &str) -> bool {
+    pname.starts_with("java") || jvm_thread_frame(pname).is_some()
+}
+
+/// Check if a frame in a Java process is Java code from a perf map (or at least
+/// looks like a JVM class descriptor).
+fn is_java_frame(func: &str, module: &str) -> bool {
+    is_jit_module(module) || (func.starts_with('L') && func.contains('/') && func.contains(';'))
+}
+
+/// Clean up Java function names from perf-map-agent and the JDK's own perf maps:
+/// `Lcom/example/Foo;::bar(Ljava/lang/String;)V` becomes `com.example.Foo.bar`.
+fn tidy_java_func(func: &str) -> String {
+    // Drop the signature
+    let name = func.split('(').next().unwrap_or(func).trim();
+
+    let (class, method) = match name.rsplit_once("::") {
+        Some((class, method)) => (class, Some(method)),
+        None => (name, None),
+    };
+
+    // `Lcom/example/Foo;` class descriptors
+    let mut class = match class.strip_prefix('L') {
+        Some(inner) if class.contains('/') || class.ends_with(';') => inner.trim_end_matches(';'),
+        _ => class,
+    }
+    .replace('/', ".");
+
+    // `Foo$$Lambda$14/0x0000000800c03000` (the `/` is now a `.`) -> `Foo$$Lambda`
+    if let Some(pos) = class.find("$$Lambda") {
+        class.truncate(pos + "$$Lambda".len());
+    }
+
+    match method {
+        Some(method) => {
+            // `lambda$main$0` -> `lambda$main`
+            let method = match method.rsplit_once('$') {
+                Some((base, n)) if method.starts_with("lambda$") && n.chars().all(|c| c.is_ascii_digit()) => base,
+                _ => method,
+            };
+            format!("{}.{}", class, method)
+        }
+        None => class,
+    }
+}
+
 /// Check if module indicates kernel code.
 fn is_kernel_module(module: &str) -> bool {
     (module.starts_with('[') || module.ends_with("vmlinux")) && !module.contains("unknown")
@@ -538,6 +2182,17 @@ fn is_special_module(module: &str) -> bool {
         || (module.starts_with('[') && module.ends_with(']'))
 }
 
+/// Kind of code a symbolized, not inlined frame runs, going by its module.
+fn frame_kind(module: &str) -> FrameKind {
+    if is_kernel_module(module) {
+        FrameKind::Kernel
+    } else if is_jit_module(module) {
+        FrameKind::Jit
+    } else {
+        FrameKind::User
+    }
+}
+
 /// Remove bracketed portions from a string.
 fn remove_brackets(s: &str) -> String {
     let mut result = String::new();
@@ -555,80 +2210,73 @@ fn remove_brackets(s: &str) -> String {
     result.trim().to_string()
 }
 
-/// Collapse perf script output into folded stacks.
-///
-/// # Arguments
-///
-/// * `input` - The perf script output as a string
-/// * `opts` - Configuration options
+/// Collapse perf script output, keeping each frame's module, kind, language,
+/// source line and address.
+pub fn collapse_perf_profile(input: &str, opts: &Options) -> Profile {
+    let mut parser = Parser::new(opts);
+    parser.run(input);
+    parser.profile
+}
+
+/// Collapse perf script output into one profile per CPU.
 ///
-/// # Returns
+/// Samples without a `[cpu]` field in their event header are left out, so the
+/// input should come from `perf script -F+cpu` (the default for system-wide recordings).
+/// The profiles share one frame table.
+pub fn collapse_perf_per_cpu(input: &str, opts: &Options) -> BTreeMap<u32, Profile> {
+    let mut parser = Parser::new(opts);
+    parser.per_cpu = Some(BTreeMap::new());
+    parser.run(input);
+    let profile = parser.profile;
+    parser
+        .per_cpu
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(cpu, stacks)| (cpu, profile.with_stacks(stacks)))
+        .collect()
+}
+
+/// Collapse perf script output, keeping every sample with its timestamp.
 ///
-/// A `CollapsedStacks` map from folded stack strings to their counts.
-pub fn collapse_perf(input: &str, opts: &Options) -> CollapsedStacks {
+/// `SampleTable::profile` holds the equivalent of `collapse_perf_profile`.
+pub fn collapse_perf_samples(input: &str, opts: &Options) -> SampleTable {
     let mut parser = Parser::new(opts);
-    let lines: Vec<&str> = input.lines().collect();
-    let mut i = 0;
-    
-    while i < lines.len() {
-        let line = lines[i];
-        
-        // Skip comments
-        if line.starts_with('#') {
-            i += 1;
-            continue;
-        }
-        
-        // Empty line: end of stack
-        if line.trim().is_empty() {
-            if parser.pname.is_some() {
-                parser.remember_stack();
-            }
-            parser.stack.clear();
-            parser.pname = None;
-            i += 1;
-            continue;
-        }
-        
-        // Try to parse as event record
-        if !line.starts_with(char::is_whitespace) {
-            if !parser.parse_event_record(line) {
-                // Filtered out, skip until next blank line
-                parser.pname = None;
-            }
-            i += 1;
-            continue;
-        }
-        
-        // Stack line
-        if parser.pname.is_some() {
-            let next_line = if opts.srcline_in_input && i + 1 < lines.len() {
-                Some(lines[i + 1])
-            } else {
-                None
-            };
-            
-            parser.parse_stack_line(line, next_line);
-            
-            // Skip the srcline if we processed it
-            if opts.srcline_in_input && next_line.is_some() {
-                i += 1;
+    parser.samples = Some(SampleTable::default());
+    parser.run(input);
+    let mut table = parser.samples.unwrap_or_default();
+    table.profile = parser.profile;
+    table
+}
+
+/// Parse a CPU list such as `0-3,8` into the individual CPU numbers.
+pub fn parse_cpu_list(spec: &str) -> Result<Vec<u32>, String> {
+    let mut cpus = Vec::new();
+
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid CPU number '{}' in '{}'", s, spec))
+        };
+
+        if let Some((lo, hi)) = part.split_once('-') {
+            let (lo, hi) = (parse(lo)?, parse(hi)?);
+            if lo > hi {
+                return Err(format!("invalid CPU range '{}'", part));
             }
+            cpus.extend(lo..=hi);
+        } else {
+            cpus.push(parse(part)?);
         }
-        
-        i += 1;
     }
-    
-    parser.finish();
-    parser.collapsed
-}
 
-/// Collapse perf script output from a reader.
-pub fn collapse_perf_reader<R: Read>(reader: R, opts: &Options) -> CollapsedStacks {
-    let mut content = String::new();
-    let mut buf_reader = BufReader::new(reader);
-    buf_reader.read_to_string(&mut content).unwrap_or(0);
-    collapse_perf(&content, opts)
+    if cpus.is_empty() {
+        return Err("empty CPU list".to_string());
+    }
+
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
 }
 
 /// Format collapsed stacks as output string (sorted by stack name).
@@ -647,7 +2295,12 @@ pub fn format_collapsed(collapsed: &CollapsedStacks) -> String {
 mod tests {
     use super::*;
 
-    #[test]
+    /// The first frame of a profile with the given name.
+    fn frame<'a>(profile: &'a Profile, name: &str) -> &'a Frame {
+        profile.frames.iter().find(|f| f.name == name).unwrap()
+    }
+
+    #[test]
     fn test_basic_collapse() {
         let input = r#"
 swapper     0 [000] 158665.570607: 1 cpu-clock:
@@ -662,7 +2315,7 @@ swapper     0 [000] 158665.570608: 1 cpu-clock:
 
 "#;
         let opts = Options::default();
-        let result = collapse_perf(input, &opts);
+        let result = collapse_perf_profile(input, &opts).fold();
         
         assert_eq!(result.len(), 1);
         let expected_key = "swapper;cpu_idle;default_idle;native_safe_halt";
@@ -681,7 +2334,7 @@ swapper     0 [000] 158665.570607: 1 cpu-clock:
             annotate_kernel: true,
             ..Default::default()
         };
-        let result = collapse_perf(input, &opts);
+        let result = collapse_perf_profile(input, &opts).fold();
         
         assert!(result.keys().next().unwrap().contains("_[k]"));
     }
@@ -697,12 +2350,276 @@ java 12345 158665.570607: 1 cpu-clock:
             include_pid: true,
             ..Default::default()
         };
-        let result = collapse_perf(input, &opts);
+        let result = collapse_perf_profile(input, &opts).fold();
         
         let key = result.keys().next().unwrap();
         assert!(key.starts_with("java-12345;"), "Key was: {}", key);
     }
 
+    #[test]
+    fn test_event_header_cpu_and_time() {
+        let header = parse_event_header("java 24636/25607 [002] 4794564.109216: 235 cpu-clock:").unwrap();
+        assert_eq!(header.comm, "java");
+        assert_eq!(header.pid, "24636");
+        assert_eq!(header.tid, "25607");
+        assert_eq!(header.cpu, Some(2));
+        assert_eq!(header.time, Some(4794564.109216));
+        assert_eq!(header.period, 235);
+
+        let header = parse_event_header("V8 WorkerThread 25607 4794564.109216: 104345 cycles:").unwrap();
+        assert_eq!(header.comm, "V8 WorkerThread");
+        assert_eq!(header.cpu, None);
+    }
+
+    #[test]
+    fn test_cpu_filter_and_split() {
+        let input = r#"
+app 10 [000] 1.000000: 1 cpu-clock:
+        1111 work (/bin/app)
+
+app 10 [001] 1.100000: 1 cpu-clock:
+        1111 work (/bin/app)
+
+app 10 [003] 1.200000: 1 cpu-clock:
+        2222 idle (/bin/app)
+
+"#;
+        let opts = Options {
+            cpu_filter: vec![0, 1],
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result["app;work"], 2);
+
+        let per_cpu = collapse_perf_per_cpu(input, &Options::default());
+        assert_eq!(per_cpu.keys().copied().collect::<Vec<_>>(), vec![0, 1, 3]);
+        assert_eq!(per_cpu[&3].fold(), CollapsedStacks::from([("app;idle".to_string(), 1)]));
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert_eq!(parse_cpu_list("5, 2,2").unwrap(), vec![2, 5]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("").is_err());
+    }
+
+    #[test]
+    fn test_time_bound_parsing() {
+        assert_eq!("@158665.5".parse::<TimeBound>(), Ok(TimeBound::Absolute(158665.5)));
+        assert_eq!("5".parse::<TimeBound>(), Ok(TimeBound::Relative(5.0)));
+        assert_eq!("2.5s".parse::<TimeBound>(), Ok(TimeBound::Relative(2.5)));
+        assert_eq!("250ms".parse::<TimeBound>(), Ok(TimeBound::Relative(0.25)));
+        assert_eq!("25%".parse::<TimeBound>(), Ok(TimeBound::Percent(25.0)));
+        assert!("150%".parse::<TimeBound>().is_err());
+        assert!("soon".parse::<TimeBound>().is_err());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let input = r#"
+app 10 [000] 100.000000: 1 cpu-clock:
+        1111 startup (/bin/app)
+
+app 10 [000] 105.000000: 1 cpu-clock:
+        2222 steady (/bin/app)
+
+app 10 [000] 110.000000: 1 cpu-clock:
+        3333 shutdown (/bin/app)
+
+"#;
+        assert_eq!(sample_time_range(input), Some((100.0, 110.0)));
+
+        let opts = Options {
+            start: Some(TimeBound::Relative(1.0)),
+            end: Some(TimeBound::Percent(90.0)),
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 1);
+        assert!(result.contains_key("app;steady"));
+
+        let opts = Options {
+            end: Some(TimeBound::Absolute(100.0)),
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.keys().collect::<Vec<_>>(), vec!["app;startup"]);
+    }
+
+    #[test]
+    fn test_sample_table() {
+        let input = r#"
+app 10 [000] 100.000000: 3 cpu-clock:
+        1111 work (/bin/app)
+
+app 10 [000] 100.500000: 2 cpu-clock:
+        2222 idle (/bin/app)
+
+app 10 [000] 101.000000: 1 cpu-clock:
+        1111 work (/bin/app)
+
+"#;
+        let opts = Options::default();
+        let table = collapse_perf_samples(input, &opts);
+
+        assert_eq!((table.profile.folded(&table.stacks[0]), table.profile.folded(&table.stacks[1])), ("app;work".into(), "app;idle".into()));
+        assert_eq!(table.samples.len(), 3);
+        assert_eq!(table.samples[2], Sample { time: Some(101.0), stack: 0, weight: 1, thread: 0 });
+        assert_eq!(table.threads, vec![Thread { pid: "10".into(), tid: "10".into(), comm: "app".into() }]);
+        assert_eq!(table.time_range(), Some((100.0, 101.0)));
+        assert_eq!(table.profile.fold(), collapse_perf_profile(input, &opts).fold());
+    }
+
+    #[test]
+    fn test_split_trace_args() {
+        let header = parse_event_header(
+            "app 10 [001] 5.000000: sched:sched_switch: prev_comm=app prev_pid=10 prev_prio=120 prev_state=S ==> next_comm=kworker/0:1 next_pid=42 next_prio=120",
+        )
+        .unwrap();
+        assert_eq!(header.event, "sched:sched_switch");
+        assert_eq!(header.cpu, Some(1));
+        assert_eq!(trace_field(&header.trace_args, "next_comm"), Some("kworker/0:1"));
+        assert_eq!(trace_field(&header.trace_args, "prev_pid"), Some("10"));
+        assert_eq!(trace_field(&header.trace_args, "pid"), None);
+    }
+
+    #[test]
+    fn test_off_cpu_pairing() {
+        let input = r#"
+app 10 [000] 1.000000: sched:sched_switch: prev_comm=app prev_pid=10 prev_prio=120 prev_state=S ==> next_comm=swapper/0 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        2222 nanosleep (/lib/libc.so.6)
+
+db 20 [001] 1.500000: sched:sched_switch: prev_comm=db prev_pid=20 prev_prio=120 prev_state=D ==> next_comm=swapper/1 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        3333 read (/lib/libc.so.6)
+
+swapper 0 [000] 1.250000: sched:sched_switch: prev_comm=swapper/0 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=app next_pid=10 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+
+swapper 0 [001] 1.503000: sched:sched_switch: prev_comm=swapper/1 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=db next_pid=20 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+
+"#;
+        let opts = Options {
+            off_cpu: true,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result["app;nanosleep;schedule"], 250_000_000);
+        assert_eq!(result["db;read;schedule"], 3_000_000);
+
+        let opts = Options {
+            off_cpu: true,
+            off_cpu_states: true,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result["[sleep];app;nanosleep;schedule"], 250_000_000);
+        assert_eq!(result["[io];db;read;schedule"], 3_000_000);
+    }
+
+    #[test]
+    fn test_off_cpu_filters() {
+        // app blocks on CPU 0 and comes back on CPU 1, db blocks on CPU 1
+        let input = r#"
+app 10 [000] 1.000000: sched:sched_switch: prev_comm=app prev_pid=10 prev_prio=120 prev_state=D ==> next_comm=swapper/0 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        3333 read (/lib/libc.so.6)
+
+db 20 [001] 1.500000: sched:sched_switch: prev_comm=db prev_pid=20 prev_prio=120 prev_state=S ==> next_comm=swapper/1 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        2222 nanosleep (/lib/libc.so.6)
+
+swapper 0 [001] 2.000000: sched:sched_switch: prev_comm=swapper/1 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=app next_pid=10 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+
+swapper 0 [000] 2.500000: sched:sched_switch: prev_comm=swapper/0 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=db next_pid=20 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+
+"#;
+        // Blocked time goes by the CPU the thread blocked on
+        let opts = Options { off_cpu: true, cpu_filter: vec![0], ..Default::default() };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result["app;read;schedule"], 1_000_000_000);
+
+        // ... and by when it blocked, even if it was switched back in past the end
+        let opts = Options { off_cpu: true, end: Some(TimeBound::Absolute(1.6)), ..Default::default() };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result["db;nanosleep;schedule"], 1_000_000_000);
+
+        let opts = Options { off_cpu: true, start: Some(TimeBound::Absolute(1.2)), ..Default::default() };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.keys().collect::<Vec<_>>(), vec!["db;nanosleep;schedule"]);
+    }
+
+    #[test]
+    fn test_off_cpu_switch_outs_only() {
+        // What `perf record -e sched:sched_switch <cmd>` without -a gives: the
+        // switch-ins are logged by the tasks being replaced, which aren't recorded
+        let input = r#"
+app 10 [000] 1.000000: sched:sched_switch: prev_comm=app prev_pid=10 prev_prio=120 prev_state=S ==> next_comm=swapper/0 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        2222 nanosleep (/lib/libc.so.6)
+
+app 10 [000] 1.300000: sched:sched_switch: prev_comm=app prev_pid=10 prev_prio=120 prev_state=S ==> next_comm=swapper/0 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        2222 nanosleep (/lib/libc.so.6)
+
+"#;
+        let opts = Options { off_cpu: true, ..Default::default() };
+        let mut parser = Parser::new(&opts);
+        parser.run(input);
+        assert!(parser.profile.stacks.is_empty());
+        assert_eq!(parser.switch_outs, 2);
+        assert!(parser.only_switch_outs());
+
+        let mut parser = Parser::new(&opts);
+        parser.run(&input.replace("next_comm=swapper/0 next_pid=0", "next_comm=app next_pid=10"));
+        assert!(!parser.only_switch_outs());
+    }
+
+    #[test]
+    fn test_off_wake_stacks() {
+        let input = r#"
+app 10 [000] 1.000000: sched:sched_switch: prev_comm=app prev_pid=10 prev_prio=120 prev_state=S ==> next_comm=swapper/0 next_pid=0 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+        2222 pthread_mutex_lock (/lib/libc.so.6)
+
+worker 11 [001] 1.200000: sched:sched_wakeup: comm=app pid=10 prio=120 target_cpu=000
+        3333 try_to_wake_up ([kernel.kallsyms])
+        4444 pthread_mutex_unlock (/lib/libc.so.6)
+        5555 release (/bin/app)
+
+swapper 0 [000] 1.250000: sched:sched_switch: prev_comm=swapper/0 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=app next_pid=10 next_prio=120
+        1111 schedule ([kernel.kallsyms])
+
+"#;
+        let opts = Options {
+            off_cpu: true,
+            off_wake: true,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 1);
+        let key = "app;pthread_mutex_lock;schedule;--;try_to_wake_up;pthread_mutex_unlock;release;worker";
+        assert_eq!(result[key], 250_000_000);
+
+        // Without off-wake the wakeup events are filtered out
+        let opts = Options {
+            off_cpu: true,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result["app;pthread_mutex_lock;schedule"], 250_000_000);
+    }
+
     #[test]
     fn test_strip_offset() {
         assert_eq!(strip_offset("func+0x1234"), "func");
@@ -714,7 +2631,9 @@ java 12345 158665.570607: 1 cpu-clock:
     #[test]
     fn test_tidy_generic() {
         assert_eq!(tidy_generic_func("foo(int, int)"), "foo");
-        assert_eq!(tidy_generic_func("foo;bar"), "foo:bar");
+        // Frame names keep `;`, only folded output replaces it
+        assert_eq!(tidy_generic_func("foo;bar"), "foo;bar");
+        assert_eq!(Frame::named("foo;bar").folded_name(), "foo:bar");
         // Go methods should be preserved
         assert_eq!(
             tidy_generic_func("net/http.(*Client).Do"),
@@ -722,6 +2641,493 @@ java 12345 158665.570607: 1 cpu-clock:
         );
     }
 
+    #[test]
+    fn test_tidy_rust() {
+        assert_eq!(
+            tidy_rust_func("_ZN4core3ptr13drop_in_place17h9f3a1c2b4d5e6f70E", false),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(tidy_rust_func("_RNvNtCs1234_7mycrate3foo3bar", false), "mycrate::foo::bar");
+        assert_eq!(
+            tidy_rust_func("_RNCNvCs1234_7mycrate4main0", false),
+            "mycrate::main::{{closure}}"
+        );
+        let name = "core::ptr::drop_in_place<alloc::vec::Vec<u8>>::h9f3a1c2b4d5e6f70";
+        assert_eq!(tidy_rust_func(name, false), "core::ptr::drop_in_place<alloc::vec::Vec<u8>>");
+        assert_eq!(tidy_rust_func(name, true), "core::ptr::drop_in_place<…>");
+        assert_eq!(
+            tidy_rust_func("<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop", true),
+            "<alloc::vec::Vec<…> as core::ops::drop::Drop>::drop"
+        );
+        assert_eq!(elide_generic_args("f<fn(u8) -> u8>::<T>", 1), "f<fn(u8) -> u8>::<T>");
+
+        // Legacy manglings are told apart from C++ by their hash
+        assert!(is_rust_symbol("_ZN4core3ptr13drop_in_place17h9f3a1c2b4d5e6f70E"));
+        assert!(!is_rust_symbol("_ZN3foo3barE"));
+        assert!(!is_rust_symbol("std::vector<int>::push_back"));
+    }
+
+    #[test]
+    fn test_tidy_cpp() {
+        assert_eq!(
+            tidy_cpp_func("std::vector<int, std::allocator<int> >::push_back(int const&)", 1),
+            "std::vector<int, std::allocator<…> >::push_back"
+        );
+        assert_eq!(tidy_cpp_func("Foo::operator()(int) const", 1), "Foo::operator()");
+        assert_eq!(tidy_cpp_func("Foo::operator<<(std::ostream&)", 0), "Foo::operator<<");
+        assert_eq!(tidy_cpp_func("bool Foo::operator< <int>(Foo const&)", 0), "Foo::operator< <int>");
+        assert_eq!(
+            tidy_cpp_func("run(int)::{lambda(int)#1}::operator()(int) const", 1),
+            "run::{lambda#1}"
+        );
+        assert_eq!(
+            tidy_cpp_func("std::__cxx11::basic_string<char, std::char_traits<char>, std::allocator<char> >::_M_create[abi:cxx11](unsigned long&, unsigned long)", 0),
+            "std::basic_string<…>::_M_create"
+        );
+        assert_eq!(
+            tidy_cpp_func("void (anonymous namespace)::worker<std::__1::vector<int> >(int)", 2),
+            "(anonymous namespace)::worker<std::vector<int> >"
+        );
+        assert_eq!(tidy_cpp_func("Foo::operator bool() const", 1), "Foo::operator bool");
+        assert_eq!(tidy_cpp_func("int (*foo())(int)", 0), "foo");
+        assert_eq!(tidy_cpp_func("int (*ns::foo(char))(int)", 1), "ns::foo");
+        assert_eq!(tidy_cpp_func("Bar<(1>2)>::baz()", 0), "Bar<…>::baz");
+        assert_eq!(tidy_cpp_func("Bar<(1>2)>::baz()", 1), "Bar<(1>2)>::baz");
+        assert_eq!(tidy_cpp_func("(*)", 0), "(*)");
+        assert_eq!(tidy_cpp_func("Baz<(a<b)>::f()", 0), "Baz<…>::f");
+        assert!(!is_cpp_func("net/http.(*Client).Do"));
+    }
+
+    #[test]
+    fn test_cpp_full_names() {
+        let input = r#"
+app 10 1.000000: 1 cpu-clock:
+        1111 Foo::bar(std::vector<int, std::allocator<int> > const&) (/bin/app)
+        2222 main (/bin/app)
+
+"#;
+        let profile = collapse_perf_profile(input, &Options::default());
+        let info = &profile.info;
+        assert_eq!(profile.fold()["app;main;Foo::bar"], 1);
+        assert_eq!(
+            info.full_names["Foo::bar"],
+            "Foo::bar(std::vector<int, std::allocator<int> > const&)"
+        );
+        assert!(!info.full_names.contains_key("main"));
+    }
+
+    #[test]
+    fn test_parse_runtime_frame() {
+        assert_eq!(
+            parse_runtime_frame("py::handle_request:/srv/app.py"),
+            Some(RuntimeFrame {
+                name: "handle_request",
+                source: Some("/srv/app.py"),
+                interpreted: true,
+                python: true,
+            })
+        );
+        assert_eq!(
+            parse_runtime_frame("LazyCompile:*parse /srv/x.js:10"),
+            Some(RuntimeFrame {
+                name: "parse",
+                source: Some("/srv/x.js:10"),
+                interpreted: false,
+                python: false,
+            })
+        );
+        let frame = parse_runtime_frame("JS:~ node:internal/main:3").unwrap();
+        assert_eq!((frame.name, frame.interpreted), ("(anonymous)", true));
+        assert_eq!(parse_runtime_frame("Builtin:ArrayMap"), None);
+    }
+
+    #[test]
+    fn test_python_frames() {
+        let input = r#"
+python 10 1.000000: 1 cpu-clock:
+        1111 py::inner:/srv/app.py (/tmp/perf-10.map)
+        2222 _PyEval_EvalFrameDefault (/usr/bin/python3.12)
+        3333 PyObject_Vectorcall (/usr/bin/python3.12)
+        4444 py::outer:/srv/app.py (/tmp/perf-10.map)
+        2222 _PyEval_EvalFrameDefault (/usr/bin/python3.12)
+        5555 main (/usr/bin/python3.12)
+
+"#;
+        let result = collapse_perf_profile(input, &Options::default()).fold();
+        assert_eq!(
+            result["python;main;_PyEval_EvalFrameDefault;outer;PyObject_Vectorcall;_PyEval_EvalFrameDefault;inner"],
+            1
+        );
+
+        let opts = Options {
+            hide_interpreter_frames: true,
+            jit_source: true,
+            jit_tiers: true,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(
+            result["python;main;_PyEval_EvalFrameDefault;outer:/srv/app.py_[b];inner:/srv/app.py_[b]"],
+            1
+        );
+    }
+
+    #[test]
+    fn test_tidy_java() {
+        assert_eq!(
+            tidy_java_func("Lcom/example/Foo;::bar(Ljava/lang/String;I)V"),
+            "com.example.Foo.bar"
+        );
+        assert_eq!(
+            tidy_java_func("Lcom/example/Foo$$Lambda$14/0x0000000800c03000;::apply"),
+            "com.example.Foo$$Lambda.apply"
+        );
+        assert_eq!(tidy_java_func("Lcom/example/Foo;::lambda$main$0"), "com.example.Foo.lambda$main");
+        assert_eq!(tidy_java_func("java.lang.String::hashCode (44 bytes)"), "java.lang.String.hashCode");
+        assert_eq!(tidy_java_func("Interpreter"), "Interpreter");
+    }
+
+    #[test]
+    fn test_java_frames() {
+        let input = r#"
+java 10 1.000000: 1 cpu-clock:
+        1111 Lcom/example/Foo;::bar (/tmp/perf-10.map)
+        2222 Interpreter (/tmp/perf-10.map)
+        3333 JavaCalls::call_helper(JavaValue*, methodHandle const&, JavaCallArguments*, JavaThread*) (/usr/lib/jvm/lib/server/libjvm.so)
+        4444 start_thread (/usr/lib/libc.so.6)
+
+GC_Thread#0 11 1.000000: 1 cpu-clock:
+        5555 G1ParScanThreadState::trim_queue() (/usr/lib/jvm/lib/server/libjvm.so)
+        6666 WorkerThread::run() (/usr/lib/jvm/lib/server/libjvm.so)
+        4444 start_thread (/usr/lib/libc.so.6)
+
+"#;
+        let result = collapse_perf_profile(input, &Options::default()).fold();
+        assert_eq!(result["java;start_thread;JavaCalls::call_helper;Interpreter_[b];com.example.Foo.bar"], 1);
+        assert_eq!(result["GC_Thread#0;start_thread;WorkerThread::run;G1ParScanThreadState::trim_queue"], 1);
+
+        let opts = Options {
+            fold_jvm_internals: true,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result["java;start_thread;[jvm];Interpreter_[b];com.example.Foo.bar"], 1);
+        assert_eq!(result["GC_Thread#0;start_thread;[jvm gc]"], 1);
+    }
+
+    #[test]
+    fn test_rules() {
+        let input = r#"
+app 10 1.000000: 1 cpu-clock:
+        1111 parse_json (/bin/app)
+        2222 tokio::runtime::task::poll (/bin/app)
+        3333 tokio::runtime::worker::run (/bin/app)
+        4444 app::handler (/bin/app)
+        5555 tokio::runtime::block_on (/bin/app)
+        6666 main (/bin/app)
+
+"#;
+        let with_rules = |toml: &str| Options {
+            rules: Rules::parse(toml).unwrap(),
+            ..Default::default()
+        };
+
+        let opts = with_rules("[[rule]]\nmatch = \"^tokio::\"\naction = \"collapse\"");
+        assert_eq!(
+            collapse_perf_profile(input, &opts).fold()["app;main;tokio::runtime::block_on;app::handler;tokio::runtime::worker::run;parse_json"],
+            1
+        );
+
+        let opts = with_rules("[[rule]]\nmatch = \"^tokio::\"\naction = \"drop\"");
+        assert_eq!(collapse_perf_profile(input, &opts).fold()["app;main;app::handler;parse_json"], 1);
+
+        let opts = with_rules("[[rule]]\nmatch = \"^app::handler$\"\naction = \"truncate\"");
+        assert_eq!(
+            collapse_perf_profile(input, &opts).fold()["app;app::handler;tokio::runtime::worker::run;tokio::runtime::task::poll;parse_json"],
+            1
+        );
+
+        let opts = with_rules("[[rule]]\nmatch = \"^app::\"\naction = \"merge\"");
+        assert_eq!(collapse_perf_profile(input, &opts).fold()["app;main;tokio::runtime::block_on;app::handler"], 1);
+    }
+
+    #[test]
+    fn test_fold_recursion() {
+        let fold = |stack: &str, mode| {
+            let (stack, levels) = fold_recursion(stack.split(';').collect(), mode, |&f| f);
+            (stack.join(";"), levels)
+        };
+
+        assert_eq!(fold("app;walk;walk;walk;leaf", Recursion::Direct), ("app;walk;leaf".to_string(), vec![1, 3, 1]));
+        assert_eq!(
+            fold("app;expr;term;expr;term;atom", Recursion::Direct),
+            ("app;expr;term;expr;term;atom".to_string(), vec![1; 6])
+        );
+        assert_eq!(
+            fold("app;expr;term;expr;term;atom", Recursion::All),
+            ("app;expr;term;atom".to_string(), vec![1, 2, 1, 1])
+        );
+
+        assert_eq!("all".parse::<Recursion>(), Ok(Recursion::All));
+        assert!("some".parse::<Recursion>().is_err());
+    }
+
+    #[test]
+    fn test_recursion_with_filter() {
+        let input = r#"
+app 10 1.000000: 1 cpu-clock:
+        1111 leaf (/bin/app)
+        2222 walk (/bin/app)
+        2222 walk (/bin/app)
+        2222 walk (/bin/app)
+        3333 handle (/bin/app)
+        4444 main (/bin/app)
+
+"#;
+        let opts = Options {
+            collapse_recursion: Some(Recursion::Direct),
+            filter: StackFilter {
+                focus: Some(regex::Regex::new("^handle$").unwrap()),
+                reroot: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let profile = collapse_perf_profile(input, &opts);
+        assert_eq!(profile.fold()["handle;walk;leaf"], 1);
+        // Keyed by the rerooted path the viewer draws
+        assert_eq!(profile.info.recursion.keys().collect::<Vec<_>>(), vec!["handle;walk"]);
+        assert_eq!(profile.info.recursion["handle;walk"], 3);
+
+        // Frames cut off by --prune note nothing
+        let opts = Options {
+            collapse_recursion: Some(Recursion::Direct),
+            filter: StackFilter { prune: Some(regex::Regex::new("^handle$").unwrap()), ..Default::default() },
+            ..Default::default()
+        };
+        assert!(collapse_perf_profile(input, &opts).info.recursion.is_empty());
+    }
+
+    #[test]
+    fn test_stack_filter() {
+        let input = r#"
+app 10 [000] 1.000000: 1 cpu-clock:
+        1111 parse (/bin/app)
+        2222 handle (/bin/app)
+        3333 main (/bin/app)
+
+app 10 [001] 1.000000: 1 cpu-clock:
+        4444 idle (/bin/app)
+        3333 main (/bin/app)
+
+"#;
+        let opts = Options {
+            filter: StackFilter {
+                focus: Some(regex::Regex::new("^handle$").unwrap()),
+                reroot: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result["handle;parse"], 1);
+
+        let per_cpu = collapse_perf_per_cpu(input, &opts);
+        assert_eq!(per_cpu.keys().copied().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_function_stats() {
+        let mut stacks = CollapsedStacks::new();
+        stacks.insert("app;main;parse;parse;malloc".to_string(), 4);
+        stacks.insert("app;main;render;malloc".to_string(), 2);
+        stacks.insert("app;main;render".to_string(), 1);
+
+        let stats = function_stats(&Profile::from_folded(&stacks));
+        let get = |name: &str| stats.iter().find(|f| f.name == name).unwrap();
+
+        assert_eq!(stats[0].name, "malloc");
+        assert_eq!((get("malloc").self_count, get("malloc").total, get("malloc").call_sites), (6, 6, 2));
+        // Recursion counts once towards the total, and as its own caller
+        assert_eq!((get("parse").self_count, get("parse").total, get("parse").call_sites), (0, 4, 2));
+        assert_eq!((get("render").self_count, get("render").total), (1, 3));
+        assert_eq!((get("app").total, get("app").call_sites), (7, 0));
+
+        // Same-named functions of different modules are counted apart
+        let input = r#"
+app 10 1.000000: 1 cpu-clock:
+        1111 helper (/usr/lib/libfoo.so)
+        2222 main (/bin/app)
+
+app 10 1.000000: 1 cpu-clock:
+        3333 helper (/bin/app)
+        2222 main (/bin/app)
+
+"#;
+        let profile = collapse_perf_profile(input, &Options::default());
+        let stats = function_stats(&profile);
+        let helpers: Vec<_> = stats.iter().filter(|f| f.name == "helper").collect();
+        assert_eq!(helpers.len(), 2);
+        assert_ne!(profile.frames[helpers[0].frame].module, profile.frames[helpers[1].frame].module);
+        let main = stats.iter().find(|f| f.name == "main").unwrap();
+        assert_eq!((main.total, main.call_sites), (2, 1));
+    }
+
+    #[test]
+    fn test_frame_modules() {
+        let input = r#"
+app 10 [000] 1.000000: 1 cpu-clock:
+        1111 malloc (/usr/lib/libc.so.6)
+        2222 main (/bin/app)
+        3333 [unknown] ([unknown])
+
+"#;
+        let profile = collapse_perf_profile(input, &Options::default());
+        assert_eq!(frame(&profile, "malloc").module.as_deref(), Some("/usr/lib/libc.so.6"));
+        assert_eq!(frame(&profile, "main").module.as_deref(), Some("/bin/app"));
+        assert_eq!(frame(&profile, "[unknown]").module, None);
+        assert_eq!(profile.addresses[profile.frames.iter().position(|f| f.name == "main").unwrap()], Some(0x2222));
+    }
+
+    #[test]
+    fn test_frame_kinds_and_languages() {
+        let input = r#"
+app 10 [000] 1.000000: 1 cpu-clock:
+        ffffffff8103ce3b native_safe_halt ([kernel.kallsyms])
+        1111 helper->inner (/bin/app)
+        2222 std::vector<int>::push_back(int) (/bin/app)
+        3333 py::handle:/srv/app.py (/tmp/perf-10.map)
+        4444 [unknown] ([unknown])
+        5555 app::main::h0123456789abcdef (/bin/app)
+
+"#;
+        let profile = collapse_perf_profile(input, &Options::default());
+        let kind = |name| frame(&profile, name).kind;
+        let language = |name| frame(&profile, name).language;
+        assert_eq!(kind("native_safe_halt"), Some(FrameKind::Kernel));
+        assert_eq!(language("native_safe_halt"), Some(Language::Native));
+        assert_eq!(kind("helper"), Some(FrameKind::User));
+        assert_eq!(kind("inner_[i]"), Some(FrameKind::Inlined));
+        assert_eq!(language("std::vector<int>::push_back"), Some(Language::Cpp));
+        assert_eq!(kind("handle"), Some(FrameKind::Jit));
+        assert_eq!(language("handle"), Some(Language::Python));
+        assert_eq!(kind("[unknown]"), Some(FrameKind::Unknown));
+        assert_eq!(language("[unknown]"), None);
+        assert_eq!(language("app::main"), Some(Language::Rust));
+        assert_eq!(kind("app"), None);
+    }
+
+    #[test]
+    fn test_mixed_rust_and_cpp() {
+        let input = r#"
+app 10 [000] 1.000000: 1 cpu-clock:
+        1111 std::vector<int, std::allocator<int> >::push_back (/usr/lib/libengine.so)
+        2222 engine::Scene<unsigned long>::draw (/usr/lib/libengine.so)
+        3333 engine::Canvas::operator= (/usr/lib/libengine.so)
+        4444 app::render (/bin/app)
+        5555 <alloc::vec::Vec<T> as core::ops::drop::Drop>::drop (/bin/app)
+        6666 app::main::h0123456789abcdef (/bin/app)
+
+"#;
+        let profile = collapse_perf_profile(input, &Options::default());
+        let language = |name| frame(&profile, name).language;
+        assert_eq!(language("std::vector<int, std::allocator<…> >::push_back"), Some(Language::Cpp));
+        assert_eq!(language("engine::Scene<unsigned long>::draw"), Some(Language::Cpp));
+        assert_eq!(language("engine::Canvas::operator="), Some(Language::Cpp));
+        assert_eq!(language("app::render"), Some(Language::Rust));
+        assert_eq!(language("<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop"), Some(Language::Rust));
+        // C++ frames keep their full name for the tooltip
+        assert_eq!(
+            profile.info.full_names["std::vector<int, std::allocator<…> >::push_back"],
+            "std::vector<int, std::allocator<int> >::push_back"
+        );
+
+        assert!(is_rust_frame("core::fmt::float::float_to_decimal_common_exact"));
+        assert!(is_rust_frame("<i32 as core::fmt::Display>::fmt"));
+        assert!(!is_rust_frame("Foo::~Foo"));
+        assert!(!is_rust_frame("std::__1::basic_string<char>::append"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let input = r#"
+app 10 [000] 1.000000: 1 cpu-clock:
+        1111 parse_a;b (/bin/app)
+  parse.c:12
+        2222 main (/bin/app)
+  main.c:40 [discriminator 2]
+
+app 10 [000] 2.000000: 2 cpu-clock:
+        1111 parse_a;b (/bin/app)
+  parse.c:12
+        2222 main (/bin/app)
+  main.c:41
+
+"#;
+        let opts = Options { srcline_in_input: true, tidy_generic: false, ..Default::default() };
+        let profile = collapse_perf_profile(input, &opts);
+
+        // Frames keep their details; `;` only gives way in the folded export
+        let parse = frame(&profile, "parse_a;b:parse.c:12");
+        assert_eq!(parse.source, Some(SourceLine { file: "parse.c".into(), line: 12 }));
+        assert_eq!(parse.module.as_deref(), Some("/bin/app"));
+        assert_eq!(profile.frames.iter().filter(|f| f.name.starts_with("main")).count(), 2);
+        assert_eq!(profile.stacks.len(), 2);
+        let lines = source_line_stats(&profile);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines["parse.c"], BTreeMap::from([(12, 3)]));
+
+        let folded = profile.fold();
+        assert_eq!(folded["app;main:main.c:40;parse_a:b:parse.c:12"], 1);
+        assert_eq!(folded, collapse_perf_profile(input, &opts).fold());
+
+        let mut merged = Profile::from_folded(&folded);
+        assert_eq!(merged.fold(), folded);
+        merged.merge(&profile);
+        assert_eq!(merged.fold()["app;main:main.c:41;parse_a:b:parse.c:12"], 4);
+
+        assert_eq!(SourceLine::parse("/src/lib.rs:7 (discriminator 3)").unwrap().to_string(), "/src/lib.rs:7");
+        assert_eq!(SourceLine::parse("??:0"), None);
+
+        let roots: Vec<SourceRoot> = ["/build/app/=/home/me/app", "/build=", "/usr/src=/src"]
+            .iter()
+            .map(|root| root.parse().unwrap())
+            .collect();
+        let remapped = |file| SourceRoot::remap(&roots, file).into_owned();
+        assert_eq!(remapped("/build/app/src/main.rs"), "/home/me/app/src/main.rs");
+        assert_eq!(remapped("/build/lib/x.c"), "lib/x.c");
+        assert_eq!(remapped("/usr/srcs/y.c"), "/usr/srcs/y.c");
+        assert_eq!(remapped("main.c"), "main.c");
+        assert!("/build".parse::<SourceRoot>().is_err());
+
+        // Unresolved frames have no source line under them
+        let input = "app 10 [000] 1.000000: 1 cpu-clock:\n        ffff schedule ([kernel.kallsyms])\n        2222 main (/bin/app)\n  main.c:40\n\napp 10 [000] 2.000000: 1 cpu-clock:\n        2222 main (/bin/app)\n\n";
+        let folded = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(folded["app;main:main.c:40;schedule"], 1);
+        assert_eq!(folded["app;main"], 1);
+    }
+
+    #[test]
+    fn test_rust_detection() {
+        let input = r#"
+app 10 1.000000: 1 cpu-clock:
+        1111 app::work::h0123456789abcdef (/bin/app)
+        2222 std::rt::lang_start<()>::{{closure}} (/bin/app)
+        3333 main (/bin/app)
+
+"#;
+        let result = collapse_perf_profile(input, &Options::default()).fold();
+        assert_eq!(result["app;main;std::rt::lang_start<()>::{{closure}};app::work"], 1);
+
+        let opts = Options {
+            tidy_rust: false,
+            ..Default::default()
+        };
+        let result = collapse_perf_profile(input, &opts).fold();
+        assert_eq!(result["app;main;std::rt::lang_start<()>::{{closure}};app::work::h0123456789abcdef"], 1);
+    }
+
     #[test]
     fn test_format_collapsed() {
         let mut collapsed = CollapsedStacks::new();
@@ -15,9 +15,11 @@ echo "Running perf record for on-CPU profiling..."
 perf record -g --call-graph dwarf -o test/perf_oncpu.data ./test/cpu_test 2000
 
 # Run with perf record for off-CPU time (sched:sched_switch tracepoint)
-# Requires root for scheduler tracepoints
+# Requires root for scheduler tracepoints. Recorded system-wide (-a): a thread's
+# switch-in is logged by the task it replaces, so following only cpu_test would
+# leave flg no switch-ins to compute blocked times from.
 echo "Running perf record for off-CPU profiling..."
-sudo perf record -g -e sched:sched_switch -o test/perf_offcpu.data ./test/cpu_test 2000
+sudo perf record -a -g -e sched:sched_switch -o test/perf_offcpu.data ./test/cpu_test 2000
 sudo chown $(whoami):$(whoami) test/perf_offcpu.data
 
 # Generate flamegraphs
@@ -25,7 +27,7 @@ echo "Generating on-CPU flamegraph..."
 cargo run -- gen test/perf_oncpu.data -o flamegraph_oncpu.html
 
 echo "Generating off-CPU flamegraph..."
-cargo run -- gen test/perf_offcpu.data -o flamegraph_offcpu.html
+cargo run -- gen test/perf_offcpu.data --off-cpu -o flamegraph_offcpu.html
 
 cargo run -- gen test/*.data -o flamegraph.html
 